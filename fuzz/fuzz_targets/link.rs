@@ -0,0 +1,92 @@
+#![no_main]
+
+use ld_rs::elf::shared_object::SharedObject64;
+use ld_rs::elf::symbol::{sym_bind, STB_LOCAL};
+use ld_rs::elf::{ElfFile64, ParseLimits};
+use ld_rs::link::{LinkOptions, OutputKind};
+use libfuzzer_sys::fuzz_target;
+
+const LIMITS: ParseLimits = ParseLimits {
+    max_sections: 4096,
+    max_size: 16 << 20,
+    max_align: 4096,
+    max_output_size: 64 << 20,
+};
+
+// what goes between inputs
+const SEPARATOR: &[u8] = b"\0\0ld-rs-fuzz-input\0\0";
+
+fn split(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut inputs = Vec::new();
+    while let Some(at) = data.windows(SEPARATOR.len()).position(|w| w == SEPARATOR) {
+        inputs.push(&data[..at]);
+        data = &data[at + SEPARATOR.len()..];
+    }
+    inputs.push(data);
+    inputs
+}
+
+fn parse(input: &[u8], name: String) -> Option<ElfFile64> {
+    let mut elf = ElfFile64::parse_with_limits(input, &LIMITS).ok()?;
+    elf.name = name;
+    Some(elf)
+}
+
+// the first byte picks a relocatable, static or shared output, the rest is
+// inputs separated by SEPARATOR. Shared objects are linked against, the
+// others are linked together. One object on its own is linked with a copy of
+// itself whose definitions are renamed, so that the two are merged rather
+// than just conflicting.
+fuzz_target!(|data: &[u8]| {
+    let (kind, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let output_kind = match kind % 3 {
+        0 => OutputKind::Relocatable,
+        1 => OutputKind::StaticExecutable,
+        _ => OutputKind::Shared,
+    };
+
+    let mut objects = Vec::new();
+    let mut shared_objects = Vec::new();
+    let inputs = split(data);
+    for (i, input) in inputs.iter().enumerate() {
+        let name = format!("input{}", i);
+        if SharedObject64::is_shared_object(input) {
+            match SharedObject64::parse(input, &name, &LIMITS) {
+                Ok(so) => shared_objects.push(so),
+                Err(_) => return,
+            }
+        } else {
+            match parse(input, name) {
+                Some(elf) => objects.push(elf),
+                None => return,
+            }
+        }
+    }
+    if inputs.len() == 1 && objects.len() == 1 {
+        let mut copy = match parse(inputs[0], "copy".to_string()) {
+            Some(copy) => copy,
+            None => return,
+        };
+        for symbol in copy.symbols.iter_mut() {
+            if symbol.shndx != 0 && sym_bind(symbol) != STB_LOCAL {
+                symbol.name.push_str(".copy");
+            }
+        }
+        objects.push(copy);
+    }
+
+    let options = LinkOptions {
+        output_kind,
+        ..LinkOptions::default()
+    };
+    let result = match ld_rs::link(objects, &shared_objects, &options) {
+        Ok((result, _warnings)) => result,
+        Err(_) => return,
+    };
+    // writing to memory only fails if the output is over the limit
+    let mut output = Vec::new();
+    let _ = ElfFile64::write_out_with_limits(result, &mut output, &LIMITS);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use ld_rs::elf::shared_object::SharedObject64;
+use ld_rs::elf::{ElfFile64, ParseLimits};
+use libfuzzer_sys::fuzz_target;
+
+// small enough that hitting a limit is an error, not an out of memory
+const LIMITS: ParseLimits = ParseLimits {
+    max_sections: 4096,
+    max_size: 16 << 20,
+    max_align: 4096,
+    max_output_size: 64 << 20,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ElfFile64::parse_with_limits(data, &LIMITS);
+    let _ = SharedObject64::parse(data, "fuzz.so", &LIMITS);
+});
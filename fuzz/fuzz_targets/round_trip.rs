@@ -0,0 +1,37 @@
+#![no_main]
+
+use ld_rs::elf::{ElfFile64, ElfFileError, ParseLimits};
+use libfuzzer_sys::fuzz_target;
+
+const LIMITS: ParseLimits = ParseLimits {
+    max_sections: 4096,
+    max_size: 16 << 20,
+    max_align: 4096,
+    max_output_size: 64 << 20,
+};
+
+// whatever is read has to be written out as something that can be read back
+fuzz_target!(|data: &[u8]| {
+    let elf = match ElfFile64::parse_with_limits(data, &LIMITS) {
+        Ok(elf) => elf,
+        Err(_) => return,
+    };
+    let num_sections = elf.unorganized_sections.len();
+    let num_symbols = elf.symbols.len();
+
+    // writing to memory only fails if the output is over the limit
+    let mut output = Vec::new();
+    if ElfFile64::write_out_with_limits(elf, &mut output, &LIMITS).is_err() {
+        return;
+    }
+
+    match ElfFile64::parse_with_limits(&output, &LIMITS) {
+        Ok(elf) => {
+            assert_eq!(elf.unorganized_sections.len(), num_sections);
+            assert_eq!(elf.symbols.len(), num_symbols);
+        }
+        // the output can be bigger than the input
+        Err(ElfFileError::LimitError) => {}
+        Err(e) => panic!("could not read the output back: {:?}", e),
+    }
+});
@@ -0,0 +1,185 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{place_mut, RelocationValues};
+use crate::link::LinkError;
+
+pub const EM_AARCH64: u16 = 183;
+
+pub const R_AARCH64_NONE: usize = 0;
+pub const R_AARCH64_ABS64: usize = 257;
+pub const R_AARCH64_ABS32: usize = 258;
+pub const R_AARCH64_ABS16: usize = 259;
+pub const R_AARCH64_PREL64: usize = 260;
+pub const R_AARCH64_PREL32: usize = 261;
+pub const R_AARCH64_PREL16: usize = 262;
+pub const R_AARCH64_ADR_PREL_PG_HI21: usize = 275;
+pub const R_AARCH64_ADD_ABS_LO12_NC: usize = 277;
+pub const R_AARCH64_LDST8_ABS_LO12_NC: usize = 278;
+pub const R_AARCH64_JUMP26: usize = 282;
+pub const R_AARCH64_CALL26: usize = 283;
+pub const R_AARCH64_LDST16_ABS_LO12_NC: usize = 284;
+pub const R_AARCH64_LDST32_ABS_LO12_NC: usize = 285;
+pub const R_AARCH64_LDST64_ABS_LO12_NC: usize = 286;
+pub const R_AARCH64_LDST128_ABS_LO12_NC: usize = 299;
+
+// servers commonly run with 64KiB pages, so segments are aligned for them
+pub const PAGE_SIZE: u64 = 0x10000;
+
+// `b` and `bl` reach +-128MiB
+const BRANCH_RANGE: i64 = 1 << 27;
+
+// a range extension thunk, for branches to targets too far away for `b` or
+// `bl`. It's PC-relative like they are, `adrp` reaches +-4GiB. The two
+// relocations in it are at THUNK_RELOCATIONS
+//   adrp x16, target
+//   add x16, x16, :lo12:target
+//   br x16
+pub const THUNK: [u8; 12] = [
+    0x10, 0x00, 0x00, 0x90, 0x10, 0x02, 0x00, 0x91, 0x00, 0x02, 0x1f, 0xd6,
+];
+pub const THUNK_RELOCATIONS: [(usize, usize); 2] = [
+    (0, R_AARCH64_ADR_PREL_PG_HI21),
+    (4, R_AARCH64_ADD_ABS_LO12_NC),
+];
+
+// `count` nops, instructions are little endian even in big endian code
+pub fn nops(count: usize) -> Vec<u8> {
+    [0x1f, 0x20, 0x03, 0xd5].repeat(count)
+}
+
+// relocations that bake an absolute address into the output
+pub fn is_absolute(r#type: usize) -> bool {
+    matches!(
+        r#type,
+        R_AARCH64_ABS64
+            | R_AARCH64_ABS32
+            | R_AARCH64_ABS16
+            | R_AARCH64_ADD_ABS_LO12_NC
+            | R_AARCH64_LDST8_ABS_LO12_NC
+            | R_AARCH64_LDST16_ABS_LO12_NC
+            | R_AARCH64_LDST32_ABS_LO12_NC
+            | R_AARCH64_LDST64_ABS_LO12_NC
+            | R_AARCH64_LDST128_ABS_LO12_NC
+    )
+}
+
+pub fn is_branch(r#type: usize) -> bool {
+    r#type == R_AARCH64_CALL26 || r#type == R_AARCH64_JUMP26
+}
+
+// can a branch at `p` reach `target` without a thunk?
+pub fn branch_in_range(p: u64, target: u64) -> bool {
+    let disp = target.wrapping_sub(p) as i64;
+    (-BRANCH_RANGE..BRANCH_RANGE).contains(&disp)
+}
+
+pub fn name(r#type: usize) -> String {
+    match r#type {
+        R_AARCH64_ABS64 => "R_AARCH64_ABS64".to_string(),
+        R_AARCH64_ABS32 => "R_AARCH64_ABS32".to_string(),
+        R_AARCH64_ABS16 => "R_AARCH64_ABS16".to_string(),
+        R_AARCH64_PREL64 => "R_AARCH64_PREL64".to_string(),
+        R_AARCH64_PREL32 => "R_AARCH64_PREL32".to_string(),
+        R_AARCH64_PREL16 => "R_AARCH64_PREL16".to_string(),
+        R_AARCH64_ADR_PREL_PG_HI21 => "R_AARCH64_ADR_PREL_PG_HI21".to_string(),
+        R_AARCH64_ADD_ABS_LO12_NC => "R_AARCH64_ADD_ABS_LO12_NC".to_string(),
+        R_AARCH64_JUMP26 => "R_AARCH64_JUMP26".to_string(),
+        R_AARCH64_CALL26 => "R_AARCH64_CALL26".to_string(),
+        _ => format!("relocation type {}", r#type),
+    }
+}
+
+// how many bytes a relocation changes
+fn width(r#type: usize) -> usize {
+    match r#type {
+        R_AARCH64_NONE => 0,
+        R_AARCH64_ABS64 | R_AARCH64_PREL64 => 8,
+        R_AARCH64_ABS16 | R_AARCH64_PREL16 => 2,
+        _ => 4,
+    }
+}
+
+// data is in the file's byte order `T`, instructions are little endian
+// whatever it is
+pub fn apply<T: ByteOrder>(
+    r#type: usize,
+    values: &RelocationValues,
+    data: &mut [u8],
+    offset: usize,
+) -> Result<(), LinkError> {
+    let RelocationValues { s, a, p, .. } = *values;
+    let place = place_mut(data, offset, width(r#type))?;
+    let sa = s.wrapping_add(a as u64);
+    let prel = sa.wrapping_sub(p);
+
+    match r#type {
+        R_AARCH64_NONE => {}
+        R_AARCH64_ABS64 => T::write_u64(place, sa),
+        R_AARCH64_ABS32 => T::write_u32(place, fits(sa, 32, r#type)? as u32),
+        R_AARCH64_ABS16 => T::write_u16(place, fits(sa, 16, r#type)? as u16),
+        R_AARCH64_PREL64 => T::write_u64(place, prel),
+        R_AARCH64_PREL32 => T::write_u32(place, fits(prel, 32, r#type)? as u32),
+        R_AARCH64_PREL16 => T::write_u16(place, fits(prel, 16, r#type)? as u16),
+        R_AARCH64_ADR_PREL_PG_HI21 => {
+            let pages = (page(sa).wrapping_sub(page(p)) as i64) >> 12;
+            if !(-(1 << 20)..1 << 20).contains(&pages) {
+                return Err(overflow("R_AARCH64_ADR_PREL_PG_HI21"));
+            }
+            let immlo = (pages as u32 & 0x3) << 29;
+            let immhi = ((pages >> 2) as u32 & 0x7ffff) << 5;
+            patch(place, 0x9f00001f, immlo | immhi)
+        }
+        R_AARCH64_ADD_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 0)),
+        R_AARCH64_LDST8_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 0)),
+        R_AARCH64_LDST16_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 1)),
+        R_AARCH64_LDST32_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 2)),
+        R_AARCH64_LDST64_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 3)),
+        R_AARCH64_LDST128_ABS_LO12_NC => patch(place, 0xffc003ff, lo12(sa, 4)),
+        R_AARCH64_CALL26 | R_AARCH64_JUMP26 => {
+            if !branch_in_range(p, sa) {
+                return Err(overflow(&name(r#type)));
+            }
+            let disp = sa.wrapping_sub(p) as u32;
+            patch(place, 0xfc000000, (disp >> 2) & 0x3ffffff)
+        }
+        _ => {
+            return Err(LinkError::UnsupportedRelocation(format!(
+                "unsupported relocation {}",
+                name(r#type)
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+fn overflow(what: &str) -> LinkError {
+    LinkError::Overflow(format!("relocation overflow in {}", what))
+}
+
+// data relocations narrower than an address take signed or unsigned values,
+// -2^(bits-1) <= value < 2^bits
+fn fits(value: u64, bits: u32, r#type: usize) -> Result<u64, LinkError> {
+    let value = value as i64;
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(overflow(&name(r#type)));
+    }
+
+    Ok(value as u64)
+}
+
+fn page(addr: u64) -> u64 {
+    addr & !0xfff
+}
+
+// the low 12 bits of an address, scaled by the access size and shifted into
+// the immediate field of an add, load or store
+fn lo12(addr: u64, shift: u32) -> u32 {
+    ((addr as u32 & 0xfff) >> shift) << 10
+}
+
+// instructions are always little endian, whatever the data is
+fn patch(place: &mut [u8], keep: u32, bits: u32) {
+    let insn = LittleEndian::read_u32(place);
+    LittleEndian::write_u32(place, (insn & keep) | bits);
+}
@@ -0,0 +1,106 @@
+use byteorder::ByteOrder;
+
+use super::place;
+use crate::link::LinkError;
+
+pub const EM_ARM: u16 = 40;
+
+pub const R_ARM_NONE: usize = 0;
+pub const R_ARM_PC24: usize = 1;
+pub const R_ARM_ABS32: usize = 2;
+pub const R_ARM_REL32: usize = 3;
+pub const R_ARM_THM_CALL: usize = 10;
+pub const R_ARM_GOTOFF32: usize = 24;
+pub const R_ARM_BASE_PREL: usize = 25;
+pub const R_ARM_GOT_BREL: usize = 26;
+pub const R_ARM_PLT32: usize = 27;
+pub const R_ARM_CALL: usize = 28;
+pub const R_ARM_JUMP24: usize = 29;
+pub const R_ARM_THM_JUMP24: usize = 30;
+pub const R_ARM_TARGET1: usize = 38;
+pub const R_ARM_V4BX: usize = 40;
+pub const R_ARM_TARGET2: usize = 41;
+pub const R_ARM_PREL31: usize = 42;
+pub const R_ARM_MOVW_ABS_NC: usize = 43;
+pub const R_ARM_MOVT_ABS: usize = 44;
+pub const R_ARM_MOVW_PREL_NC: usize = 45;
+pub const R_ARM_MOVT_PREL: usize = 46;
+pub const R_ARM_THM_MOVW_ABS_NC: usize = 47;
+pub const R_ARM_THM_MOVT_ABS: usize = 48;
+pub const R_ARM_THM_MOVW_PREL_NC: usize = 49;
+pub const R_ARM_THM_MOVT_PREL: usize = 50;
+pub const R_ARM_GOT_PREL: usize = 96;
+pub const R_ARM_TLS_GD32: usize = 104;
+pub const R_ARM_TLS_LDM32: usize = 105;
+pub const R_ARM_TLS_LDO32: usize = 106;
+pub const R_ARM_TLS_IE32: usize = 107;
+pub const R_ARM_TLS_LE32: usize = 108;
+
+// the addend of a SHT_REL relocation, which for instructions is their
+// immediate field, scaled the way the instruction scales it
+pub fn implicit_addend<T: ByteOrder>(
+    r#type: usize,
+    data: &[u8],
+    offset: usize,
+) -> Result<i64, LinkError> {
+    let addend = match r#type {
+        R_ARM_NONE | R_ARM_V4BX => 0,
+        R_ARM_ABS32 | R_ARM_REL32 | R_ARM_GOTOFF32 | R_ARM_BASE_PREL | R_ARM_GOT_BREL
+        | R_ARM_TARGET1 | R_ARM_TARGET2 | R_ARM_GOT_PREL | R_ARM_TLS_GD32 | R_ARM_TLS_LDM32
+        | R_ARM_TLS_LDO32 | R_ARM_TLS_IE32 | R_ARM_TLS_LE32 => {
+            T::read_i32(place(data, offset, 4)?) as i64
+        }
+        R_ARM_PREL31 => sign_extend(T::read_u32(place(data, offset, 4)?) as u64, 31),
+        // B and BL: a 24-bit word offset
+        R_ARM_PC24 | R_ARM_PLT32 | R_ARM_CALL | R_ARM_JUMP24 => {
+            let insn = T::read_u32(place(data, offset, 4)?) as u64;
+            sign_extend(insn & 0xffffff, 24) << 2
+        }
+        // MOVW and MOVT: imm4:imm12
+        R_ARM_MOVW_ABS_NC | R_ARM_MOVT_ABS | R_ARM_MOVW_PREL_NC | R_ARM_MOVT_PREL => {
+            let insn = T::read_u32(place(data, offset, 4)?) as u64;
+            sign_extend((insn >> 4) & 0xf000 | insn & 0xfff, 16)
+        }
+        // Thumb-2 instructions are two halfwords, the first one holds the
+        // opcode and top bits. BL and B.W: S:I1:I2:imm10:imm11 where
+        // I1 = !(J1 ^ S) and I2 = !(J2 ^ S)
+        R_ARM_THM_CALL | R_ARM_THM_JUMP24 => {
+            let (upper, lower) = halfwords::<T>(data, offset)?;
+            let s = (upper >> 10) & 1;
+            let i1 = !((lower >> 13) ^ s) & 1;
+            let i2 = !((lower >> 11) ^ s) & 1;
+            let imm = s << 23 | i1 << 22 | i2 << 21 | (upper & 0x3ff) << 11 | lower & 0x7ff;
+            sign_extend(imm, 24) << 1
+        }
+        // Thumb-2 MOVW and MOVT: imm4:i:imm3:imm8
+        R_ARM_THM_MOVW_ABS_NC
+        | R_ARM_THM_MOVT_ABS
+        | R_ARM_THM_MOVW_PREL_NC
+        | R_ARM_THM_MOVT_PREL => {
+            let (upper, lower) = halfwords::<T>(data, offset)?;
+            let imm = (upper & 0xf) << 12
+                | ((upper >> 10) & 1) << 11
+                | ((lower >> 12) & 0x7) << 8
+                | lower & 0xff;
+            sign_extend(imm, 16)
+        }
+        _ => {
+            return Err(LinkError::UnsupportedRelocation(format!(
+                "unsupported ARM relocation type {}",
+                r#type
+            )))
+        }
+    };
+
+    Ok(addend)
+}
+
+fn halfwords<T: ByteOrder>(data: &[u8], offset: usize) -> Result<(u64, u64), LinkError> {
+    let place = place(data, offset, 4)?;
+    Ok((T::read_u16(place) as u64, T::read_u16(&place[2..]) as u64))
+}
+
+// the low `bits` bits of `value` as a signed number
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
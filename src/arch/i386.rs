@@ -0,0 +1,29 @@
+use byteorder::ByteOrder;
+
+use super::place;
+use crate::link::LinkError;
+
+pub const EM_386: u16 = 3;
+
+pub const R_386_NONE: usize = 0;
+pub const R_386_16: usize = 20;
+pub const R_386_PC16: usize = 21;
+pub const R_386_8: usize = 22;
+pub const R_386_PC8: usize = 23;
+pub const R_386_TLS_DESC_CALL: usize = 40;
+
+// the addend of a SHT_REL relocation, stored in the place being relocated,
+// which is a 32-bit word for everything but the few 8 and 16-bit relocations
+pub fn implicit_addend<T: ByteOrder>(
+    r#type: usize,
+    data: &[u8],
+    offset: usize,
+) -> Result<i64, LinkError> {
+    Ok(match r#type {
+        // the place is an instruction, not an addend
+        R_386_NONE | R_386_TLS_DESC_CALL => 0,
+        R_386_16 | R_386_PC16 => T::read_i16(place(data, offset, 2)?) as i64,
+        R_386_8 | R_386_PC8 => place(data, offset, 1)?[0] as i8 as i64,
+        _ => T::read_i32(place(data, offset, 4)?) as i64,
+    })
+}
@@ -0,0 +1,66 @@
+use byteorder::ByteOrder;
+
+use super::place;
+use crate::elf::relocation::RelocationA64;
+use crate::link::LinkError;
+
+pub const EM_MIPS: u16 = 8;
+
+pub const R_MIPS_NONE: usize = 0;
+pub const R_MIPS_16: usize = 1;
+pub const R_MIPS_32: usize = 2;
+pub const R_MIPS_REL32: usize = 3;
+pub const R_MIPS_26: usize = 4;
+pub const R_MIPS_HI16: usize = 5;
+pub const R_MIPS_LO16: usize = 6;
+pub const R_MIPS_GPREL16: usize = 7;
+pub const R_MIPS_CALL16: usize = 11;
+pub const R_MIPS_GPREL32: usize = 12;
+
+// the addend of the SHT_REL relocation `relas[index]`. A R_MIPS_HI16 only
+// holds the top half of it, the bottom half is in the R_MIPS_LO16 against
+// the same symbol that follows it. Other instructions' addends are their
+// 16-bit immediate.
+pub fn implicit_addend<T: ByteOrder>(
+    relas: &[RelocationA64],
+    index: usize,
+    data: &[u8],
+) -> Result<i64, LinkError> {
+    let rela = &relas[index];
+    let immediate = |offset: u64| -> Result<i64, LinkError> {
+        Ok(T::read_u32(place(data, offset as usize, 4)?) as i16 as i64)
+    };
+
+    let addend = match rela.get_type() {
+        R_MIPS_NONE => 0,
+        R_MIPS_32 | R_MIPS_REL32 | R_MIPS_GPREL32 => {
+            T::read_i32(place(data, rela.offset as usize, 4)?) as i64
+        }
+        R_MIPS_16 => T::read_i16(place(data, rela.offset as usize, 2)?) as i64,
+        R_MIPS_LO16 | R_MIPS_GPREL16 | R_MIPS_CALL16 => immediate(rela.offset)?,
+        R_MIPS_26 => {
+            let insn = T::read_u32(place(data, rela.offset as usize, 4)?);
+            ((insn & 0x3ffffff) << 2) as i64
+        }
+        R_MIPS_HI16 => {
+            let lo = relas[index + 1..]
+                .iter()
+                .find(|r| r.get_type() == R_MIPS_LO16 && r.get_sym() == rela.get_sym())
+                .ok_or_else(|| {
+                    LinkError::InvalidRelocation(format!(
+                        "R_MIPS_HI16 at {:#x} has no matching R_MIPS_LO16",
+                        rela.offset
+                    ))
+                })?;
+            ((immediate(rela.offset)? & 0xffff) << 16) + immediate(lo.offset)?
+        }
+        r#type => {
+            return Err(LinkError::UnsupportedRelocation(format!(
+                "unsupported MIPS relocation type {}",
+                r#type
+            )))
+        }
+    };
+
+    Ok(addend)
+}
@@ -0,0 +1,208 @@
+pub mod aarch64;
+pub mod arm;
+pub mod i386;
+pub mod mips;
+pub mod riscv;
+pub mod x86_64;
+
+use crate::elf::relocation::RelocationA64;
+use crate::link::LinkError;
+
+// the architectures final links can be done for, picked by `e_machine`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv,
+}
+
+impl Arch {
+    pub fn from_machine(machine: u16) -> Option<Arch> {
+        match machine {
+            x86_64::EM_X86_64 => Some(Arch::X86_64),
+            aarch64::EM_AARCH64 => Some(Arch::Aarch64),
+            riscv::EM_RISCV => Some(Arch::Riscv),
+            _ => None,
+        }
+    }
+
+    pub fn page_size(self) -> u64 {
+        match self {
+            Arch::X86_64 => x86_64::PAGE_SIZE,
+            Arch::Aarch64 => aarch64::PAGE_SIZE,
+            Arch::Riscv => riscv::PAGE_SIZE,
+        }
+    }
+
+    pub fn needs_got(self, r#type: usize) -> bool {
+        match self {
+            Arch::X86_64 => x86_64::needs_got(r#type),
+            Arch::Aarch64 => false,
+            Arch::Riscv => riscv::needs_got(r#type),
+        }
+    }
+
+    pub fn needs_plt(self, r#type: usize) -> bool {
+        match self {
+            Arch::X86_64 => x86_64::needs_plt(r#type),
+            Arch::Aarch64 | Arch::Riscv => false,
+        }
+    }
+
+    pub fn is_absolute(self, r#type: usize) -> bool {
+        match self {
+            Arch::X86_64 => x86_64::is_absolute(r#type),
+            Arch::Aarch64 => aarch64::is_absolute(r#type),
+            Arch::Riscv => riscv::is_absolute(r#type),
+        }
+    }
+
+    pub fn name(self, r#type: usize) -> String {
+        match self {
+            Arch::X86_64 => x86_64::name(r#type),
+            Arch::Aarch64 => aarch64::name(r#type),
+            Arch::Riscv => riscv::name(r#type),
+        }
+    }
+
+    // `T` is the output's byte order
+    pub fn apply<T: byteorder::ByteOrder>(
+        self,
+        r#type: usize,
+        values: &RelocationValues,
+        data: &mut [u8],
+        offset: usize,
+    ) -> Result<(), LinkError> {
+        match self {
+            Arch::X86_64 => x86_64::apply(r#type, values, data, offset),
+            Arch::Aarch64 => aarch64::apply::<T>(r#type, values, data, offset),
+            Arch::Riscv => riscv::apply(r#type, values, data, offset),
+        }
+    }
+}
+
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        x86_64::EM_X86_64 => "x86-64".to_string(),
+        aarch64::EM_AARCH64 => "AArch64".to_string(),
+        riscv::EM_RISCV => "RISC-V".to_string(),
+        i386::EM_386 => "i386".to_string(),
+        arm::EM_ARM => "ARM".to_string(),
+        mips::EM_MIPS => "MIPS".to_string(),
+        _ => format!("machine {}", machine),
+    }
+}
+
+// combine the `e_flags` of two objects being linked together, or say why
+// they can't be
+pub fn merge_flags(machine: u16, flags: u32, other: u32) -> Result<u32, String> {
+    match machine {
+        riscv::EM_RISCV => riscv::merge_flags(flags, other),
+        _ => Ok(flags),
+    }
+}
+
+// the addend of the SHT_REL relocation `relas[index]` against `data`, read
+// from the place it relocates
+pub fn implicit_addend(
+    machine: u16,
+    endianness: nom::number::Endianness,
+    relas: &[RelocationA64],
+    index: usize,
+    data: &[u8],
+) -> Result<i64, LinkError> {
+    use byteorder::{BigEndian, LittleEndian};
+    use nom::number::Endianness::*;
+
+    match endianness {
+        Big => implicit_addend_endian::<BigEndian>(machine, relas, index, data),
+        _ => implicit_addend_endian::<LittleEndian>(machine, relas, index, data),
+    }
+}
+
+fn implicit_addend_endian<T: byteorder::ByteOrder>(
+    machine: u16,
+    relas: &[RelocationA64],
+    index: usize,
+    data: &[u8],
+) -> Result<i64, LinkError> {
+    let r#type = relas[index].get_type();
+    let offset = relas[index].offset as usize;
+    match machine {
+        x86_64::EM_X86_64 => x86_64::implicit_addend(r#type, data, offset),
+        i386::EM_386 => i386::implicit_addend::<T>(r#type, data, offset),
+        arm::EM_ARM => arm::implicit_addend::<T>(r#type, data, offset),
+        mips::EM_MIPS => mips::implicit_addend::<T>(relas, index, data),
+        _ => Err(LinkError::UnsupportedRelocation(format!(
+            "can't read implicit addends for {}",
+            machine_name(machine)
+        ))),
+    }
+}
+
+// the `len` bytes at `offset` in a section, which a relocation there changes
+fn place(data: &[u8], offset: usize, len: usize) -> Result<&[u8], LinkError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| {
+            LinkError::InvalidRelocation(format!(
+                "relocation at {:#x} is past the end of its section",
+                offset
+            ))
+        })
+}
+
+// the same, to be written
+fn place_mut(data: &mut [u8], offset: usize, len: usize) -> Result<&mut [u8], LinkError> {
+    let end = offset.checked_add(len).filter(|end| *end <= data.len());
+    match end {
+        Some(end) => Ok(&mut data[offset..end]),
+        None => Err(LinkError::InvalidRelocation(format!(
+            "relocation at {:#x} is past the end of its section",
+            offset
+        ))),
+    }
+}
+
+// `len` bytes of padding between pieces of code, made of instructions that do
+// nothing in case it ever runs. Bytes too few to make an instruction come
+// first, so that the instructions line up with the code after them.
+pub fn code_fill(machine: u16, len: usize) -> Vec<u8> {
+    let (lead, nops) = match machine {
+        x86_64::EM_X86_64 => (0, x86_64::nops(len)),
+        aarch64::EM_AARCH64 => (len % 4, aarch64::nops(len / 4)),
+        riscv::EM_RISCV => (len % 2, riscv::nops(len - len % 2)),
+        _ => (len, Vec::new()),
+    };
+
+    let mut retval = vec![0; lead];
+    retval.extend(nops);
+
+    retval
+}
+
+// the values that go into computing a relocation, named as in the System V ABI
+#[derive(Debug)]
+pub struct RelocationValues {
+    // value of the symbol (or its PLT entry)
+    pub s: u64,
+    // addend
+    pub a: i64,
+    // address of the place being relocated
+    pub p: u64,
+    // address of the global offset table
+    pub got: u64,
+    // address of the symbol's entry in the global offset table
+    pub g: u64,
+    // size of the symbol
+    pub z: u64,
+    // address of the start of the TLS segment, DTPOFF relocations are
+    // relative to it
+    pub tls: u64,
+    // where the thread pointer would be if the TLS segment was this thread's
+    // TLS block, TPOFF relocations are relative to it
+    pub tp: u64,
+    // value of `__global_pointer$` (RISC-V)
+    pub gp: u64,
+}
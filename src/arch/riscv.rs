@@ -0,0 +1,346 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{place_mut, RelocationValues};
+use crate::link::LinkError;
+
+pub const EM_RISCV: u16 = 243;
+
+pub const R_RISCV_NONE: usize = 0;
+pub const R_RISCV_32: usize = 1;
+pub const R_RISCV_64: usize = 2;
+pub const R_RISCV_BRANCH: usize = 16;
+pub const R_RISCV_JAL: usize = 17;
+pub const R_RISCV_CALL: usize = 18;
+pub const R_RISCV_CALL_PLT: usize = 19;
+pub const R_RISCV_GOT_HI20: usize = 20;
+pub const R_RISCV_PCREL_HI20: usize = 23;
+pub const R_RISCV_PCREL_LO12_I: usize = 24;
+pub const R_RISCV_PCREL_LO12_S: usize = 25;
+pub const R_RISCV_HI20: usize = 26;
+pub const R_RISCV_LO12_I: usize = 27;
+pub const R_RISCV_LO12_S: usize = 28;
+pub const R_RISCV_ADD8: usize = 33;
+pub const R_RISCV_ADD16: usize = 34;
+pub const R_RISCV_ADD32: usize = 35;
+pub const R_RISCV_ADD64: usize = 36;
+pub const R_RISCV_SUB8: usize = 37;
+pub const R_RISCV_SUB16: usize = 38;
+pub const R_RISCV_SUB32: usize = 39;
+pub const R_RISCV_SUB64: usize = 40;
+pub const R_RISCV_ALIGN: usize = 43;
+pub const R_RISCV_RVC_BRANCH: usize = 44;
+pub const R_RISCV_RVC_JUMP: usize = 45;
+// no longer emitted by assemblers, used for loads and stores relaxed to be
+// relative to the global pointer
+pub const R_RISCV_GPREL_I: usize = 47;
+pub const R_RISCV_GPREL_S: usize = 48;
+pub const R_RISCV_RELAX: usize = 51;
+pub const R_RISCV_SUB6: usize = 52;
+pub const R_RISCV_SET6: usize = 53;
+pub const R_RISCV_SET8: usize = 54;
+pub const R_RISCV_SET16: usize = 55;
+pub const R_RISCV_SET32: usize = 56;
+pub const R_RISCV_32_PCREL: usize = 57;
+
+// `e_flags` bits
+pub const EF_RISCV_RVC: u32 = 0x1;
+pub const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+pub const EF_RISCV_RVE: u32 = 0x8;
+pub const EF_RISCV_TSO: u32 = 0x10;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+// defined by the linker in the small data section when it's referenced
+pub const GLOBAL_POINTER_SYMBOL: &str = "__global_pointer$";
+
+// `jal` reaches +-1MiB
+const JAL_RANGE: i64 = 1 << 20;
+// loads, stores and `addi` take a 12-bit signed immediate
+const LO12_RANGE: i64 = 1 << 11;
+
+const GP_REGISTER: u32 = 3;
+
+// the float ABIs have to match, RVE code can't be mixed with RVI code, and
+// the output uses compressed instructions or TSO if any input does
+pub fn merge_flags(flags: u32, other: u32) -> Result<u32, String> {
+    if flags & EF_RISCV_FLOAT_ABI != other & EF_RISCV_FLOAT_ABI {
+        return Err(format!(
+            "cannot link {} float ABI code with {} float ABI code",
+            float_abi_name(flags),
+            float_abi_name(other)
+        ));
+    }
+    if flags & EF_RISCV_RVE != other & EF_RISCV_RVE {
+        return Err("cannot link RVE code with non-RVE code".to_string());
+    }
+
+    Ok(flags | (other & (EF_RISCV_RVC | EF_RISCV_TSO)))
+}
+
+fn float_abi_name(flags: u32) -> &'static str {
+    match flags & EF_RISCV_FLOAT_ABI {
+        0x0 => "soft",
+        0x2 => "single",
+        0x4 => "double",
+        _ => "quad",
+    }
+}
+
+pub fn needs_got(r#type: usize) -> bool {
+    r#type == R_RISCV_GOT_HI20
+}
+
+// relocations that bake an absolute address into the output
+pub fn is_absolute(r#type: usize) -> bool {
+    matches!(
+        r#type,
+        R_RISCV_32 | R_RISCV_64 | R_RISCV_HI20 | R_RISCV_LO12_I | R_RISCV_LO12_S
+    )
+}
+
+pub fn is_pcrel_hi(r#type: usize) -> bool {
+    r#type == R_RISCV_PCREL_HI20 || r#type == R_RISCV_GOT_HI20
+}
+
+pub fn is_pcrel_lo(r#type: usize) -> bool {
+    r#type == R_RISCV_PCREL_LO12_I || r#type == R_RISCV_PCREL_LO12_S
+}
+
+// can an `auipc`/`jalr` pair at `p` be replaced with a `jal` to `target`?
+pub fn jal_in_range(p: u64, target: u64) -> bool {
+    let disp = target.wrapping_sub(p) as i64;
+    (-JAL_RANGE..JAL_RANGE).contains(&disp)
+}
+
+// can `addr` be reached with a 12-bit offset from the global pointer?
+pub fn gp_in_range(gp: u64, addr: u64) -> bool {
+    let disp = addr.wrapping_sub(gp) as i64;
+    (-LO12_RANGE..LO12_RANGE).contains(&disp)
+}
+
+// the destination register of the `jalr` in a call sequence
+pub fn call_rd(data: &[u8], offset: usize) -> u32 {
+    (LittleEndian::read_u32(&data[offset + 4..]) >> 7) & 0x1f
+}
+
+pub fn jal(rd: u32) -> u32 {
+    0x6f | rd << 7
+}
+
+// switch the base register of a load, store or `addi` to the global pointer
+pub fn use_gp(data: &mut [u8], offset: usize) {
+    patch(&mut data[offset..], !(0x1f << 15), GP_REGISTER << 15);
+}
+
+// `count` bytes of nops, `addi x0, x0, 0` and a `c.nop` if needed
+pub fn nops(count: usize) -> Vec<u8> {
+    let mut retval = Vec::new();
+    for _ in 0..count / 4 {
+        retval.extend_from_slice(&[0x13, 0x00, 0x00, 0x00]);
+    }
+    if !count.is_multiple_of(4) {
+        retval.extend_from_slice(&[0x01, 0x00]);
+    }
+
+    retval
+}
+
+pub fn name(r#type: usize) -> String {
+    match r#type {
+        R_RISCV_32 => "R_RISCV_32".to_string(),
+        R_RISCV_64 => "R_RISCV_64".to_string(),
+        R_RISCV_BRANCH => "R_RISCV_BRANCH".to_string(),
+        R_RISCV_JAL => "R_RISCV_JAL".to_string(),
+        R_RISCV_CALL => "R_RISCV_CALL".to_string(),
+        R_RISCV_CALL_PLT => "R_RISCV_CALL_PLT".to_string(),
+        R_RISCV_PCREL_HI20 => "R_RISCV_PCREL_HI20".to_string(),
+        R_RISCV_PCREL_LO12_I => "R_RISCV_PCREL_LO12_I".to_string(),
+        R_RISCV_PCREL_LO12_S => "R_RISCV_PCREL_LO12_S".to_string(),
+        R_RISCV_HI20 => "R_RISCV_HI20".to_string(),
+        R_RISCV_GPREL_I => "R_RISCV_GPREL_I".to_string(),
+        R_RISCV_GPREL_S => "R_RISCV_GPREL_S".to_string(),
+        R_RISCV_RVC_BRANCH => "R_RISCV_RVC_BRANCH".to_string(),
+        R_RISCV_RVC_JUMP => "R_RISCV_RVC_JUMP".to_string(),
+        _ => format!("relocation type {}", r#type),
+    }
+}
+
+// how many bytes a relocation changes
+fn width(r#type: usize) -> usize {
+    match r#type {
+        R_RISCV_NONE | R_RISCV_RELAX | R_RISCV_ALIGN => 0,
+        R_RISCV_ADD8 | R_RISCV_SUB6 | R_RISCV_SUB8 | R_RISCV_SET6 | R_RISCV_SET8 => 1,
+        R_RISCV_ADD16 | R_RISCV_SUB16 | R_RISCV_SET16 | R_RISCV_RVC_BRANCH | R_RISCV_RVC_JUMP => 2,
+        R_RISCV_64 | R_RISCV_ADD64 | R_RISCV_SUB64 | R_RISCV_CALL | R_RISCV_CALL_PLT => 8,
+        _ => 4,
+    }
+}
+
+// PCREL_LO12 relocations refer to the `auipc` with the matching HI20
+// relocation, `s` is the value of that relocation
+pub fn apply(
+    r#type: usize,
+    values: &RelocationValues,
+    data: &mut [u8],
+    offset: usize,
+) -> Result<(), LinkError> {
+    let RelocationValues { s, a, p, g, gp, .. } = *values;
+    let place = place_mut(data, offset, width(r#type))?;
+    let sa = s.wrapping_add(a as u64);
+    let pcrel = sa.wrapping_sub(p) as i64;
+    let gprel = sa.wrapping_sub(gp) as i64;
+
+    match r#type {
+        R_RISCV_NONE | R_RISCV_RELAX | R_RISCV_ALIGN => {}
+        R_RISCV_32 => LittleEndian::write_u32(place, sa as u32),
+        R_RISCV_64 => LittleEndian::write_u64(place, sa),
+        R_RISCV_32_PCREL => LittleEndian::write_u32(place, fits(pcrel, 32, &name(r#type))? as u32),
+        R_RISCV_HI20 => patch(place, 0xfff, hi20(fits_hi20(sa)?)),
+        R_RISCV_LO12_I => patch(place, 0xfffff, i_imm(sa as i64)),
+        R_RISCV_LO12_S => patch(place, 0x1fff07f, s_imm(sa as i64)),
+        R_RISCV_PCREL_HI20 => patch(place, 0xfff, hi20(fits(pcrel, 32, &name(r#type))?)),
+        R_RISCV_GOT_HI20 => {
+            let value = g.wrapping_add(a as u64).wrapping_sub(p) as i64;
+            patch(place, 0xfff, hi20(fits(value, 32, "R_RISCV_GOT_HI20")?))
+        }
+        R_RISCV_PCREL_LO12_I => patch(place, 0xfffff, i_imm(sa as i64)),
+        R_RISCV_PCREL_LO12_S => patch(place, 0x1fff07f, s_imm(sa as i64)),
+        R_RISCV_GPREL_I => patch(place, 0xfffff, i_imm(fits(gprel, 12, &name(r#type))?)),
+        R_RISCV_GPREL_S => patch(place, 0x1fff07f, s_imm(fits(gprel, 12, &name(r#type))?)),
+        R_RISCV_CALL | R_RISCV_CALL_PLT => {
+            let value = fits(pcrel, 32, &name(r#type))?;
+            patch(place, 0xfff, hi20(value));
+            patch(&mut place[4..], 0xfffff, i_imm(value));
+        }
+        R_RISCV_JAL => {
+            let imm = fits(pcrel, 21, &name(r#type))? as u32;
+            let bits = (imm >> 20 & 1) << 31
+                | (imm >> 1 & 0x3ff) << 21
+                | (imm >> 11 & 1) << 20
+                | (imm >> 12 & 0xff) << 12;
+            patch(place, 0xfff, bits)
+        }
+        R_RISCV_BRANCH => {
+            let imm = fits(pcrel, 13, &name(r#type))? as u32;
+            let bits = (imm >> 12 & 1) << 31
+                | (imm >> 5 & 0x3f) << 25
+                | (imm >> 1 & 0xf) << 8
+                | (imm >> 11 & 1) << 7;
+            patch(place, 0x1fff07f, bits)
+        }
+        R_RISCV_RVC_BRANCH => {
+            let imm = fits(pcrel, 9, &name(r#type))? as u16;
+            let bits = (imm >> 8 & 1) << 12
+                | (imm >> 3 & 0x3) << 10
+                | (imm >> 6 & 0x3) << 5
+                | (imm >> 1 & 0x3) << 3
+                | (imm >> 5 & 1) << 2;
+            patch16(place, 0xe383, bits)
+        }
+        R_RISCV_RVC_JUMP => {
+            let imm = fits(pcrel, 12, &name(r#type))? as u16;
+            let bits = (imm >> 11 & 1) << 12
+                | (imm >> 4 & 1) << 11
+                | (imm >> 8 & 0x3) << 9
+                | (imm >> 10 & 1) << 8
+                | (imm >> 6 & 1) << 7
+                | (imm >> 7 & 1) << 6
+                | (imm >> 1 & 0x7) << 3
+                | (imm >> 5 & 1) << 2;
+            patch16(place, 0xe003, bits)
+        }
+        // ADD and SUB pairs compute the distance between two symbols, e.g.
+        // the length of a function in .eh_frame
+        R_RISCV_ADD8 => place[0] = place[0].wrapping_add(sa as u8),
+        R_RISCV_ADD16 => {
+            let value = LittleEndian::read_u16(place).wrapping_add(sa as u16);
+            LittleEndian::write_u16(place, value)
+        }
+        R_RISCV_ADD32 => {
+            let value = LittleEndian::read_u32(place).wrapping_add(sa as u32);
+            LittleEndian::write_u32(place, value)
+        }
+        R_RISCV_ADD64 => {
+            let value = LittleEndian::read_u64(place).wrapping_add(sa);
+            LittleEndian::write_u64(place, value)
+        }
+        R_RISCV_SUB6 => place[0] = (place[0] & 0xc0) | (place[0].wrapping_sub(sa as u8) & 0x3f),
+        R_RISCV_SUB8 => place[0] = place[0].wrapping_sub(sa as u8),
+        R_RISCV_SUB16 => {
+            let value = LittleEndian::read_u16(place).wrapping_sub(sa as u16);
+            LittleEndian::write_u16(place, value)
+        }
+        R_RISCV_SUB32 => {
+            let value = LittleEndian::read_u32(place).wrapping_sub(sa as u32);
+            LittleEndian::write_u32(place, value)
+        }
+        R_RISCV_SUB64 => {
+            let value = LittleEndian::read_u64(place).wrapping_sub(sa);
+            LittleEndian::write_u64(place, value)
+        }
+        R_RISCV_SET6 => place[0] = (place[0] & 0xc0) | (sa as u8 & 0x3f),
+        R_RISCV_SET8 => place[0] = sa as u8,
+        R_RISCV_SET16 => LittleEndian::write_u16(place, sa as u16),
+        R_RISCV_SET32 => LittleEndian::write_u32(place, sa as u32),
+        _ => {
+            return Err(LinkError::UnsupportedRelocation(format!(
+                "unsupported relocation {}",
+                name(r#type)
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+// the upper 20 bits, rounded so that adding the sign-extended lower 12 bits
+// gives back `value`
+fn hi20(value: i64) -> u32 {
+    (value.wrapping_add(0x800) as u32) & 0xfffff000
+}
+
+fn i_imm(value: i64) -> u32 {
+    (value as u32 & 0xfff) << 20
+}
+
+fn s_imm(value: i64) -> u32 {
+    let lo = value as u32 & 0xfff;
+    (lo >> 5) << 25 | (lo & 0x1f) << 7
+}
+
+// `lui` loads a 32-bit value, sign extended on RV64. Addresses on RV32 are
+// unsigned, and wrap the same way, so either works.
+fn fits_hi20(value: u64) -> Result<i64, LinkError> {
+    let value = value as i64;
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(LinkError::Overflow(
+            "relocation overflow in R_RISCV_HI20".to_string(),
+        ));
+    }
+
+    Ok(value)
+}
+
+// does `value` fit in a `bits`-bit signed immediate?
+fn fits(value: i64, bits: u32, what: &str) -> Result<i64, LinkError> {
+    let range = 1_i64 << (bits - 1);
+    if value < -range || value >= range {
+        return Err(LinkError::Overflow(format!(
+            "relocation overflow in {}",
+            what
+        )));
+    }
+
+    Ok(value)
+}
+
+// instructions are always little endian
+fn patch(place: &mut [u8], keep: u32, bits: u32) {
+    let insn = LittleEndian::read_u32(place);
+    LittleEndian::write_u32(place, (insn & keep) | bits);
+}
+
+fn patch16(place: &mut [u8], keep: u16, bits: u16) {
+    let insn = LittleEndian::read_u16(place);
+    LittleEndian::write_u16(place, (insn & keep) | bits);
+}
@@ -0,0 +1,327 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{place, place_mut, RelocationValues};
+use crate::link::LinkError;
+
+pub const EM_X86_64: u16 = 62;
+
+pub const R_X86_64_NONE: usize = 0;
+pub const R_X86_64_64: usize = 1;
+pub const R_X86_64_PC32: usize = 2;
+pub const R_X86_64_PLT32: usize = 4;
+pub const R_X86_64_GLOB_DAT: usize = 6;
+pub const R_X86_64_RELATIVE: usize = 8;
+pub const R_X86_64_GOTPCREL: usize = 9;
+pub const R_X86_64_32: usize = 10;
+pub const R_X86_64_32S: usize = 11;
+pub const R_X86_64_DTPMOD64: usize = 16;
+pub const R_X86_64_DTPOFF64: usize = 17;
+pub const R_X86_64_TPOFF64: usize = 18;
+pub const R_X86_64_TLSGD: usize = 19;
+pub const R_X86_64_TLSLD: usize = 20;
+pub const R_X86_64_DTPOFF32: usize = 21;
+pub const R_X86_64_GOTTPOFF: usize = 22;
+pub const R_X86_64_TPOFF32: usize = 23;
+pub const R_X86_64_PC64: usize = 24;
+pub const R_X86_64_GOTOFF64: usize = 25;
+pub const R_X86_64_GOTPC32: usize = 26;
+pub const R_X86_64_SIZE32: usize = 32;
+pub const R_X86_64_SIZE64: usize = 33;
+pub const R_X86_64_GOTPC32_TLSDESC: usize = 34;
+pub const R_X86_64_TLSDESC_CALL: usize = 35;
+pub const R_X86_64_GOTPCRELX: usize = 41;
+pub const R_X86_64_REX_GOTPCRELX: usize = 42;
+
+pub const PLT_ENTRY_SIZE: usize = 8;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+pub fn needs_got(r#type: usize) -> bool {
+    matches!(
+        r#type,
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX
+    )
+}
+
+pub fn needs_plt(r#type: usize) -> bool {
+    r#type == R_X86_64_PLT32
+}
+
+// relocations that bake an absolute address into the output, and so need a
+// dynamic relocation if the output can be loaded anywhere
+pub fn is_absolute(r#type: usize) -> bool {
+    matches!(r#type, R_X86_64_64 | R_X86_64_32 | R_X86_64_32S)
+}
+
+pub fn name(r#type: usize) -> String {
+    match r#type {
+        R_X86_64_64 => "R_X86_64_64".to_string(),
+        R_X86_64_PC32 => "R_X86_64_PC32".to_string(),
+        R_X86_64_32 => "R_X86_64_32".to_string(),
+        R_X86_64_32S => "R_X86_64_32S".to_string(),
+        R_X86_64_TLSGD => "R_X86_64_TLSGD".to_string(),
+        R_X86_64_TLSLD => "R_X86_64_TLSLD".to_string(),
+        R_X86_64_GOTTPOFF => "R_X86_64_GOTTPOFF".to_string(),
+        R_X86_64_TPOFF32 => "R_X86_64_TPOFF32".to_string(),
+        R_X86_64_GOTPC32_TLSDESC => "R_X86_64_GOTPC32_TLSDESC".to_string(),
+        R_X86_64_TLSDESC_CALL => "R_X86_64_TLSDESC_CALL".to_string(),
+        _ => format!("relocation type {}", r#type),
+    }
+}
+
+// a non-lazy PLT entry, `jmp *got_entry(%rip)` padded with a 2-byte nop
+pub fn plt_entry(entry_addr: u64, got_entry_addr: u64) -> Result<Vec<u8>, LinkError> {
+    let mut retval = vec![0xff, 0x25, 0, 0, 0, 0, 0x66, 0x90];
+    let disp = got_entry_addr as i64 - (entry_addr as i64 + 6);
+    LittleEndian::write_i32(&mut retval[2..6], fits_i32(disp, "PLT entry")?);
+
+    Ok(retval)
+}
+
+// the recommended multi-byte nops, by length
+const NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0f, 0x1f, 0x00],
+    &[0x0f, 0x1f, 0x40, 0x00],
+    &[0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+// `len` bytes of nops, as few as possible
+pub fn nops(mut len: usize) -> Vec<u8> {
+    let mut retval = Vec::new();
+    while len > 0 {
+        let nop = NOPS[std::cmp::min(len, NOPS.len()) - 1];
+        retval.extend_from_slice(nop);
+        len -= nop.len();
+    }
+
+    retval
+}
+
+// GOTPCRELX relaxation, for symbols known to be defined in the output
+//   mov foo@GOTPCREL(%rip), %reg  becomes  lea foo(%rip), %reg
+//   call *foo@GOTPCREL(%rip)      becomes  addr32 call foo
+//   jmp *foo@GOTPCREL(%rip)       becomes  jmp foo; nop
+// returns the offset of the PC32 relocation that replaces the GOTPCRELX one,
+// or None if the instruction can't be relaxed
+pub fn relax_gotpcrelx(data: &mut [u8], offset: usize) -> Option<usize> {
+    if offset < 2 || data.len() < offset + 4 {
+        return None;
+    }
+
+    match (data[offset - 2], data[offset - 1]) {
+        (0x8b, _) => {
+            data[offset - 2] = 0x8d;
+            Some(offset)
+        }
+        (0xff, 0x15) => {
+            data[offset - 2..offset].copy_from_slice(&[0x67, 0xe8]);
+            Some(offset)
+        }
+        (0xff, 0x25) => {
+            data[offset - 2] = 0xe9;
+            data[offset + 3] = 0x90;
+            Some(offset - 1)
+        }
+        _ => None,
+    }
+}
+
+fn unexpected(r#type: usize) -> LinkError {
+    LinkError::InvalidRelocation(format!(
+        "unexpected instruction sequence for {}",
+        name(r#type)
+    ))
+}
+
+// general dynamic to local exec, turns
+//   lea x@tlsgd(%rip), %rdi; call __tls_get_addr@plt
+// or, with -fno-plt,
+//   lea x@tlsgd(%rip), %rdi; call *__tls_get_addr@GOTPCREL(%rip)
+// into
+//   mov %fs:0, %rax; lea x@tpoff(%rax), %rax
+// `offset` is that of the TLSGD relocation, the TPOFF32 value goes 8 bytes
+// after it (where the call's relocation was)
+pub fn relax_tls_gd_to_le(data: &mut [u8], offset: usize) -> Result<(), LinkError> {
+    const GD: [u8; 4] = [0x66, 0x48, 0x8d, 0x3d];
+    const CALL: [u8; 4] = [0x66, 0x66, 0x48, 0xe8];
+    const INDIRECT_CALL: [u8; 4] = [0x66, 0x48, 0xff, 0x15];
+
+    if offset < 4 || data.get(offset - 4..offset) != Some(&GD[..]) {
+        return Err(unexpected(R_X86_64_TLSGD));
+    }
+    match data.get(offset + 4..offset + 8) {
+        Some(call) if call == CALL || call == INDIRECT_CALL => {}
+        _ => return Err(unexpected(R_X86_64_TLSGD)),
+    }
+
+    data[offset - 4..offset + 8]
+        .copy_from_slice(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0x48, 0x8d, 0x80]);
+
+    Ok(())
+}
+
+// local dynamic to local exec, turns
+//   lea x@tlsld(%rip), %rdi; call __tls_get_addr@plt
+// or, with -fno-plt,
+//   lea x@tlsld(%rip), %rdi; call *__tls_get_addr@GOTPCREL(%rip)
+// into
+//   mov %fs:0, %rax (with prefixes to take up the same space)
+// after which DTPOFF32 offsets are relative to the thread pointer
+pub fn relax_tls_ld_to_le(data: &mut [u8], offset: usize) -> Result<(), LinkError> {
+    const LD: [u8; 3] = [0x48, 0x8d, 0x3d];
+
+    if offset < 3 || data.get(offset - 3..offset) != Some(&LD[..]) {
+        return Err(unexpected(R_X86_64_TLSLD));
+    }
+    let prefixes = match data.get(offset + 4..offset + 6) {
+        Some([0xe8, _]) if data.len() >= offset + 9 => 3,
+        Some([0xff, 0x15]) if data.len() >= offset + 10 => 4,
+        _ => return Err(unexpected(R_X86_64_TLSLD)),
+    };
+
+    let mut mov = vec![0x66; prefixes];
+    mov.extend_from_slice(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0]);
+    data[offset - 3..offset - 3 + mov.len()].copy_from_slice(&mov);
+
+    Ok(())
+}
+
+// can the instruction using a GOTTPOFF relocation at `offset` be relaxed to
+// local exec, only `mov` and `add` from memory into a register can
+pub fn can_relax_tls_ie(data: &[u8], offset: usize) -> bool {
+    if offset < 3 {
+        return false;
+    }
+
+    let (rex, opcode, modrm) = (data[offset - 3], data[offset - 2], data[offset - 1]);
+    (rex == 0x48 || rex == 0x4c) && (opcode == 0x8b || opcode == 0x03) && modrm & 0xc7 == 0x05
+}
+
+// initial exec to local exec, turns
+//   mov x@gottpoff(%rip), %reg  into  mov $x@tpoff, %reg
+//   add x@gottpoff(%rip), %reg  into  add $x@tpoff, %reg
+// the TPOFF32 value goes where the GOTTPOFF one was
+pub fn relax_tls_ie_to_le(data: &mut [u8], offset: usize) -> Result<(), LinkError> {
+    if !can_relax_tls_ie(data, offset) {
+        return Err(unexpected(R_X86_64_GOTTPOFF));
+    }
+
+    let reg = (data[offset - 1] >> 3) & 0x7;
+    // the register moves from the reg field (REX.R) to the r/m one (REX.B)
+    if data[offset - 3] == 0x4c {
+        data[offset - 3] = 0x49;
+    }
+    data[offset - 2] = if data[offset - 2] == 0x8b { 0xc7 } else { 0x81 };
+    data[offset - 1] = 0xc0 | reg;
+
+    Ok(())
+}
+
+// how many bytes a relocation changes
+fn width(r#type: usize) -> usize {
+    match r#type {
+        R_X86_64_NONE => 0,
+        R_X86_64_64 | R_X86_64_DTPOFF64 | R_X86_64_PC64 | R_X86_64_GOTOFF64 | R_X86_64_SIZE64 => 8,
+        _ => 4,
+    }
+}
+
+// the addend of a SHT_REL relocation, stored in the place being relocated
+pub fn implicit_addend(r#type: usize, data: &[u8], offset: usize) -> Result<i64, LinkError> {
+    Ok(match r#type {
+        R_X86_64_NONE => 0,
+        R_X86_64_64 | R_X86_64_DTPOFF64 | R_X86_64_PC64 | R_X86_64_GOTOFF64 | R_X86_64_SIZE64 => {
+            LittleEndian::read_i64(place(data, offset, 8)?)
+        }
+        R_X86_64_32 => LittleEndian::read_u32(place(data, offset, 4)?) as i64,
+        _ => LittleEndian::read_i32(place(data, offset, 4)?) as i64,
+    })
+}
+
+pub fn apply(
+    r#type: usize,
+    values: &RelocationValues,
+    data: &mut [u8],
+    offset: usize,
+) -> Result<(), LinkError> {
+    let RelocationValues {
+        s,
+        a,
+        p,
+        got,
+        g,
+        z,
+        tls,
+        tp,
+        ..
+    } = *values;
+    let (s, p, got, g, z) = (s as i64, p as i64, got as i64, g as i64, z as i64);
+    let (tls, tp) = (tls as i64, tp as i64);
+    let place = place_mut(data, offset, width(r#type))?;
+    let sa = s.wrapping_add(a);
+
+    match r#type {
+        R_X86_64_NONE => {}
+        R_X86_64_64 => LittleEndian::write_i64(place, sa),
+        R_X86_64_PC32 | R_X86_64_PLT32 => {
+            LittleEndian::write_i32(place, fits_i32(sa.wrapping_sub(p), &name(r#type))?)
+        }
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX => LittleEndian::write_i32(
+            place,
+            fits_i32(g.wrapping_add(a).wrapping_sub(p), "GOTPCREL")?,
+        ),
+        R_X86_64_32 => {
+            if sa < 0 || sa > u32::MAX as i64 {
+                return Err(overflow("R_X86_64_32"));
+            }
+            LittleEndian::write_u32(place, sa as u32)
+        }
+        R_X86_64_32S => LittleEndian::write_i32(place, fits_i32(sa, "R_X86_64_32S")?),
+        R_X86_64_DTPOFF64 => LittleEndian::write_i64(place, sa.wrapping_sub(tls)),
+        R_X86_64_TLSGD | R_X86_64_TLSLD | R_X86_64_GOTTPOFF => LittleEndian::write_i32(
+            place,
+            fits_i32(g.wrapping_add(a).wrapping_sub(p), &name(r#type))?,
+        ),
+        R_X86_64_DTPOFF32 => {
+            LittleEndian::write_i32(place, fits_i32(sa.wrapping_sub(tls), "DTPOFF32")?)
+        }
+        R_X86_64_TPOFF32 => {
+            LittleEndian::write_i32(place, fits_i32(sa.wrapping_sub(tp), "TPOFF32")?)
+        }
+        R_X86_64_PC64 => LittleEndian::write_i64(place, sa.wrapping_sub(p)),
+        R_X86_64_GOTOFF64 => LittleEndian::write_i64(place, sa.wrapping_sub(got)),
+        R_X86_64_GOTPC32 => LittleEndian::write_i32(
+            place,
+            fits_i32(got.wrapping_add(a).wrapping_sub(p), "GOTPC32")?,
+        ),
+        R_X86_64_SIZE32 => LittleEndian::write_i32(place, fits_i32(z.wrapping_add(a), "SIZE32")?),
+        R_X86_64_SIZE64 => LittleEndian::write_i64(place, z.wrapping_add(a)),
+        // TLS descriptors among them
+        _ => {
+            return Err(LinkError::UnsupportedRelocation(format!(
+                "unsupported relocation {}",
+                name(r#type)
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+fn overflow(what: &str) -> LinkError {
+    LinkError::Overflow(format!("relocation overflow in {}", what))
+}
+
+fn fits_i32(value: i64, what: &str) -> Result<i32, LinkError> {
+    if value < i32::MIN as i64 || value > i32::MAX as i64 {
+        return Err(overflow(what));
+    }
+
+    Ok(value as i32)
+}
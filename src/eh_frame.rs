@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use byteorder::ByteOrder;
+
+// DWARF pointer encodings, the format in the low bits and what the pointer
+// is relative to in the high ones
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+const DW_EH_PE_OMIT: u8 = 0xff;
+
+// the version and three encodings, the pointer to .eh_frame and the number
+// of entries, then a pair of addresses for each
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 8;
+
+// a CIE or FDE: where it starts, where its contents start, where it ends
+struct Record {
+    start: usize,
+    contents: usize,
+    end: usize,
+}
+
+// the CIEs and FDEs in .eh_frame, stopping at anything malformed
+fn records<T: ByteOrder>(data: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let (length, contents) = match T::read_u32(&data[offset..]) {
+            // the end of a list, but inputs' lists are merged one after
+            // another
+            0 => {
+                offset += 4;
+                continue;
+            }
+            0xffff_ffff if offset + 12 <= data.len() => {
+                (T::read_u64(&data[offset + 4..]) as usize, offset + 12)
+            }
+            0xffff_ffff => break,
+            length => (length as usize, offset + 4),
+        };
+        let end = match contents.checked_add(length) {
+            Some(end) if end <= data.len() && length >= 4 => end,
+            _ => break,
+        };
+        records.push(Record {
+            start: offset,
+            contents,
+            end,
+        });
+        offset = end;
+    }
+
+    records
+}
+
+// the CIE pointer of an FDE, which is 0 for a CIE
+fn cie_pointer<T: ByteOrder>(data: &[u8], record: &Record) -> u32 {
+    T::read_u32(&data[record.contents..])
+}
+
+fn read_uleb128(data: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*offset)?;
+        *offset += 1;
+        if shift < 64 {
+            value |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+// the size of a pointer with `encoding`, `None` for ones we can't read
+fn encoded_size(encoding: u8, word: usize) -> Option<usize> {
+    match encoding & 0x0f {
+        DW_EH_PE_ABSPTR => Some(word),
+        DW_EH_PE_UDATA2 | DW_EH_PE_SDATA2 => Some(2),
+        DW_EH_PE_UDATA4 | DW_EH_PE_SDATA4 => Some(4),
+        DW_EH_PE_UDATA8 | DW_EH_PE_SDATA8 => Some(8),
+        _ => None,
+    }
+}
+
+// read a pointer with `encoding` at `offset` of .eh_frame, which is loaded
+// at `addr`
+fn read_encoded<T: ByteOrder>(
+    data: &[u8],
+    offset: usize,
+    encoding: u8,
+    word: usize,
+    addr: u64,
+) -> Option<u64> {
+    let size = encoded_size(encoding, word)?;
+    let bytes = data.get(offset..offset + size)?;
+    let value = match (encoding & 0x0f, size) {
+        (DW_EH_PE_SDATA2, _) => T::read_i16(bytes) as u64,
+        (DW_EH_PE_SDATA4, _) => T::read_i32(bytes) as u64,
+        (_, 2) => T::read_u16(bytes) as u64,
+        (_, 4) => T::read_u32(bytes) as u64,
+        _ => T::read_u64(bytes),
+    };
+
+    match encoding & 0x70 {
+        0 => Some(value),
+        DW_EH_PE_PCREL => Some(value.wrapping_add(addr + offset as u64)),
+        _ => None,
+    }
+}
+
+// the encoding of the start address of FDEs using a CIE, from its `R`
+// augmentation
+fn fde_encoding(data: &[u8], cie: &Record, word: usize) -> Option<u8> {
+    let mut offset = cie.contents + 4;
+    let version = *data.get(offset)?;
+    offset += 1;
+    let augmentation_end = offset + data[offset..cie.end].iter().position(|b| *b == 0)?;
+    let augmentation = &data[offset..augmentation_end];
+    offset = augmentation_end + 1;
+
+    // code alignment, data alignment, return address register
+    read_uleb128(data, &mut offset)?;
+    read_uleb128(data, &mut offset)?;
+    if version == 1 {
+        offset += 1;
+    } else {
+        read_uleb128(data, &mut offset)?;
+    }
+
+    if augmentation.first() != Some(&b'z') {
+        return Some(DW_EH_PE_ABSPTR);
+    }
+    read_uleb128(data, &mut offset)?;
+    for c in augmentation[1..].iter() {
+        match c {
+            b'R' => return data.get(offset).copied(),
+            b'L' => offset += 1,
+            b'P' => {
+                let encoding = *data.get(offset)?;
+                offset += 1 + encoded_size(encoding, word)?;
+            }
+            b'S' | b'B' => {}
+            _ => return None,
+        }
+    }
+
+    Some(DW_EH_PE_ABSPTR)
+}
+
+// how big .eh_frame_hdr is for `eh_frame`, so it can be laid out before
+// .eh_frame is relocated
+pub(crate) fn header_size<T: ByteOrder>(eh_frame: &[u8]) -> usize {
+    let records = records::<T>(eh_frame);
+    let fdes = records
+        .iter()
+        .filter(|r| cie_pointer::<T>(eh_frame, r) != 0)
+        .count();
+
+    HEADER_SIZE + fdes * ENTRY_SIZE
+}
+
+// build .eh_frame_hdr at `addr` for `eh_frame` (relocated, at
+// `eh_frame_addr`): a pointer to .eh_frame and a table of the FDEs sorted by
+// the address they start at, for unwinders to binary search. If an FDE
+// can't be read, the table is left out, and the rest is padding.
+pub(crate) fn header<T: ByteOrder>(
+    eh_frame: &[u8],
+    eh_frame_addr: u64,
+    addr: u64,
+    word: usize,
+) -> Vec<u8> {
+    let records = records::<T>(eh_frame);
+    let encodings: HashMap<usize, Option<u8>> = records
+        .iter()
+        .filter(|r| cie_pointer::<T>(eh_frame, r) == 0)
+        .map(|cie| (cie.start, fde_encoding(eh_frame, cie, word)))
+        .collect();
+
+    let mut table = Some(Vec::new());
+    for record in records.iter() {
+        let pointer = cie_pointer::<T>(eh_frame, record);
+        if pointer == 0 {
+            continue;
+        }
+        let entry = record
+            .contents
+            .checked_sub(pointer as usize)
+            .and_then(|cie_start| {
+                let encoding = (*encodings.get(&cie_start)?)?;
+                read_encoded::<T>(eh_frame, record.contents + 4, encoding, word, eh_frame_addr)
+            })
+            .map(|start| (start, eh_frame_addr + record.start as u64));
+        table = match (table, entry) {
+            (Some(mut table), Some(entry)) => {
+                table.push(entry);
+                Some(table)
+            }
+            _ => None,
+        };
+    }
+
+    let mut data = vec![0; HEADER_SIZE];
+    data[0] = 1;
+    data[1] = DW_EH_PE_PCREL | DW_EH_PE_SDATA4;
+    T::write_i32(&mut data[4..], eh_frame_addr.wrapping_sub(addr + 4) as i32);
+    match table {
+        Some(mut table) => {
+            table.sort();
+            data[2] = DW_EH_PE_UDATA4;
+            data[3] = DW_EH_PE_DATAREL | DW_EH_PE_SDATA4;
+            T::write_u32(&mut data[8..], table.len() as u32);
+            for (start, fde) in table {
+                let mut entry = [0; ENTRY_SIZE];
+                T::write_i32(&mut entry[..], start.wrapping_sub(addr) as i32);
+                T::write_i32(&mut entry[4..], fde.wrapping_sub(addr) as i32);
+                data.extend_from_slice(&entry);
+            }
+        }
+        None => {
+            data[2] = DW_EH_PE_OMIT;
+            data[3] = DW_EH_PE_OMIT;
+        }
+    }
+
+    data
+}
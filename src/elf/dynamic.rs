@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use super::section::{Section64, SectionType64, SHF_ALLOC, SHF_WRITE};
+use super::strtab;
+use super::symbol::Symbol64;
+use super::ELFCLASS64;
+
+pub const DT_NULL: u64 = 0;
+pub const DT_NEEDED: u64 = 1;
+pub const DT_HASH: u64 = 4;
+pub const DT_STRTAB: u64 = 5;
+pub const DT_SYMTAB: u64 = 6;
+pub const DT_RELA: u64 = 7;
+pub const DT_RELASZ: u64 = 8;
+pub const DT_RELAENT: u64 = 9;
+pub const DT_STRSZ: u64 = 10;
+pub const DT_SYMENT: u64 = 11;
+pub const DT_SONAME: u64 = 14;
+pub const DT_TEXTREL: u64 = 22;
+pub const DT_INIT_ARRAY: u64 = 25;
+pub const DT_FINI_ARRAY: u64 = 26;
+pub const DT_INIT_ARRAYSZ: u64 = 27;
+pub const DT_FINI_ARRAYSZ: u64 = 28;
+pub const DT_RUNPATH: u64 = 29;
+pub const DT_FLAGS: u64 = 30;
+pub const DT_PREINIT_ARRAY: u64 = 32;
+pub const DT_PREINIT_ARRAYSZ: u64 = 33;
+pub const DT_GNU_HASH: u64 = 0x6ffffef5;
+pub const DT_VERSYM: u64 = 0x6ffffff0;
+pub const DT_RELACOUNT: u64 = 0x6ffffff9;
+pub const DT_FLAGS_1: u64 = 0x6ffffffb;
+pub const DT_VERDEF: u64 = 0x6ffffffc;
+pub const DT_VERDEFNUM: u64 = 0x6ffffffd;
+pub const DT_VERNEED: u64 = 0x6ffffffe;
+pub const DT_VERNEEDNUM: u64 = 0x6fffffff;
+
+pub const DF_TEXTREL: u64 = 0x4;
+pub const DF_BIND_NOW: u64 = 0x8;
+pub const DF_STATIC_TLS: u64 = 0x10;
+
+pub const DF_1_NOW: u64 = 0x1;
+
+#[derive(Clone, Debug)]
+pub struct Dynamic64 {
+    pub tag: u64,
+    pub val: u64,
+}
+
+impl Dynamic64 {
+    pub fn as_raw<T: byteorder::ByteOrder>(&self) -> Vec<u8> {
+        use byteorder::WriteBytesExt;
+
+        let mut retval = Vec::new();
+        retval.write_u64::<T>(self.tag).expect("could not write");
+        retval.write_u64::<T>(self.val).expect("could not write");
+
+        retval
+    }
+}
+
+// the hash function used by .hash, from the System V ABI
+pub fn sysv_hash(name: &str) -> u32 {
+    let mut h: u32 = 0;
+    for c in strtab::encode(name) {
+        h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf0000000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+
+    h
+}
+
+// the hash function used by .gnu.hash (DJB hash)
+pub fn gnu_hash(name: &str) -> u32 {
+    let mut h: u32 = 5381;
+    for c in strtab::encode(name) {
+        h = h.wrapping_mul(33).wrapping_add(c as u32);
+    }
+
+    h
+}
+
+// number of buckets to use in .gnu.hash for `count` hashed symbols
+pub fn gnu_hash_buckets(count: usize) -> usize {
+    std::cmp::max(1, count / 4)
+}
+
+impl Section64 {
+    pub fn from_dynsym<T: byteorder::ByteOrder>(
+        dynsyms: &[Symbol64],
+        dynstr: &HashMap<String, usize>,
+        dynstr_idx: usize,
+    ) -> Self {
+        use std::io::Write;
+        use SectionType64::*;
+
+        let mut data = Vec::new();
+        for symbol in dynsyms {
+            data.write_all(&symbol.as_raw::<T>(ELFCLASS64, dynstr)[..])
+                .expect("could not write");
+        }
+
+        Section64 {
+            name: ".dynsym".to_string(),
+            r#type: Dynsym,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynstr_idx as u32,
+            // only the null symbol is local
+            info: 1,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+
+    pub fn from_hash<T: byteorder::ByteOrder>(dynsyms: &[Symbol64], dynsym_idx: usize) -> Self {
+        use byteorder::WriteBytesExt;
+        use SectionType64::*;
+
+        let nbucket = std::cmp::max(1, dynsyms.len() / 2);
+        let mut buckets = vec![0_u32; nbucket];
+        let mut chains = vec![0_u32; dynsyms.len()];
+        for (i, symbol) in dynsyms.iter().enumerate().skip(1) {
+            let bucket = sysv_hash(&symbol.name) as usize % nbucket;
+            chains[i] = buckets[bucket];
+            buckets[bucket] = i as u32;
+        }
+
+        let mut data = Vec::new();
+        data.write_u32::<T>(nbucket as u32)
+            .expect("could not write");
+        data.write_u32::<T>(dynsyms.len() as u32)
+            .expect("could not write");
+        for x in buckets.iter().chain(chains.iter()) {
+            data.write_u32::<T>(*x).expect("could not write");
+        }
+
+        Section64 {
+            name: ".hash".to_string(),
+            r#type: Hash,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynsym_idx as u32,
+            info: 0,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+
+    // the hashed symbols of `dynsyms` (those from `symoffset` on) must already
+    // be sorted by bucket
+    pub fn from_gnu_hash<T: byteorder::ByteOrder>(
+        dynsyms: &[Symbol64],
+        symoffset: usize,
+        dynsym_idx: usize,
+    ) -> Self {
+        use byteorder::WriteBytesExt;
+        use SectionType64::*;
+
+        const BLOOM_SHIFT: u32 = 26;
+
+        let hashed = &dynsyms[symoffset..];
+        let hashes: Vec<u32> = hashed.iter().map(|s| gnu_hash(&s.name)).collect();
+        let nbuckets = gnu_hash_buckets(hashed.len());
+        let bloom_size = std::cmp::max(1, hashed.len() / 8).next_power_of_two();
+
+        let mut bloom = vec![0_u64; bloom_size];
+        for h in hashes.iter() {
+            let word = (*h as usize / 64) % bloom_size;
+            bloom[word] |= 1 << (h % 64);
+            bloom[word] |= 1 << ((h >> BLOOM_SHIFT) % 64);
+        }
+
+        let mut buckets = vec![0_u32; nbuckets];
+        let mut chains = vec![0_u32; hashed.len()];
+        for (i, h) in hashes.iter().enumerate() {
+            let bucket = *h as usize % nbuckets;
+            if buckets[bucket] == 0 {
+                buckets[bucket] = (symoffset + i) as u32;
+            }
+
+            // the low bit marks the last symbol in a bucket's chain
+            let last = i + 1 == hashes.len() || hashes[i + 1] as usize % nbuckets != bucket;
+            chains[i] = (h & !1) | last as u32;
+        }
+
+        let mut data = Vec::new();
+        data.write_u32::<T>(nbuckets as u32)
+            .expect("could not write");
+        data.write_u32::<T>(symoffset as u32)
+            .expect("could not write");
+        data.write_u32::<T>(bloom_size as u32)
+            .expect("could not write");
+        data.write_u32::<T>(BLOOM_SHIFT).expect("could not write");
+        for word in bloom {
+            data.write_u64::<T>(word).expect("could not write");
+        }
+        for x in buckets.iter().chain(chains.iter()) {
+            data.write_u32::<T>(*x).expect("could not write");
+        }
+
+        Section64 {
+            name: ".gnu.hash".to_string(),
+            r#type: GnuHash,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynsym_idx as u32,
+            info: 0,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+
+    pub fn from_dynamic<T: byteorder::ByteOrder>(entries: &[Dynamic64], dynstr_idx: usize) -> Self {
+        use std::io::Write;
+        use SectionType64::*;
+
+        let mut data = Vec::new();
+        for entry in entries {
+            data.write_all(&entry.as_raw::<T>()[..])
+                .expect("could not write");
+        }
+
+        Section64 {
+            name: ".dynamic".to_string(),
+            r#type: Dynamic,
+            flags: SHF_ALLOC | SHF_WRITE,
+            addr: 0,
+            link: dynstr_idx as u32,
+            info: 0,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+}
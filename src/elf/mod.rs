@@ -1,28 +1,61 @@
+pub mod dynamic;
 mod parse;
 pub mod relocation;
 pub mod section;
+pub mod segment;
+pub mod shared_object;
+pub(crate) mod strtab;
 pub mod symbol;
+pub mod version;
 mod write;
 
-use parse::{ElfFile64HeaderRaw, ElfFile64Raw, ElfFile64RawParseError};
+use std::io;
+
+use parse::{ElfFile64Raw, ElfFile64RawParseError};
 use relocation::get_relocations;
 use section::{get_sections, organize_sections, Section64};
+use segment::Segment64;
 use symbol::{get_symbols, Symbol64};
 
+pub use parse::ElfFile64HeaderRaw;
+pub(crate) use write::build_string_table;
+
 pub const ELF_MAGIC: &[u8] = b"\x7FELF";
+pub const EHSIZE_32: usize = 52;
 pub const EHSIZE_64: usize = 64;
+pub const SHENTSIZE_32: usize = 40;
+pub const SHENTSIZE_64: usize = 64;
+
+// values of `e_ident[EI_CLASS]`
+pub const ELFCLASS32: u8 = 1;
+pub const ELFCLASS64: u8 = 2;
 
+// values of `e_ident[EI_OSABI]`, objects for System V work with any OS
+pub const ELFOSABI_NONE: u8 = 0;
+
+// values of `e_type`
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+// despite the name, this holds ELF32 files too (every field is wide enough),
+// `header.identifier.class` says which layout it is read from and written as
 #[derive(Debug)]
 pub struct ElfFile64 {
+    // the file it was read from, for error messages
+    pub name: String,
     pub header: ElfFile64HeaderRaw,
     pub unorganized_sections: Vec<Section64>,
     pub symbols: Vec<Symbol64>,
+    pub segments: Vec<Segment64>,
 }
 
 #[derive(Debug)]
 pub enum ElfFileError {
     ParseError,
     InvalidFileError,
+    // the file goes over one of the `ParseLimits`
+    LimitError,
 }
 
 impl From<ElfFile64RawParseError> for ElfFileError {
@@ -31,28 +64,188 @@ impl From<ElfFile64RawParseError> for ElfFileError {
     }
 }
 
+// how much one input file is allowed to cost, so that a malformed or hostile
+// file is turned away instead of making the linker allocate without bound,
+// and how big an output file can get
+#[derive(Clone, Copy, Debug)]
+pub struct ParseLimits {
+    pub max_sections: usize,
+    // bytes held for the file once it's read: the file itself, a copy of
+    // every section's contents (including the zeroes of .bss) and the names
+    // of its sections and symbols
+    pub max_size: u64,
+    // the largest `sh_addralign` a section can have, each one can need that
+    // much padding before it wherever it's put
+    pub max_align: u64,
+    // bytes written for an output file, padding included
+    pub max_output_size: u64,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_sections: 1 << 20,
+            max_size: 4 << 30,
+            max_align: 1 << 20,
+            max_output_size: 4 << 30,
+        }
+    }
+}
+
+// what's left of `ParseLimits::max_size` while a file is being read
+pub(crate) struct SizeBudget(u64);
+
+impl SizeBudget {
+    pub(crate) fn take(&mut self, size: usize) -> Result<(), ElfFileError> {
+        self.0 = self
+            .0
+            .checked_sub(size as u64)
+            .ok_or(ElfFileError::LimitError)?;
+
+        Ok(())
+    }
+}
+
 impl ElfFile64 {
     pub fn parse(input: &[u8]) -> Result<ElfFile64, ElfFileError> {
-        Ok(ElfFile64Raw::parse(input)?.into())
+        ElfFile64::parse_with_limits(input, &ParseLimits::default())
     }
-}
 
-impl From<ElfFile64Raw> for ElfFile64 {
-    fn from(raw: ElfFile64Raw) -> Self {
-        let sections = get_sections(&raw);
+    // nothing in `input` is trusted: anything malformed is an error, never a
+    // panic, and nothing is allocated past `limits`
+    pub fn parse_with_limits(
+        input: &[u8],
+        limits: &ParseLimits,
+    ) -> Result<ElfFile64, ElfFileError> {
+        let raw = ElfFile64Raw::parse(input, limits)?;
+        let mut budget = SizeBudget(limits.max_size - input.len() as u64);
 
-        let (mut unorganized_sections, symtab, relas, index_map) = organize_sections(sections);
+        let sections = get_sections(&raw, &mut budget)?;
+        let (mut unorganized_sections, symtab, relas, index_map) = organize_sections(sections)?;
 
-        let symbols = get_symbols(&raw, &symtab, &index_map);
+        let symbols = get_symbols(&raw, &symtab, &index_map, &mut budget)?;
         for rela in relas {
             let referenced_section = &mut unorganized_sections[rela.info as usize];
-            referenced_section.relocations = Some(get_relocations(&raw, &rela));
+            let relocations = get_relocations(&raw, &rela)?;
+            // every relocation has to be for a symbol and a place that exist
+            let valid = relocations.iter().all(|r| {
+                r.get_sym() < symbols.len() && r.offset < referenced_section.data.len() as u64
+            });
+            if !valid {
+                return Err(ElfFileError::InvalidFileError);
+            }
+            referenced_section.relocations = Some(relocations);
         }
 
-        ElfFile64 {
+        Ok(ElfFile64 {
+            name: String::new(),
             header: raw.header,
             unorganized_sections,
             symbols,
+            segments: Vec::new(),
+        })
+    }
+
+    pub fn class(&self) -> u8 {
+        self.header.identifier.class
+    }
+}
+
+// a name from outside any ELF file (given on the command line or in a version
+// script), kept the way names read from one are so that it's written out as
+// the same bytes
+pub fn name_from_bytes(bytes: &[u8]) -> String {
+    strtab::decode(bytes)
+}
+
+pub fn ehsize(class: u8) -> usize {
+    if class == ELFCLASS32 {
+        EHSIZE_32
+    } else {
+        EHSIZE_64
+    }
+}
+
+pub fn shentsize(class: u8) -> usize {
+    if class == ELFCLASS32 {
+        SHENTSIZE_32
+    } else {
+        SHENTSIZE_64
+    }
+}
+
+// addresses, offsets and sizes (`Elf32_Addr` vs `Elf64_Addr` etc.) are the
+// fields that change size with the class
+pub(crate) fn write_word<T: byteorder::ByteOrder, W: io::Write>(
+    output: &mut W,
+    class: u8,
+    value: u64,
+) -> io::Result<()> {
+    use byteorder::WriteBytesExt;
+
+    if class == ELFCLASS32 {
+        output.write_u32::<T>(value as u32)
+    } else {
+        output.write_u64::<T>(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    const MAIN_O: &[u8] = include_bytes!("../../test-files/01-combine-objects/main.o");
+
+    // `file` with section `index`'s `sh_addralign` changed
+    fn with_align(file: &[u8], index: usize, align: u64) -> Vec<u8> {
+        let mut file = file.to_vec();
+        let shoff = LittleEndian::read_u64(&file[0x28..]) as usize;
+        let at = shoff + index * SHENTSIZE_64 + 48;
+        LittleEndian::write_u64(&mut file[at..], align);
+
+        file
+    }
+
+    #[test]
+    fn alignments_are_checked() {
+        // a fuzzer found this one, it used to be written out as 2.9GB
+        let found = include_bytes!("../../test-files/fuzz/odd-alignment.o");
+        assert!(matches!(
+            ElfFile64::parse(found),
+            Err(ElfFileError::InvalidFileError)
+        ));
+        assert!(matches!(
+            ElfFile64::parse(&with_align(MAIN_O, 2, 0x36000000000008)),
+            Err(ElfFileError::InvalidFileError)
+        ));
+        assert!(matches!(
+            ElfFile64::parse(&with_align(MAIN_O, 2, 1 << 40)),
+            Err(ElfFileError::LimitError)
+        ));
+
+        for align in [0, 1, 0x1000].iter() {
+            let elf = ElfFile64::parse(&with_align(MAIN_O, 2, *align)).unwrap();
+            let mut output = Vec::new();
+            ElfFile64::write_out(elf, &mut output).unwrap();
+            assert!(output.len() < 0x2000);
         }
     }
+
+    #[test]
+    fn output_size_is_checked() {
+        let limits = ParseLimits {
+            max_output_size: 0x10000,
+            ..ParseLimits::default()
+        };
+        let write = |align| {
+            let elf = ElfFile64::parse(&with_align(MAIN_O, 2, align)).unwrap();
+            let mut output = Vec::new();
+            ElfFile64::write_out_with_limits(elf, &mut output, &limits).map(|_| output)
+        };
+
+        assert!(write(0x1000).is_ok());
+        // padding .text to its alignment is what makes it too big
+        assert!(write(0x20000).is_err());
+    }
 }
@@ -1,12 +1,18 @@
 use nom::bytes::complete::{tag, take};
 use nom::combinator::all_consuming;
-use nom::multi::many1;
+use nom::error::ErrorKind;
+use nom::multi::many0;
 use nom::number::complete as num_parse;
 use nom::Finish;
 use nom::IResult;
 
 use super::relocation::RelocationA64;
-use super::ELF_MAGIC;
+use super::section::SHN_XINDEX;
+use super::strtab;
+use super::{ElfFileError, ParseLimits, ELFCLASS32, ELFCLASS64, ELF_MAGIC};
+
+// `e_phnum` when the real number of program headers is in section header 0
+const PN_XNUM: u16 = 0xffff;
 
 #[derive(Debug)]
 pub struct ElfFileIdentifier {
@@ -20,7 +26,7 @@ pub struct ElfFileIdentifier {
 impl ElfFileIdentifier {
     fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (input, _) = tag(ELF_MAGIC)(input)?;
-        let (input, class) = num_parse::u8(input)?;
+        let (input, class) = elf_class(input)?;
         let (input, endianness) = elf_endianness(input)?;
         let (input, version) = num_parse::u8(input)?;
         let (input, os_abi) = num_parse::u8(input)?;
@@ -40,16 +46,38 @@ impl ElfFileIdentifier {
     }
 }
 
+fn elf_class(input: &[u8]) -> IResult<&[u8], u8> {
+    match num_parse::u8(input)? {
+        (rest, class @ (ELFCLASS32 | ELFCLASS64)) => Ok((rest, class)),
+        _ => Err(invalid(input)),
+    }
+}
+
 fn elf_endianness(input: &[u8]) -> IResult<&[u8], nom::number::Endianness> {
-    let (input, b) = num_parse::u8(input)?;
+    match num_parse::u8(input)? {
+        (rest, 0x01) => Ok((rest, nom::number::Endianness::Little)),
+        (rest, 0x02) => Ok((rest, nom::number::Endianness::Big)),
+        _ => Err(invalid(input)),
+    }
+}
 
-    let endianness = match b {
-        0x01 => nom::number::Endianness::Little,
-        0x02 => nom::number::Endianness::Big,
-        _ => unreachable!(),
-    };
+fn invalid(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Error(nom::error::Error::new(input, ErrorKind::Verify))
+}
 
-    Ok((input, endianness))
+// parse an address, offset or size, which are 4 bytes in ELF32 and 8 in ELF64
+fn parse_word(
+    class: u8,
+    endianness: nom::number::Endianness,
+) -> impl Fn(&[u8]) -> IResult<&[u8], u64> {
+    move |input: &[u8]| {
+        if class == ELFCLASS32 {
+            let (input, word) = num_parse::u32(endianness)(input)?;
+            Ok((input, word as u64))
+        } else {
+            num_parse::u64(endianness)(input)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -76,14 +104,14 @@ impl ElfFile64HeaderRaw {
 
         let parse_u16 = |i| num_parse::u16(identifier.endianness)(i);
         let parse_u32 = |i| num_parse::u32(identifier.endianness)(i);
-        let parse_u64 = |i| num_parse::u64(identifier.endianness)(i);
+        let parse_word = parse_word(identifier.class, identifier.endianness);
 
         let (input, r#type) = parse_u16(input)?;
         let (input, machine) = parse_u16(input)?;
         let (input, version) = parse_u32(input)?;
-        let (input, entry) = parse_u64(input)?;
-        let (input, phoff) = parse_u64(input)?;
-        let (input, shoff) = parse_u64(input)?;
+        let (input, entry) = parse_word(input)?;
+        let (input, phoff) = parse_word(input)?;
+        let (input, shoff) = parse_word(input)?;
         let (input, flags) = parse_u32(input)?;
         let (input, ehsize) = parse_u16(input)?;
         let (input, phentsize) = parse_u16(input)?;
@@ -129,20 +157,24 @@ pub struct ElfFile64SectionHeaderRaw {
 }
 
 impl ElfFile64SectionHeaderRaw {
-    pub fn parse(input: &[u8], endianness: nom::number::Endianness) -> IResult<&[u8], Self> {
+    pub fn parse(
+        input: &[u8],
+        class: u8,
+        endianness: nom::number::Endianness,
+    ) -> IResult<&[u8], Self> {
         let parse_u32 = |i| num_parse::u32(endianness)(i);
-        let parse_u64 = |i| num_parse::u64(endianness)(i);
+        let parse_word = parse_word(class, endianness);
 
         let (input, name) = parse_u32(input)?;
         let (input, r#type) = parse_u32(input)?;
-        let (input, flags) = parse_u64(input)?;
-        let (input, addr) = parse_u64(input)?;
-        let (input, offset) = parse_u64(input)?;
-        let (input, size) = parse_u64(input)?;
+        let (input, flags) = parse_word(input)?;
+        let (input, addr) = parse_word(input)?;
+        let (input, offset) = parse_word(input)?;
+        let (input, size) = parse_word(input)?;
         let (input, link) = parse_u32(input)?;
         let (input, info) = parse_u32(input)?;
-        let (input, addralign) = parse_u64(input)?;
-        let (input, entsize) = parse_u64(input)?;
+        let (input, addralign) = parse_word(input)?;
+        let (input, entsize) = parse_word(input)?;
 
         Ok((
             input,
@@ -162,10 +194,69 @@ impl ElfFile64SectionHeaderRaw {
     }
 }
 
+#[derive(Debug)]
+pub struct ElfFile64ProgramHeaderRaw {
+    pub r#type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ElfFile64ProgramHeaderRaw {
+    // Elf32_Phdr has the flags after the sizes, Elf64_Phdr right after the
+    // type
+    pub fn parse(
+        input: &[u8],
+        class: u8,
+        endianness: nom::number::Endianness,
+    ) -> IResult<&[u8], Self> {
+        let parse_u32 = |i| num_parse::u32(endianness)(i);
+        let parse_word = parse_word(class, endianness);
+
+        let (input, r#type) = parse_u32(input)?;
+        let (input, flags) = if class == ELFCLASS32 {
+            (input, 0)
+        } else {
+            parse_u32(input)?
+        };
+        let (input, offset) = parse_word(input)?;
+        let (input, vaddr) = parse_word(input)?;
+        let (input, paddr) = parse_word(input)?;
+        let (input, filesz) = parse_word(input)?;
+        let (input, memsz) = parse_word(input)?;
+        let (input, flags) = if class == ELFCLASS32 {
+            parse_u32(input)?
+        } else {
+            (input, flags)
+        };
+        let (input, align) = parse_word(input)?;
+
+        Ok((
+            input,
+            ElfFile64ProgramHeaderRaw {
+                r#type,
+                flags,
+                offset,
+                vaddr,
+                paddr,
+                filesz,
+                memsz,
+                align,
+            },
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub struct ElfFile64Raw {
     pub header: ElfFile64HeaderRaw,
-    pub section_data: Vec<u8>,
+    // the whole file, everything in it is found by its offset
+    pub data: Vec<u8>,
+    pub program_headers: Vec<ElfFile64ProgramHeaderRaw>,
     pub section_headers: Vec<ElfFile64SectionHeaderRaw>,
 }
 
@@ -178,28 +269,136 @@ impl From<nom::error::Error<&[u8]>> for ElfFile64RawParseError {
     }
 }
 
+impl From<nom::error::Error<&[u8]>> for ElfFileError {
+    fn from(_: nom::error::Error<&[u8]>) -> Self {
+        ElfFileError::ParseError
+    }
+}
+
 impl ElfFile64Raw {
-    pub fn parse(input: &[u8]) -> Result<Self, ElfFile64RawParseError> {
-        let (_, elf_file) = Finish::finish(all_consuming(ElfFile64Raw::parse_nom)(input))?;
+    // the header says where the program and section header tables are, and
+    // the section headers say where each section is. Nothing else about the
+    // layout is assumed: there can be gaps, trailing bytes, and the tables
+    // can be anywhere.
+    pub fn parse(input: &[u8], limits: &ParseLimits) -> Result<Self, ElfFileError> {
+        if input.len() as u64 > limits.max_size {
+            return Err(ElfFileError::LimitError);
+        }
+
+        let (_, header) = Finish::finish(ElfFile64HeaderRaw::parse(input))?;
+        let class = header.identifier.class;
+        let endianness = header.identifier.endianness;
+        let parse_section_header = |i| ElfFile64SectionHeaderRaw::parse(i, class, endianness);
+        let parse_program_header = |i| ElfFile64ProgramHeaderRaw::parse(i, class, endianness);
+
+        // files with too many sections or segments for the header's 16-bit
+        // fields keep the counts in section header 0
+        let first = if header.shoff == 0 {
+            None
+        } else {
+            let entry = input
+                .get(header.shoff as usize..)
+                .ok_or(ElfFile64RawParseError)?;
+            Some(Finish::finish(parse_section_header(entry))?.1)
+        };
+        let shnum = match (header.shnum, &first) {
+            (0, Some(first)) => first.size as usize,
+            (shnum, _) => shnum as usize,
+        };
+        let phnum = match (header.phnum, &first) {
+            (PN_XNUM, Some(first)) => first.info as usize,
+            (phnum, _) => phnum as usize,
+        };
+        if shnum > limits.max_sections {
+            return Err(ElfFileError::LimitError);
+        }
 
-        Ok(elf_file)
+        let section_headers = parse_table(
+            input,
+            header.shoff,
+            header.shentsize,
+            shnum,
+            parse_section_header,
+        )?;
+        let program_headers = parse_table(
+            input,
+            header.phoff,
+            header.phentsize,
+            phnum,
+            parse_program_header,
+        )?;
+
+        // alignments are powers of two, 0 means the same as 1
+        for section_header in section_headers.iter() {
+            let align = section_header.addralign;
+            if align != 0 && !align.is_power_of_two() {
+                return Err(ElfFileError::InvalidFileError);
+            }
+            if align > limits.max_align {
+                return Err(ElfFileError::LimitError);
+            }
+        }
+
+        Ok(ElfFile64Raw {
+            header,
+            data: input.to_vec(),
+            program_headers,
+            section_headers,
+        })
     }
 
-    fn parse_nom(input: &[u8]) -> IResult<&[u8], Self> {
-        let (input, header) = ElfFile64HeaderRaw::parse(input)?;
-        let (input, section_data) = take(header.shoff - header.ehsize as u64)(input)?;
-        let (input, section_headers) =
-            many1(|i| ElfFile64SectionHeaderRaw::parse(i, header.identifier.endianness))(input)?;
+    // `e_shstrndx`, or the real index from section header 0 if it doesn't
+    // fit
+    pub fn shstrndx(&self) -> usize {
+        match (self.header.shstrndx, self.section_headers.first()) {
+            (shstrndx, Some(first)) if shstrndx == SHN_XINDEX as u16 => first.link as usize,
+            (shstrndx, _) => shstrndx as usize,
+        }
+    }
 
-        Ok((
-            input,
-            ElfFile64Raw {
-                header,
-                section_data: section_data.to_vec(),
-                section_headers,
-            },
-        ))
+    // the string at `offset` in the string table section `strtab`, if both
+    // are really there
+    pub fn string_at(&self, strtab: usize, offset: usize) -> Option<String> {
+        let header = self.section_headers.get(strtab)?;
+
+        strtab::string_at(self.section_contents(header)?, offset)
+    }
+
+    // the bytes of the section described by `header`, if they are in the file
+    pub fn section_contents(&self, header: &ElfFile64SectionHeaderRaw) -> Option<&[u8]> {
+        let begin = header.offset as usize;
+        let end = begin.checked_add(header.size as usize)?;
+
+        self.data.get(begin..end)
+    }
+}
+
+// `count` entries of a table at `offset`, `entsize` bytes apart. Every entry
+// has to be in `input`, so a bogus count can't allocate more than the file
+// could hold.
+fn parse_table<'a, O>(
+    input: &'a [u8],
+    offset: u64,
+    entsize: u16,
+    count: usize,
+    parser: impl Fn(&'a [u8]) -> IResult<&'a [u8], O>,
+) -> Result<Vec<O>, ElfFile64RawParseError> {
+    let mut retval = Vec::new();
+    for i in 0..count as u64 {
+        let start = i
+            .checked_mul(entsize as u64)
+            .and_then(|x| x.checked_add(offset))
+            .ok_or(ElfFile64RawParseError)?;
+        let entry = input.get(start as usize..).ok_or(ElfFile64RawParseError)?;
+        let (rest, parsed) = Finish::finish(parser(entry))?;
+        // each entry has to fit in its `entsize` bytes
+        if entry.len() - rest.len() > entsize as usize {
+            return Err(ElfFile64RawParseError);
+        }
+        retval.push(parsed);
     }
+
+    Ok(retval)
 }
 
 #[derive(Debug)]
@@ -215,15 +414,19 @@ pub struct Symbol64Raw {
 impl Symbol64Raw {
     pub fn parse_many(
         input: &[u8],
+        class: u8,
         endianness: nom::number::Endianness,
     ) -> Result<Vec<Self>, ElfFile64RawParseError> {
-        let single_parser = Symbol64Raw::parse_one(endianness);
-        let (_, symbols) = Finish::finish(all_consuming(many1(single_parser))(input))?;
+        let single_parser = Symbol64Raw::parse_one(class, endianness);
+        let (_, symbols) = Finish::finish(all_consuming(many0(single_parser))(input))?;
 
         Ok(symbols)
     }
 
+    // Elf32_Sym puts the value and size before the info, other and shndx
+    // fields, Elf64_Sym puts them after
     pub fn parse_one(
+        class: u8,
         endianness: nom::number::Endianness,
     ) -> impl Fn(&[u8]) -> IResult<&[u8], Self> {
         move |input: &[u8]| {
@@ -232,11 +435,21 @@ impl Symbol64Raw {
             let parse_u64 = |i| num_parse::u64(endianness)(i);
 
             let (input, name) = parse_u32(input)?;
-            let (input, info) = num_parse::u8(input)?;
-            let (input, other) = num_parse::u8(input)?;
-            let (input, shndx) = parse_u16(input)?;
-            let (input, value) = parse_u64(input)?;
-            let (input, size) = parse_u64(input)?;
+            let (input, info, other, shndx, value, size) = if class == ELFCLASS32 {
+                let (input, value) = parse_u32(input)?;
+                let (input, size) = parse_u32(input)?;
+                let (input, info) = num_parse::u8(input)?;
+                let (input, other) = num_parse::u8(input)?;
+                let (input, shndx) = parse_u16(input)?;
+                (input, info, other, shndx, value as u64, size as u64)
+            } else {
+                let (input, info) = num_parse::u8(input)?;
+                let (input, other) = num_parse::u8(input)?;
+                let (input, shndx) = parse_u16(input)?;
+                let (input, value) = parse_u64(input)?;
+                let (input, size) = parse_u64(input)?;
+                (input, info, other, shndx, value, size)
+            };
 
             let symbol = Symbol64Raw {
                 name,
@@ -255,29 +468,47 @@ impl Symbol64Raw {
 impl RelocationA64 {
     pub fn parse_many(
         input: &[u8],
+        class: u8,
         endianness: nom::number::Endianness,
+        implicit_addend: bool,
     ) -> Result<Vec<Self>, ElfFile64RawParseError> {
-        let single_parser = RelocationA64::parse_one(endianness);
-        let (_, relas) = Finish::finish(all_consuming(many1(single_parser))(input))?;
+        let single_parser = RelocationA64::parse_one(class, endianness, implicit_addend);
+        let (_, relas) = Finish::finish(all_consuming(many0(single_parser))(input))?;
 
         Ok(relas)
     }
 
+    // SHT_REL entries are the same as SHT_RELA ones, without the addend. The
+    // ELF32 `r_info` is kept in the ELF64 encoding, so that symbol and type
+    // are found the same way for both classes
     pub fn parse_one(
+        class: u8,
         endianness: nom::number::Endianness,
+        implicit_addend: bool,
     ) -> impl Fn(&[u8]) -> IResult<&[u8], Self> {
         move |input: &[u8]| {
-            let parse_u64 = |i| num_parse::u64(endianness)(i);
-
-            let (input, offset) = parse_u64(input)?;
-            let (input, info) = parse_u64(input)?;
-            let (input, addend) = parse_u64(input)?;
+            let parse_word = parse_word(class, endianness);
+
+            let (input, offset) = parse_word(input)?;
+            let (input, info) = parse_word(input)?;
+            let (input, addend) = if implicit_addend {
+                (input, 0)
+            } else {
+                parse_word(input)?
+            };
+            let (info, addend) = if class == ELFCLASS32 {
+                ((info >> 8) << 32 | info & 0xff, addend as u32 as i32 as u64)
+            } else {
+                (info, addend)
+            };
 
             let rela = RelocationA64 {
                 offset,
                 info,
                 addend,
                 merged: false,
+                implicit_addend,
+                input: None,
             };
 
             Ok((input, rela))
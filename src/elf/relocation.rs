@@ -1,5 +1,8 @@
+use std::rc::Rc;
+
 use super::parse::ElfFile64Raw;
-use super::section::Section64;
+use super::section::{Section64, SectionType64};
+use super::{write_word, ElfFileError, ELFCLASS32};
 
 #[derive(Clone, Debug)]
 pub struct RelocationA64 {
@@ -7,16 +10,33 @@ pub struct RelocationA64 {
     pub info: u64,
     pub addend: u64,
     pub merged: bool, // XXX remove me
+    // read from a SHT_REL section, the addend is whatever is at `offset` in
+    // the section being relocated and `addend` is unused
+    pub implicit_addend: bool,
+    // the input it was read from, for error messages once inputs are merged
+    pub input: Option<Rc<str>>,
 }
 
 impl RelocationA64 {
-    pub fn as_raw<T: byteorder::ByteOrder>(&self) -> Vec<u8> {
-        use byteorder::WriteBytesExt;
+    pub fn as_raw<T: byteorder::ByteOrder>(&self, class: u8) -> Vec<u8> {
+        let mut retval = self.as_raw_rel::<T>(class);
+        write_word::<T, _>(&mut retval, class, self.addend).expect("could not write");
+
+        retval
+    }
+
+    // the SHT_REL form, without the addend
+    pub fn as_raw_rel<T: byteorder::ByteOrder>(&self, class: u8) -> Vec<u8> {
+        // ELF32 packs the symbol and type into one 32-bit word
+        let info = if class == ELFCLASS32 {
+            (self.get_sym() << 8 | self.get_type() & 0xff) as u64
+        } else {
+            self.info
+        };
 
         let mut retval = Vec::new();
-        retval.write_u64::<T>(self.offset).expect("could not write");
-        retval.write_u64::<T>(self.info).expect("could not write");
-        retval.write_u64::<T>(self.addend).expect("could not write");
+        write_word::<T, _>(&mut retval, class, self.offset).expect("could not write");
+        write_word::<T, _>(&mut retval, class, info).expect("could not write");
 
         retval
     }
@@ -34,7 +54,34 @@ impl RelocationA64 {
     }
 }
 
-pub fn get_relocations(raw: &ElfFile64Raw, rela: &Section64) -> Vec<RelocationA64> {
-    RelocationA64::parse_many(&rela.data[..], raw.header.identifier.endianness)
-        .expect("could not get symbols")
+pub fn get_relocations(
+    raw: &ElfFile64Raw,
+    rela: &Section64,
+) -> Result<Vec<RelocationA64>, ElfFileError> {
+    let implicit_addend = rela.r#type == SectionType64::Rel;
+    let relas = RelocationA64::parse_many(
+        &rela.data[..],
+        raw.header.identifier.class,
+        raw.header.identifier.endianness,
+        implicit_addend,
+    )?;
+
+    Ok(relas)
+}
+
+// SHT_REL if every relocation has an implicit addend, SHT_RELA otherwise
+pub fn relocation_section_type(relas: &[RelocationA64]) -> SectionType64 {
+    if !relas.is_empty() && relas.iter().all(|r| r.implicit_addend) {
+        SectionType64::Rel
+    } else {
+        SectionType64::Rela
+    }
+}
+
+// the name of the section holding relocations for `section`, e.g. `.rela.text`
+pub fn relocation_section_name(section: &Section64) -> String {
+    match section.relocations.as_deref().map(relocation_section_type) {
+        Some(SectionType64::Rel) => format!(".rel{}", &section.name),
+        _ => format!(".rela{}", &section.name),
+    }
 }
@@ -1,8 +1,47 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
-use super::parse::ElfFile64Raw;
-use super::relocation::RelocationA64;
+use super::parse::{ElfFile64Raw, ElfFile64SectionHeaderRaw};
+use super::relocation::{relocation_section_type, RelocationA64};
+use super::strtab;
 use super::symbol::{sym_bind, Symbol64};
+use super::{ElfFileError, SizeBudget, ELFCLASS32};
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+pub const SHF_MERGE: u64 = 0x10;
+pub const SHF_STRINGS: u64 = 0x20;
+pub const SHF_TLS: u64 = 0x400;
+
+// section indexes from SHN_LORESERVE up are reserved. Symbols store indexes
+// in 16 bits, so in memory they are 32 bits wide and the reserved ones are
+// moved to the top of the range, leaving room for real indexes past 0xff00.
+pub const SHN_UNDEF: u32 = 0;
+pub const SHN_LORESERVE: u32 = 0xffff_ff00;
+pub const SHN_ABS: u32 = 0xffff_fff1;
+pub const SHN_COMMON: u32 = 0xffff_fff2;
+// the real index is somewhere else: in .symtab_shndx for symbols, section
+// header 0 for `e_shstrndx`
+pub const SHN_XINDEX: u32 = 0xffff_ffff;
+
+// the in-memory form of a 16-bit section index read from a file
+pub fn shndx_from_raw(raw: u16) -> u32 {
+    if raw >= SHN_LORESERVE as u16 {
+        SHN_LORESERVE | raw as u32
+    } else {
+        raw as u32
+    }
+}
+
+// the 16-bit form of a section index, SHN_XINDEX if it doesn't fit
+pub fn shndx_to_raw(shndx: u32) -> u16 {
+    if shndx >= SHN_LORESERVE || shndx < SHN_LORESERVE as u16 as u32 {
+        shndx as u16
+    } else {
+        SHN_XINDEX as u16
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SectionType64 {
@@ -11,9 +50,24 @@ pub enum SectionType64 {
     Symtab,
     Strtab,
     Rela,
+    Hash,
+    Dynamic,
+    Note,
     Nobits,
+    Rel,
+    Dynsym,
+    InitArray,
+    FiniArray,
+    PreinitArray,
+    SymtabShndx,
+    GnuHash,
+    GnuVerdef,
+    GnuVerneed,
+    GnuVersym,
     UnwindX64,
     Loos,
+    // anything else is carried through as it is
+    Other(u32),
 }
 
 impl From<u32> for SectionType64 {
@@ -26,10 +80,23 @@ impl From<u32> for SectionType64 {
             2 => Symtab,
             3 => Strtab,
             4 => Rela,
+            5 => Hash,
+            6 => Dynamic,
+            7 => Note,
             8 => Nobits,
+            9 => Rel,
+            11 => Dynsym,
+            14 => InitArray,
+            15 => FiniArray,
+            16 => PreinitArray,
+            18 => SymtabShndx,
+            1879048182 => GnuHash,
+            1879048189 => GnuVerdef,
+            1879048190 => GnuVerneed,
+            1879048191 => GnuVersym,
             1879048193 => UnwindX64,
             1879002115 => Loos,
-            _ => unimplemented!(),
+            x => Other(x),
         }
     }
 }
@@ -44,9 +111,23 @@ impl From<SectionType64> for u32 {
             Symtab => 2,
             Strtab => 3,
             Rela => 4,
+            Hash => 5,
+            Dynamic => 6,
+            Note => 7,
             Nobits => 8,
+            Rel => 9,
+            Dynsym => 11,
+            InitArray => 14,
+            FiniArray => 15,
+            PreinitArray => 16,
+            SymtabShndx => 18,
+            GnuHash => 1879048182,
+            GnuVerdef => 1879048189,
+            GnuVerneed => 1879048190,
+            GnuVersym => 1879048191,
             UnwindX64 => 1879048193,
             Loos => 1879002115,
+            Other(x) => x,
         }
     }
 }
@@ -60,48 +141,72 @@ pub struct Section64 {
     pub link: u32,
     pub info: u32,
     pub addralign: u64,
+    // the size of each entry, for sections that are tables or SHF_MERGE
+    pub entsize: u64,
     pub data: Vec<u8>,
     pub relocations: Option<Vec<RelocationA64>>,
 }
 
-pub fn get_sections(raw: &ElfFile64Raw) -> Vec<Section64> {
-    let get_name = |idx: usize| {
-        let section_name_string_table_header = &raw.section_headers[raw.header.shstrndx as usize];
-        let offset = (section_name_string_table_header.offset - raw.header.ehsize as u64) as usize;
-        let start_of_name = &raw.section_data[offset + idx] as *const u8 as *const i8;
-        let name = unsafe { std::ffi::CStr::from_ptr(start_of_name) };
-
-        name.to_str().expect("could not create a &str").to_string()
+pub(crate) fn get_sections(
+    raw: &ElfFile64Raw,
+    budget: &mut SizeBudget,
+) -> Result<Vec<Section64>, ElfFileError> {
+    let get_name = |idx: usize, budget: &mut SizeBudget| -> Result<String, ElfFileError> {
+        let name = raw
+            .string_at(raw.shstrndx(), idx)
+            .ok_or(ElfFileError::InvalidFileError)?;
+        budget.take(name.len())?;
+
+        Ok(name)
     };
 
-    let get_data = |offset: usize, size: usize| {
-        if offset == 0 && size == 0 {
-            return Vec::new();
+    let get_data = |r#type: SectionType64,
+                    header: &ElfFile64SectionHeaderRaw,
+                    budget: &mut SizeBudget|
+     -> Result<Vec<u8>, ElfFileError> {
+        // section header 0 can hold the section count in its size
+        if r#type == SectionType64::Null {
+            return Ok(Vec::new());
+        }
+
+        // NOBITS sections (.bss, .tbss) take up no space in the file
+        if r#type == SectionType64::Nobits {
+            let size = usize::try_from(header.size).map_err(|_| ElfFileError::LimitError)?;
+            budget.take(size)?;
+            return Ok(vec![0; size]);
         }
 
-        let begin = offset - raw.header.ehsize as usize;
-        let end = begin + size as usize;
-        let data = &raw.section_data[begin..end];
+        let contents = raw
+            .section_contents(header)
+            .ok_or(ElfFileError::InvalidFileError)?;
+        budget.take(contents.len())?;
 
-        data.to_owned()
+        Ok(contents.to_owned())
     };
 
     let mut sections = Vec::new();
     for section_header in raw.section_headers.iter() {
+        // merging the section into another can pad it by up to its alignment
+        let align =
+            usize::try_from(section_header.addralign).map_err(|_| ElfFileError::LimitError)?;
+        budget.take(align)?;
+
+        let r#type = section_header.r#type.into();
         sections.push(Section64 {
-            name: get_name(section_header.name as usize),
-            r#type: section_header.r#type.into(),
+            name: get_name(section_header.name as usize, budget)?,
+            r#type,
             flags: section_header.flags,
             addr: section_header.addr,
             link: section_header.link,
             info: section_header.info,
             addralign: section_header.addralign,
-            data: get_data(section_header.offset as usize, section_header.size as usize),
+            entsize: section_header.entsize,
+            data: get_data(r#type, section_header, budget)?,
             relocations: None,
         });
     }
 
-    sections
+    Ok(sections)
 }
 
 // filter out sections that have their data represented elsewhere in the in-memory
@@ -114,14 +219,18 @@ pub fn get_sections(raw: &ElfFile64Raw) -> Vec<Section64> {
 //   - symtab section
 //   - rela sections
 //   - map from old indexes to new for unorganized sections
+#[allow(clippy::type_complexity)]
 pub fn organize_sections(
     sections: Vec<Section64>,
-) -> (
-    Vec<Section64>,
-    Section64,
-    Vec<Section64>,
-    HashMap<usize, usize>,
-) {
+) -> Result<
+    (
+        Vec<Section64>,
+        Section64,
+        Vec<Section64>,
+        HashMap<usize, usize>,
+    ),
+    ElfFileError,
+> {
     use SectionType64::*;
 
     let mut unorganized_sections = Vec::new();
@@ -133,8 +242,9 @@ pub fn organize_sections(
     for (i, section) in sections.into_iter().enumerate() {
         match section.r#type {
             Symtab => symtab = Some(section),
-            Rela => relas.push(section),
-            Strtab => {}
+            Rela | Rel => relas.push(section),
+            // read along with .symtab
+            Strtab | SymtabShndx => {}
             _ => {
                 unorganized_sections.push(section);
                 index_map.insert(i, i - offset);
@@ -148,18 +258,19 @@ pub fn organize_sections(
     // re-write rela references
     for rela in relas.iter_mut() {
         let old_info = rela.info as usize;
+        // relocations for a symbol or string table aren't a thing
         let new_info = index_map
             .get(&old_info)
-            .expect("referenced a section that was not explicitly included");
+            .ok_or(ElfFileError::InvalidFileError)?;
         rela.info = *new_info as u32;
     }
 
-    (
+    Ok((
         unorganized_sections,
-        symtab.expect("no .symtab found"),
+        symtab.ok_or(ElfFileError::InvalidFileError)?,
         relas,
         index_map,
-    )
+    ))
 }
 
 impl Section64 {
@@ -168,7 +279,7 @@ impl Section64 {
         use std::io::Write;
         use SectionType64::*;
 
-        let mut all: Vec<(&String, &usize)> = strtab.into_iter().collect();
+        let mut all: Vec<(&String, &usize)> = strtab.iter().collect();
         all.sort_by(|(_, idx1), (_, idx2)| {
             if idx1 < idx2 {
                 Less
@@ -181,11 +292,11 @@ impl Section64 {
 
         let mut data = vec![0_u8];
         for (s, _) in all.iter() {
-            if &s[..] == "" {
+            if s.is_empty() {
                 continue;
             }
 
-            data.write_all(s.as_bytes()).expect("could not write");
+            data.write_all(&strtab::encode(s)).expect("could not write");
             data.write_all(&[0_u8]).expect("could not write");
         }
 
@@ -197,6 +308,7 @@ impl Section64 {
             link: 0,
             info: 0,
             addralign: 1,
+            entsize: 0,
             data,
             relocations: None,
         }
@@ -204,6 +316,7 @@ impl Section64 {
 
     pub fn from_symtab<T: byteorder::ByteOrder>(
         symtab: &Vec<Symbol64>,
+        class: u8,
         strtab: &HashMap<String, usize>,
         strtab_idx: usize,
     ) -> Self {
@@ -212,16 +325,16 @@ impl Section64 {
 
         let mut data = Vec::new();
         for symbol in symtab {
-            data.write_all(&symbol.as_raw::<T>(strtab)[..])
+            data.write_all(&symbol.as_raw::<T>(class, strtab)[..])
                 .expect("could not write");
         }
 
+        // index of the first nonlocal symbol, all symbols can be local after
+        // a final link
         let info = symtab
             .iter()
-            .enumerate()
-            .find(|(_, sym)| sym_bind(*sym) != 0)
-            .expect("no nonlocal symbols")
-            .0 as u32;
+            .position(|sym| sym_bind(sym) != 0)
+            .unwrap_or(symtab.len()) as u32;
 
         Section64 {
             name: ".symtab".to_string(),
@@ -230,14 +343,49 @@ impl Section64 {
             addr: 0,
             link: strtab_idx as u32,
             info,
-            addralign: 8,
+            addralign: word_align(class),
+            entsize: 0,
             data,
             relocations: None,
         }
     }
 
+    // the section indexes of the symbols whose index doesn't fit in .symtab,
+    // only needed if there are any
+    pub fn from_symtab_shndx<T: byteorder::ByteOrder>(
+        symtab: &[Symbol64],
+        symtab_idx: usize,
+    ) -> Option<Self> {
+        use byteorder::WriteBytesExt;
+
+        let extended = |symbol: &Symbol64| shndx_to_raw(symbol.shndx) == SHN_XINDEX as u16;
+        if !symtab.iter().any(extended) {
+            return None;
+        }
+
+        let mut data = Vec::new();
+        for symbol in symtab {
+            let shndx = if extended(symbol) { symbol.shndx } else { 0 };
+            data.write_u32::<T>(shndx).expect("could not write");
+        }
+
+        Some(Section64 {
+            name: ".symtab_shndx".to_string(),
+            r#type: SectionType64::SymtabShndx,
+            flags: 0,
+            addr: 0,
+            link: symtab_idx as u32,
+            info: 0,
+            addralign: 4,
+            entsize: 0,
+            data,
+            relocations: None,
+        })
+    }
+
     pub fn from_rela<T: byteorder::ByteOrder>(
         relas: &Vec<RelocationA64>,
+        class: u8,
         symtab_idx: usize,
         section_idx: usize,
         name: String,
@@ -245,22 +393,37 @@ impl Section64 {
         use std::io::Write;
         use SectionType64::*;
 
+        // all relocations are written in the same form
+        let r#type = relocation_section_type(relas);
         let mut data = Vec::new();
         for rela in relas {
-            data.write_all(&rela.as_raw::<T>()[..])
-                .expect("could not write");
+            let raw = match r#type {
+                Rel => rela.as_raw_rel::<T>(class),
+                _ => rela.as_raw::<T>(class),
+            };
+            data.write_all(&raw[..]).expect("could not write");
         }
 
         Section64 {
             name,
-            r#type: Rela,
+            r#type,
             flags: 0, // set I flag?
             addr: 0,
             link: symtab_idx as u32,
             info: section_idx as u32,
-            addralign: 8,
+            addralign: word_align(class),
+            entsize: 0,
             data,
             relocations: None,
         }
     }
 }
+
+// symbol and relocation tables are aligned to the size of their widest field
+pub(crate) fn word_align(class: u8) -> u64 {
+    if class == ELFCLASS32 {
+        4
+    } else {
+        8
+    }
+}
@@ -0,0 +1,91 @@
+use super::{write_word, ELFCLASS32};
+
+pub const PHENTSIZE_32: usize = 32;
+pub const PHENTSIZE_64: usize = 56;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentType64 {
+    Null,
+    Load,
+    Dynamic,
+    Interp,
+    Note,
+    Phdr,
+    Tls,
+    GnuEhFrame,
+    GnuStack,
+    GnuRelro,
+}
+
+impl From<SegmentType64> for u32 {
+    fn from(x: SegmentType64) -> Self {
+        use SegmentType64::*;
+
+        match x {
+            Null => 0,
+            Load => 1,
+            Dynamic => 2,
+            Interp => 3,
+            Note => 4,
+            Phdr => 6,
+            Tls => 7,
+            GnuEhFrame => 0x6474e550,
+            GnuStack => 0x6474e551,
+            GnuRelro => 0x6474e552,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Segment64 {
+    pub r#type: SegmentType64,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl Segment64 {
+    // Elf32_Phdr has the flags after the sizes, Elf64_Phdr right after the
+    // type
+    pub fn as_raw<T: byteorder::ByteOrder>(&self, class: u8) -> Vec<u8> {
+        use byteorder::WriteBytesExt;
+
+        let mut retval = Vec::new();
+        retval
+            .write_u32::<T>(self.r#type.into())
+            .expect("could not write");
+        if class != ELFCLASS32 {
+            retval.write_u32::<T>(self.flags).expect("could not write");
+        }
+        // paddr is always the same as vaddr
+        for word in [self.offset, self.vaddr, self.vaddr, self.filesz, self.memsz] {
+            write_word::<T, _>(&mut retval, class, word).expect("could not write");
+        }
+        if class == ELFCLASS32 {
+            retval.write_u32::<T>(self.flags).expect("could not write");
+        }
+        write_word::<T, _>(&mut retval, class, self.align).expect("could not write");
+
+        retval
+    }
+
+    // does this segment cover the virtual address `addr`?
+    pub fn contains(&self, addr: u64) -> bool {
+        self.vaddr <= addr && addr <= self.vaddr + self.memsz
+    }
+}
+
+pub fn phentsize(class: u8) -> usize {
+    if class == ELFCLASS32 {
+        PHENTSIZE_32
+    } else {
+        PHENTSIZE_64
+    }
+}
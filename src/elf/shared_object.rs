@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use nom::number::complete as num_parse;
+
+use super::dynamic::DT_SONAME;
+use super::parse::{ElfFile64HeaderRaw, ElfFile64Raw, Symbol64Raw};
+use super::section::SectionType64;
+use super::strtab::string_at;
+use super::version::{VERSYM_HIDDEN, VER_FLG_BASE, VER_NDX_GLOBAL};
+use super::{ElfFileError, ParseLimits, SizeBudget, ELFCLASS32, ET_DYN};
+
+// a symbol defined by a shared object we're linking against
+#[derive(Clone, Debug)]
+pub struct SharedSymbol64 {
+    pub name: String,
+    pub info: u8,
+    pub other: u8,
+    pub size: u64,
+    // shared by every symbol with the version, rather than copied for each
+    pub version: Option<Rc<str>>,
+    // references without an explicit version bind to the default version,
+    // `foo@@VERS` is a default version, `foo@VERS` is not
+    pub is_default: bool,
+}
+
+// the parts of a shared object that matter when linking against it: the name
+// it is loaded by, and the symbols it defines
+#[derive(Debug)]
+pub struct SharedObject64 {
+    pub header: ElfFile64HeaderRaw,
+    pub soname: String,
+    pub symbols: Vec<SharedSymbol64>,
+}
+
+impl SharedObject64 {
+    pub fn is_shared_object(input: &[u8]) -> bool {
+        match ElfFile64HeaderRaw::parse(input) {
+            Ok((_, header)) => header.r#type == ET_DYN,
+            Err(_) => false,
+        }
+    }
+
+    // `filename` is used as the soname if the object doesn't have DT_SONAME
+    pub fn parse(
+        input: &[u8],
+        filename: &str,
+        limits: &ParseLimits,
+    ) -> Result<SharedObject64, ElfFileError> {
+        use ElfFileError::*;
+
+        let raw = ElfFile64Raw::parse(input, limits)?;
+        let mut budget = SizeBudget(limits.max_size - input.len() as u64);
+        let class = raw.header.identifier.class;
+        let endianness = raw.header.identifier.endianness;
+        let find = |r#type: SectionType64| {
+            let r#type = u32::from(r#type);
+            raw.section_headers.iter().find(|h| h.r#type == r#type)
+        };
+        let contents = |header| raw.section_contents(header).ok_or(InvalidFileError);
+
+        let dynsym_header = find(SectionType64::Dynsym).ok_or(InvalidFileError)?;
+        let dynstr_header = raw
+            .section_headers
+            .get(dynsym_header.link as usize)
+            .ok_or(InvalidFileError)?;
+        let dynstr = contents(dynstr_header)?;
+
+        let versions = match find(SectionType64::GnuVerdef) {
+            Some(header) => parse_verdef(contents(header)?, dynstr, endianness, &mut budget)?,
+            None => HashMap::new(),
+        };
+        let versyms = match find(SectionType64::GnuVersym) {
+            Some(header) => parse_versym(contents(header)?, endianness)?,
+            None => Vec::new(),
+        };
+
+        let soname = match find(SectionType64::Dynamic) {
+            Some(header) => parse_soname(contents(header)?, dynstr, class, endianness)?,
+            None => None,
+        };
+        if let Some(soname) = &soname {
+            budget.take(soname.len())?;
+        }
+
+        let raw_symbols = Symbol64Raw::parse_many(contents(dynsym_header)?, class, endianness)?;
+        let mut symbols = Vec::new();
+        for (i, raw_symbol) in raw_symbols.iter().enumerate() {
+            // only defined, nonlocal symbols can satisfy a reference
+            if raw_symbol.shndx == 0 || raw_symbol.info >> 4 == 0 {
+                continue;
+            }
+
+            let versym = versyms.get(i).copied().unwrap_or(VER_NDX_GLOBAL);
+            let name = string_at(dynstr, raw_symbol.name as usize).ok_or(InvalidFileError)?;
+            budget.take(name.len())?;
+            symbols.push(SharedSymbol64 {
+                name,
+                info: raw_symbol.info,
+                other: raw_symbol.other,
+                size: raw_symbol.size,
+                version: versions.get(&(versym & !VERSYM_HIDDEN)).cloned(),
+                is_default: versym & VERSYM_HIDDEN == 0,
+            });
+        }
+
+        Ok(SharedObject64 {
+            header: raw.header,
+            soname: soname.unwrap_or_else(|| filename.to_string()),
+            symbols,
+        })
+    }
+
+    // find the definition a reference to `name` binds to, `version` is given
+    // for references like `foo@VERS`
+    pub fn lookup(&self, name: &str, version: Option<&str>) -> Option<&SharedSymbol64> {
+        self.symbols.iter().find(|s| {
+            s.name == name
+                && match version {
+                    Some(version) => s.version.as_deref() == Some(version),
+                    None => s.is_default,
+                }
+        })
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, endianness: nom::number::Endianness) -> Option<u16> {
+    let result: nom::IResult<&[u8], u16> = num_parse::u16(endianness)(data.get(offset..)?);
+    result.ok().map(|(_, x)| x)
+}
+
+fn read_u32(data: &[u8], offset: usize, endianness: nom::number::Endianness) -> Option<u32> {
+    let result: nom::IResult<&[u8], u32> = num_parse::u32(endianness)(data.get(offset..)?);
+    result.ok().map(|(_, x)| x)
+}
+
+fn read_u64(data: &[u8], offset: usize, endianness: nom::number::Endianness) -> Option<u64> {
+    let result: nom::IResult<&[u8], u64> = num_parse::u64(endianness)(data.get(offset..)?);
+    result.ok().map(|(_, x)| x)
+}
+
+fn parse_versym(
+    data: &[u8],
+    endianness: nom::number::Endianness,
+) -> Result<Vec<u16>, ElfFileError> {
+    (0..data.len() / 2)
+        .map(|i| read_u16(data, i * 2, endianness).ok_or(ElfFileError::InvalidFileError))
+        .collect()
+}
+
+// map from version index to version name, the base version (the file's own
+// name) is left out. Each name is charged to `budget`, even the ones that
+// turn out to be the same.
+fn parse_verdef(
+    data: &[u8],
+    dynstr: &[u8],
+    endianness: nom::number::Endianness,
+    budget: &mut SizeBudget,
+) -> Result<HashMap<u16, Rc<str>>, ElfFileError> {
+    let mut versions = HashMap::new();
+    let mut offset = 0;
+    loop {
+        let entry = || -> Option<(u16, u16, String, u32)> {
+            let flags = read_u16(data, offset + 2, endianness)?;
+            let index = read_u16(data, offset + 4, endianness)?;
+            let aux = read_u32(data, offset + 12, endianness)?;
+            let next = read_u32(data, offset + 16, endianness)?;
+            let name = read_u32(data, offset + aux as usize, endianness)?;
+
+            Some((flags, index, string_at(dynstr, name as usize)?, next))
+        };
+
+        let (flags, index, name, next) = entry().ok_or(ElfFileError::InvalidFileError)?;
+        budget.take(name.len())?;
+        if flags & VER_FLG_BASE == 0 {
+            versions.insert(index, Rc::from(name));
+        }
+
+        if next == 0 {
+            break;
+        }
+        offset += next as usize;
+    }
+
+    Ok(versions)
+}
+
+// .dynamic entries are a tag and a value, each a word of the file's class
+fn parse_soname(
+    data: &[u8],
+    dynstr: &[u8],
+    class: u8,
+    endianness: nom::number::Endianness,
+) -> Result<Option<String>, ElfFileError> {
+    let read_word = |offset| {
+        if class == ELFCLASS32 {
+            read_u32(data, offset, endianness).map(u64::from)
+        } else {
+            read_u64(data, offset, endianness)
+        }
+    };
+    let word = if class == ELFCLASS32 { 4 } else { 8 };
+
+    for i in 0..data.len() / (2 * word) {
+        let tag = read_word(i * 2 * word).ok_or(ElfFileError::InvalidFileError)?;
+        let val = read_word(i * 2 * word + word).ok_or(ElfFileError::InvalidFileError)?;
+        if tag == DT_SONAME {
+            return Ok(string_at(dynstr, val as usize));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hello and goodbye, both in a version with a 509-byte name
+    const LIBGREET: &[u8] = include_bytes!("../../test-files/03-versioned-library/libgreet.so");
+
+    #[test]
+    fn versions_are_shared_between_symbols() {
+        let so = SharedObject64::parse(LIBGREET, "libgreet.so", &ParseLimits::default()).unwrap();
+        assert_eq!(so.soname, "libgreet.so.1");
+
+        let hello = so.lookup("hello", None).unwrap().version.clone().unwrap();
+        let goodbye = so.lookup("goodbye", None).unwrap().version.clone().unwrap();
+        assert!(hello.starts_with("LIBGREET_XXX"));
+        assert!(Rc::ptr_eq(&hello, &goodbye));
+    }
+
+    #[test]
+    fn versions_are_charged_to_the_budget() {
+        let parse = |extra: u64| {
+            let limits = ParseLimits {
+                max_size: LIBGREET.len() as u64 + extra,
+                ..ParseLimits::default()
+            };
+            SharedObject64::parse(LIBGREET, "libgreet.so", &limits)
+        };
+
+        // the symbol names (one of them is the version's, 509 bytes) fit,
+        // but not the version definitions and soname on top of them
+        assert!(matches!(parse(800), Err(ElfFileError::LimitError)));
+        assert!(parse(1100).is_ok());
+    }
+}
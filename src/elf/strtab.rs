@@ -0,0 +1,123 @@
+// string tables hold NUL-terminated byte strings, which are almost always,
+// but not necessarily, UTF-8. Names are kept as `String`s with every byte
+// that isn't part of a UTF-8 character escaped to one of the last 256 code
+// points (which are private use characters), so that any name survives being
+// read and written out again. Characters that really are in that range are
+// escaped byte by byte too, to keep the mapping reversible. Names that
+// aren't read from a file (like `--soname`) are decoded from their bytes all
+// the same, otherwise one with those characters would be written out as if
+// they were escapes.
+const ESCAPE_BASE: u32 = 0x10ff00;
+
+// the string starting at `offset` in a string table, which has to end with
+// a NUL inside the table
+pub fn string_at(table: &[u8], offset: usize) -> Option<String> {
+    let bytes = table.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+
+    Some(decode(&bytes[..len]))
+}
+
+pub fn decode(mut bytes: &[u8]) -> String {
+    let mut retval = String::new();
+    while !bytes.is_empty() {
+        let (valid, rest) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                (std::str::from_utf8(valid).unwrap(), rest)
+            }
+        };
+
+        for c in valid.chars() {
+            if is_escape(c) {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    retval.push(escape(b));
+                }
+            } else {
+                retval.push(c);
+            }
+        }
+
+        // the first byte that isn't part of a character
+        match rest.split_first() {
+            Some((b, after)) => {
+                retval.push(escape(*b));
+                bytes = after;
+            }
+            None => break,
+        }
+    }
+
+    retval
+}
+
+// the bytes `name` was read from
+pub fn encode(name: &str) -> Vec<u8> {
+    let mut retval = Vec::with_capacity(name.len());
+    for c in name.chars() {
+        if is_escape(c) {
+            retval.push((c as u32 - ESCAPE_BASE) as u8);
+        } else {
+            let mut buf = [0; 4];
+            retval.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+
+    retval
+}
+
+fn escape(b: u8) -> char {
+    char::from_u32(ESCAPE_BASE + b as u32).unwrap()
+}
+
+fn is_escape(c: char) -> bool {
+    c as u32 >= ESCAPE_BASE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        let names: [&[u8]; 9] = [
+            b"",
+            b"main",
+            "h\u{e9}llo_\u{4e16}\u{754c}".as_bytes(),
+            b"\xff\xfe",
+            b"a\xc3",
+            b"\xc3a",
+            b"\xf4\x8f\xbf",
+            "\u{10ffff}".as_bytes(),
+            "x\u{10ff00}\u{10fffe}y".as_bytes(),
+        ];
+        for bytes in names.iter() {
+            assert_eq!(&encode(&decode(bytes))[..], *bytes);
+        }
+    }
+
+    #[test]
+    fn escapes_are_not_mistaken_for_characters() {
+        // 0xff alone is escaped to U+10FFFF, U+10FFFF itself is escaped byte
+        // by byte
+        let raw = decode(b"\xff");
+        let utf8 = decode("\u{10ffff}".as_bytes());
+        assert_eq!(raw, "\u{10ffff}");
+        assert_ne!(raw, utf8);
+        assert_eq!(utf8.chars().count(), 4);
+    }
+
+    #[test]
+    fn strings_end_at_nul() {
+        let table = b"\0foo\0b\xffr\0baz";
+        assert_eq!(string_at(table, 0).as_deref(), Some(""));
+        assert_eq!(string_at(table, 1).as_deref(), Some("foo"));
+        assert_eq!(string_at(table, 2).as_deref(), Some("oo"));
+        assert_eq!(encode(&string_at(table, 5).unwrap()), b"b\xffr");
+        // not terminated, or past the end
+        assert_eq!(string_at(table, 9), None);
+        assert_eq!(string_at(table, 100), None);
+    }
+}
@@ -1,20 +1,93 @@
 use std::collections::HashMap;
 
 use super::parse::{ElfFile64Raw, Symbol64Raw};
-use super::section::Section64;
+use super::section::{
+    shndx_from_raw, shndx_to_raw, Section64, SectionType64, SHN_LORESERVE, SHN_UNDEF, SHN_XINDEX,
+};
+use super::{ElfFileError, SizeBudget, ELFCLASS32};
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_WEAK: u8 = 2;
+
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_TLS: u8 = 6;
+
+// symbol visibility, stored in the low two bits of `Symbol64::other`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolVisibility {
+    Default,
+    Internal,
+    Hidden,
+    Protected,
+}
+
+impl From<u8> for SymbolVisibility {
+    fn from(x: u8) -> Self {
+        use SymbolVisibility::*;
+
+        match x & 0x3 {
+            0 => Default,
+            1 => Internal,
+            2 => Hidden,
+            3 => Protected,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<SymbolVisibility> for u8 {
+    fn from(x: SymbolVisibility) -> Self {
+        use SymbolVisibility::*;
+
+        match x {
+            Default => 0,
+            Internal => 1,
+            Hidden => 2,
+            Protected => 3,
+        }
+    }
+}
+
+impl SymbolVisibility {
+    // internal is the most restrictive, then hidden, then protected, then
+    // default
+    fn restrictiveness(self) -> u8 {
+        use SymbolVisibility::*;
+
+        match self {
+            Default => 0,
+            Protected => 1,
+            Hidden => 2,
+            Internal => 3,
+        }
+    }
+
+    pub fn most_restrictive(self, other: SymbolVisibility) -> SymbolVisibility {
+        if other.restrictiveness() > self.restrictiveness() {
+            other
+        } else {
+            self
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Symbol64 {
     pub name: String,
     pub info: u8,
     pub other: u8,
-    pub shndx: u16,
+    pub shndx: u32,
     pub value: u64,
     pub size: u64,
 }
 
 impl Symbol64 {
-    pub fn as_raw<T: byteorder::ByteOrder>(&self, strtab: &HashMap<String, usize>) -> Vec<u8> {
+    pub fn as_raw<T: byteorder::ByteOrder>(
+        &self,
+        class: u8,
+        strtab: &HashMap<String, usize>,
+    ) -> Vec<u8> {
         use byteorder::WriteBytesExt;
 
         let mut retval = Vec::new();
@@ -22,64 +95,125 @@ impl Symbol64 {
         let idx = strtab.get(&self.name).expect("could not get symbol name");
 
         retval.write_u32::<T>(*idx as u32).expect("could not write");
+        if class == ELFCLASS32 {
+            retval
+                .write_u32::<T>(self.value as u32)
+                .expect("could not write");
+            retval
+                .write_u32::<T>(self.size as u32)
+                .expect("could not write");
+        }
         retval.write_u8(self.info).expect("could not write");
         retval.write_u8(self.other).expect("could not write");
-        retval.write_u16::<T>(self.shndx).expect("could not write");
-        retval.write_u64::<T>(self.value).expect("could not write");
-        retval.write_u64::<T>(self.size).expect("could not write");
+        retval
+            .write_u16::<T>(shndx_to_raw(self.shndx))
+            .expect("could not write");
+        if class != ELFCLASS32 {
+            retval.write_u64::<T>(self.value).expect("could not write");
+            retval.write_u64::<T>(self.size).expect("could not write");
+        }
 
         retval
     }
+
+    pub fn visibility(&self) -> SymbolVisibility {
+        self.other.into()
+    }
+
+    pub fn set_visibility(&mut self, visibility: SymbolVisibility) {
+        self.other = (self.other & !0x3) | u8::from(visibility);
+    }
+
+    // can this symbol be seen from outside the module it ends up in?
+    pub fn is_visible_outside(&self) -> bool {
+        use SymbolVisibility::*;
+
+        sym_bind(self) != STB_LOCAL && matches!(self.visibility(), Default | Protected)
+    }
+
+    pub fn make_local(&mut self) {
+        self.info = (STB_LOCAL << 4) | sym_type(self);
+    }
 }
 
-pub fn get_symbols(
+pub(crate) fn get_symbols(
     raw: &ElfFile64Raw,
     symtab_section: &Section64,
     index_map: &HashMap<usize, usize>,
-) -> Vec<Symbol64> {
-    let raw_symbols =
-        Symbol64Raw::parse_many(&symtab_section.data[..], raw.header.identifier.endianness)
-            .expect("could not get symbols");
-
-    // XXX duplicated from super::section
-    // move into method on ElfFile64Raw
-    let get_name = |idx: usize| {
-        let symbol_name_string_table_header = &raw.section_headers[symtab_section.link as usize];
-        let offset = (symbol_name_string_table_header.offset - raw.header.ehsize as u64) as usize;
-        let start_of_name = &raw.section_data[offset + idx] as *const u8 as *const i8;
-        let name = unsafe { std::ffi::CStr::from_ptr(start_of_name) };
-
-        name.to_str().expect("could not create a &str").to_string()
+    budget: &mut SizeBudget,
+) -> Result<Vec<Symbol64>, ElfFileError> {
+    use ElfFileError::*;
+
+    let raw_symbols = Symbol64Raw::parse_many(
+        &symtab_section.data[..],
+        raw.header.identifier.class,
+        raw.header.identifier.endianness,
+    )?;
+
+    let mut get_name = |idx: usize| -> Result<String, ElfFileError> {
+        let name = raw
+            .string_at(symtab_section.link as usize, idx)
+            .ok_or(InvalidFileError)?;
+        budget.take(name.len())?;
+
+        Ok(name)
+    };
+
+    // section indexes that don't fit in a symbol are in .symtab_shndx, one
+    // word per symbol
+    let endianness = raw.header.identifier.endianness;
+    let xindex = match raw
+        .section_headers
+        .iter()
+        .find(|h| h.r#type == u32::from(SectionType64::SymtabShndx))
+    {
+        Some(h) => Some(raw.section_contents(h).ok_or(InvalidFileError)?),
+        None => None,
+    };
+    let get_xindex = |i: usize| {
+        let entry = xindex?.get(i.checked_mul(4)?..)?;
+        let result: nom::IResult<&[u8], u32> = nom::number::complete::u32(endianness)(entry);
+        result.ok().map(|(_, shndx)| shndx)
     };
 
     let mut symbols = Vec::new();
-    for raw_symbol in raw_symbols.iter() {
+    for (i, raw_symbol) in raw_symbols.iter().enumerate() {
+        let shndx = match shndx_from_raw(raw_symbol.shndx) {
+            SHN_XINDEX => get_xindex(i).ok_or(InvalidFileError)?,
+            shndx => shndx,
+        };
         symbols.push(Symbol64 {
-            name: get_name(raw_symbol.name as usize),
+            name: get_name(raw_symbol.name as usize)?,
             info: raw_symbol.info,
             other: raw_symbol.other,
-            shndx: get_new_shndx(raw_symbol.shndx, index_map),
+            shndx: get_new_shndx(shndx, index_map)?,
             value: raw_symbol.value,
             size: raw_symbol.size,
         });
     }
-    symbols
+
+    Ok(symbols)
 }
 
-fn get_new_shndx(old: u16, index_map: &HashMap<usize, usize>) -> u16 {
-    if old == 0xfff1 {
-        // SHN_ABS - not affected by relocation
-        old
+// symbols can't be in a section that was filtered out, like a symbol table
+fn get_new_shndx(old: u32, index_map: &HashMap<usize, usize>) -> Result<u32, ElfFileError> {
+    if old == SHN_UNDEF || old >= SHN_LORESERVE {
+        // SHN_ABS etc. - not affected by relocation
+        Ok(old)
     } else {
         let old_shndx = old as usize;
         let new_shndx = index_map
             .get(&old_shndx)
-            .expect("tried to reference a filtered out section");
+            .ok_or(ElfFileError::InvalidFileError)?;
 
-        *new_shndx as u16
+        Ok(*new_shndx as u32)
     }
 }
 
 pub fn sym_bind(sym: &Symbol64) -> u8 {
     (sym.info) >> 4
 }
+
+pub fn sym_type(sym: &Symbol64) -> u8 {
+    sym.info & 0xf
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use super::dynamic::sysv_hash;
+use super::section::{Section64, SectionType64, SHF_ALLOC};
+
+pub const VER_NDX_LOCAL: u16 = 0;
+pub const VER_NDX_GLOBAL: u16 = 1;
+pub const VER_FLG_BASE: u16 = 0x1;
+// set in a .gnu.version entry for `foo@VERS` (as opposed to `foo@@VERS`)
+pub const VERSYM_HIDDEN: u16 = 0x8000;
+
+// an entry of .gnu.version_d, a version this output defines
+#[derive(Clone, Debug)]
+pub struct VersionDefinition {
+    pub name: String,
+    pub index: u16,
+    pub flags: u16,
+    pub parents: Vec<String>,
+}
+
+// an entry of .gnu.version_r, the versions needed from one shared object
+#[derive(Clone, Debug)]
+pub struct VersionRequirement {
+    pub file: String,
+    // version names and the index each was given in .gnu.version
+    pub versions: Vec<(String, u16)>,
+}
+
+// split a symbol name like `foo@@VERS_1.0` into its name, its version and
+// whether that version is the default one
+pub fn split_versioned_name(name: &str) -> (&str, Option<&str>, bool) {
+    match name.find('@') {
+        Some(at) => {
+            let version = &name[at + 1..];
+            match version.strip_prefix('@') {
+                Some(version) => (&name[..at], Some(version), true),
+                None => (&name[..at], Some(version), false),
+            }
+        }
+        None => (name, None, true),
+    }
+}
+
+impl Section64 {
+    pub fn from_versym<T: byteorder::ByteOrder>(versyms: &[u16], dynsym_idx: usize) -> Self {
+        use byteorder::WriteBytesExt;
+        use SectionType64::*;
+
+        let mut data = Vec::new();
+        for versym in versyms {
+            data.write_u16::<T>(*versym).expect("could not write");
+        }
+
+        Section64 {
+            name: ".gnu.version".to_string(),
+            r#type: GnuVersym,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynsym_idx as u32,
+            info: 0,
+            addralign: 2,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+
+    pub fn from_verdef<T: byteorder::ByteOrder>(
+        definitions: &[VersionDefinition],
+        dynstr: &HashMap<String, usize>,
+        dynstr_idx: usize,
+    ) -> Self {
+        use byteorder::WriteBytesExt;
+        use SectionType64::*;
+
+        // Elf64_Verdef is 20 bytes, each Elf64_Verdaux 8
+        let mut data = Vec::new();
+        for (i, definition) in definitions.iter().enumerate() {
+            let names: Vec<&String> = std::iter::once(&definition.name)
+                .chain(definition.parents.iter())
+                .collect();
+            let next = if i + 1 == definitions.len() {
+                0
+            } else {
+                20 + 8 * names.len() as u32
+            };
+
+            data.write_u16::<T>(1).expect("could not write");
+            data.write_u16::<T>(definition.flags)
+                .expect("could not write");
+            data.write_u16::<T>(definition.index)
+                .expect("could not write");
+            data.write_u16::<T>(names.len() as u16)
+                .expect("could not write");
+            data.write_u32::<T>(sysv_hash(&definition.name))
+                .expect("could not write");
+            data.write_u32::<T>(20).expect("could not write");
+            data.write_u32::<T>(next).expect("could not write");
+
+            for (j, name) in names.iter().enumerate() {
+                let next = if j + 1 == names.len() { 0 } else { 8 };
+                let name = *dynstr.get(*name).expect("version name not in .dynstr");
+                data.write_u32::<T>(name as u32).expect("could not write");
+                data.write_u32::<T>(next).expect("could not write");
+            }
+        }
+
+        Section64 {
+            name: ".gnu.version_d".to_string(),
+            r#type: GnuVerdef,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynstr_idx as u32,
+            info: definitions.len() as u32,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+
+    pub fn from_verneed<T: byteorder::ByteOrder>(
+        requirements: &[VersionRequirement],
+        dynstr: &HashMap<String, usize>,
+        dynstr_idx: usize,
+    ) -> Self {
+        use byteorder::WriteBytesExt;
+        use SectionType64::*;
+
+        // Elf64_Verneed and Elf64_Vernaux are both 16 bytes
+        let mut data = Vec::new();
+        for (i, requirement) in requirements.iter().enumerate() {
+            let count = requirement.versions.len();
+            let next = if i + 1 == requirements.len() {
+                0
+            } else {
+                16 + 16 * count as u32
+            };
+            let file = *dynstr
+                .get(&requirement.file)
+                .expect("file name not in .dynstr");
+
+            data.write_u16::<T>(1).expect("could not write");
+            data.write_u16::<T>(count as u16).expect("could not write");
+            data.write_u32::<T>(file as u32).expect("could not write");
+            data.write_u32::<T>(16).expect("could not write");
+            data.write_u32::<T>(next).expect("could not write");
+
+            for (j, (version, index)) in requirement.versions.iter().enumerate() {
+                let next = if j + 1 == count { 0 } else { 16 };
+                let name = *dynstr.get(version).expect("version name not in .dynstr");
+                data.write_u32::<T>(sysv_hash(version))
+                    .expect("could not write");
+                data.write_u16::<T>(0).expect("could not write");
+                data.write_u16::<T>(*index).expect("could not write");
+                data.write_u32::<T>(name as u32).expect("could not write");
+                data.write_u32::<T>(next).expect("could not write");
+            }
+        }
+
+        Section64 {
+            name: ".gnu.version_r".to_string(),
+            r#type: GnuVerneed,
+            flags: SHF_ALLOC,
+            addr: 0,
+            link: dynstr_idx as u32,
+            info: requirements.len() as u32,
+            addralign: 8,
+            entsize: 0,
+            data,
+            relocations: None,
+        }
+    }
+}
@@ -1,16 +1,36 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io;
 
-use super::section::Section64;
-use super::{ElfFile64, EHSIZE_64, ELF_MAGIC};
+use super::relocation::relocation_section_name;
+use super::section::{
+    shndx_to_raw, word_align, Section64, SectionType64, SHF_ALLOC, SHN_LORESERVE, SHN_XINDEX,
+};
+use super::segment::{phentsize, SegmentType64};
+use super::strtab;
+use super::{ehsize, shentsize, write_word, ElfFile64, ParseLimits, ELFCLASS32, ELF_MAGIC};
+use crate::utils;
 
 impl ElfFile64 {
     pub fn write_out<W: io::Write>(file: ElfFile64, output: W) -> io::Result<()> {
+        ElfFile64::write_out_with_limits(file, output, &ParseLimits::default())
+    }
+
+    // nothing is written if the file would be bigger than
+    // `limits.max_output_size`
+    pub fn write_out_with_limits<W: io::Write>(
+        file: ElfFile64,
+        output: W,
+        limits: &ParseLimits,
+    ) -> io::Result<()> {
         use nom::number::Endianness::*;
 
+        let max_size = limits.max_output_size;
         match file.header.identifier.endianness {
-            Big => ElfFile64::write_out_endian::<_, byteorder::BigEndian>(file, output),
-            Little => ElfFile64::write_out_endian::<_, byteorder::LittleEndian>(file, output),
+            Big => ElfFile64::write_out_endian::<_, byteorder::BigEndian>(file, output, max_size),
+            Little => {
+                ElfFile64::write_out_endian::<_, byteorder::LittleEndian>(file, output, max_size)
+            }
             _ => unreachable!(),
         }
     }
@@ -18,6 +38,7 @@ impl ElfFile64 {
     fn write_out_endian<W: io::Write, T: byteorder::ByteOrder>(
         mut file: ElfFile64,
         mut output: W,
+        max_size: u64,
     ) -> io::Result<()> {
         let mut num_relas = 0;
         // collect data needed to build string tables
@@ -32,12 +53,19 @@ impl ElfFile64 {
         section_names.push(".symtab".to_string());
         for section in file.unorganized_sections.iter() {
             if section.relocations.is_some() {
-                let rela_name = format!(".rela{}", &section.name);
-                section_names.push(rela_name);
+                section_names.push(relocation_section_name(section));
                 num_relas += 1;
             }
         }
 
+        // .symtab_shndx goes last, so it doesn't move the other sections
+        let num_sections = file.unorganized_sections.len() + num_relas + 3;
+        let symtab_index = file.unorganized_sections.len() + num_relas;
+        let symtab_shndx = Section64::from_symtab_shndx::<T>(&file.symbols, symtab_index);
+        if let Some(symtab_shndx) = &symtab_shndx {
+            section_names.push(symtab_shndx.name.clone());
+        }
+
         let strtab = build_string_table(symbol_names);
         let shstrtab = build_string_table(section_names);
 
@@ -45,17 +73,23 @@ impl ElfFile64 {
         let mut relas = Vec::new();
         for (idx, section) in file.unorganized_sections.iter().enumerate() {
             if let Some(ref rs) = section.relocations {
-                let symtab_index = file.unorganized_sections.len() + num_relas;
-                let name = format!(".rela{}", &section.name);
-                relas.push(Section64::from_rela::<T>(rs, symtab_index, idx, name));
+                let name = relocation_section_name(section);
+                relas.push(Section64::from_rela::<T>(
+                    rs,
+                    file.class(),
+                    symtab_index,
+                    idx,
+                    name,
+                ));
             }
         }
 
         let symtab = Section64::from_symtab::<T>(
             &file.symbols,
+            file.class(),
             &strtab,
             // will be index of .strtab where symbol names will be held
-            file.unorganized_sections.len() + num_relas + 1,
+            symtab_index + 1,
         );
 
         // put all sections together
@@ -65,48 +99,123 @@ impl ElfFile64 {
         sections.push(symtab);
         sections.push(Section64::from_strtab(&strtab, ".strtab".to_string()));
         sections.push(Section64::from_strtab(&shstrtab, ".shstrtab".to_string()));
+        sections.extend(symtab_shndx);
+        let shstrndx = num_sections - 1;
 
         // write file
-        file.write_header::<_, T>(&mut output, &sections)?;
-        file.write_section_data(&mut output, &sections)?;
-        file.write_section_headers::<_, T>(&mut output, &sections, &shstrtab)?;
+        let (offsets, shoff) = file.section_offsets(&sections);
+        // the section headers come last
+        let size = shoff.saturating_add((sections.len() * shentsize(file.class())) as u64);
+        if size > max_size {
+            return Err(io::Error::other(format!(
+                "the output would be {} bytes, more than the limit of {}",
+                size, max_size
+            )));
+        }
+        file.write_header::<_, T>(&mut output, &sections, shstrndx, shoff)?;
+        file.write_program_headers::<_, T>(&mut output)?;
+        file.write_section_data(&mut output, &sections, &offsets, shoff)?;
+        file.write_section_headers::<_, T>(&mut output, &sections, shstrndx, &offsets, &shstrtab)?;
 
         Ok(())
     }
 
+    // the ELF header and program headers come first in the file
+    fn headers_size(&self) -> u64 {
+        (ehsize(self.class()) + self.segments.len() * phentsize(self.class())) as u64
+    }
+
+    // where each section's data goes in the file, and where the section headers
+    // go after them. Sections in a loadable segment are placed according to
+    // their address, everything else is packed in after them at an offset
+    // that meets its `sh_addralign`.
+    fn section_offsets(&self, sections: &[Section64]) -> (Vec<u64>, u64) {
+        let loads: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|s| s.r#type == SegmentType64::Load)
+            .collect();
+
+        let mut offset = self.headers_size();
+        for load in loads.iter() {
+            offset = std::cmp::max(offset, load.offset + load.filesz);
+        }
+
+        let mut offsets = Vec::new();
+        for section in sections {
+            let load = loads
+                .iter()
+                .find(|l| section.flags & SHF_ALLOC != 0 && l.contains(section.addr));
+
+            match load {
+                _ if section.r#type == SectionType64::Null => offsets.push(0),
+                Some(load) => offsets.push(load.offset + section.addr - load.vaddr),
+                None => {
+                    offset = utils::align_up(offset, section.addralign);
+                    offsets.push(offset);
+                    offset += get_file_size(section) as u64;
+                }
+            }
+        }
+
+        // the section header table is made of words too
+        let shoff = utils::align_up(offset, word_align(self.class()));
+
+        (offsets, shoff)
+    }
+
     fn write_header<W: io::Write, T: byteorder::ByteOrder>(
         &self,
         output: &mut W,
-        sections: &Vec<Section64>,
+        sections: &[Section64],
+        shstrndx: usize,
+        shoff: u64,
     ) -> io::Result<()> {
         use byteorder::WriteBytesExt;
 
+        let class = self.class();
+        let (phoff, phentsize) = if self.segments.is_empty() {
+            (0, self.header.phentsize)
+        } else {
+            (ehsize(class) as u64, phentsize(class) as u16)
+        };
+
         // write ELF identifier
-        output.write(ELF_MAGIC)?;
-        output.write(&[
-            2_u8, // 64-bit object
+        output.write_all(ELF_MAGIC)?;
+        output.write_all(&[
+            class,
             serialize_endianness(self.header.identifier.endianness),
             self.header.identifier.version,
             self.header.identifier.os_abi,
             self.header.identifier.abi_version,
         ])?;
-        output.write(&[0; 7])?;
+        output.write_all(&[0; 7])?;
 
         // write rest of ELF header
         output.write_u16::<T>(self.header.r#type)?;
         output.write_u16::<T>(self.header.machine)?;
         output.write_u32::<T>(self.header.version)?;
-        output.write_u64::<T>(0)?;
-        output.write_u64::<T>(0)?;
-        let shoff = get_section_data_size(sections) as u64;
-        output.write_u64::<T>(EHSIZE_64 as u64 + shoff)?;
+        write_word::<T, _>(output, class, self.header.entry)?;
+        write_word::<T, _>(output, class, phoff)?;
+        write_word::<T, _>(output, class, shoff)?;
         output.write_u32::<T>(self.header.flags)?;
-        output.write_u16::<T>(EHSIZE_64 as u16)?;
-        output.write_u16::<T>(self.header.phentsize)?;
-        output.write_u16::<T>(0)?;
-        output.write_u16::<T>(self.header.shentsize)?;
-        output.write_u16::<T>(sections.len() as u16)?;
-        output.write_u16::<T>((sections.len() - 1) as u16)?;
+        output.write_u16::<T>(ehsize(class) as u16)?;
+        output.write_u16::<T>(phentsize)?;
+        output.write_u16::<T>(self.segments.len() as u16)?;
+        output.write_u16::<T>(shentsize(class) as u16)?;
+        output.write_u16::<T>(shnum_field(sections.len()))?;
+        output.write_u16::<T>(shndx_to_raw(shstrndx as u32))?;
+
+        Ok(())
+    }
+
+    fn write_program_headers<W: io::Write, T: byteorder::ByteOrder>(
+        &self,
+        output: &mut W,
+    ) -> io::Result<()> {
+        for segment in self.segments.iter() {
+            output.write_all(&segment.as_raw::<T>(self.class())[..])?;
+        }
 
         Ok(())
     }
@@ -114,10 +223,33 @@ impl ElfFile64 {
     fn write_section_data<W: io::Write>(
         &self,
         output: &mut W,
-        sections: &Vec<Section64>,
+        sections: &[Section64],
+        offsets: &[u64],
+        shoff: u64,
     ) -> io::Result<()> {
-        for section in sections {
-            output.write(&section.data[..])?;
+        let mut order: Vec<usize> = (0..sections.len()).collect();
+        order.sort_by_key(|i| offsets[*i]);
+
+        let mut position = self.headers_size();
+        for i in order {
+            let size = get_file_size(&sections[i]);
+            if size == 0 {
+                continue;
+            }
+
+            // pad up to where the section goes
+            if offsets[i] > position {
+                write_zeroes(output, offsets[i] - position)?;
+                position = offsets[i];
+            }
+
+            output.write_all(&sections[i].data[..])?;
+            position += size as u64;
+        }
+
+        // and up to the section headers
+        if shoff > position {
+            write_zeroes(output, shoff - position)?;
         }
 
         Ok(())
@@ -126,52 +258,68 @@ impl ElfFile64 {
     fn write_section_headers<W: io::Write, T: byteorder::ByteOrder>(
         &self,
         output: &mut W,
-        sections: &Vec<Section64>,
+        sections: &[Section64],
+        shstrndx: usize,
+        offsets: &[u64],
         shstrtab: &HashMap<String, usize>,
     ) -> io::Result<()> {
         use super::section::SectionType64::*;
         use byteorder::WriteBytesExt;
 
-        let mut offset = EHSIZE_64;
-        for section in sections {
+        let class = self.class();
+        let word = if class == ELFCLASS32 { 4 } else { 8 };
+        for (i, (section, offset)) in sections.iter().zip(offsets.iter()).enumerate() {
             let name = *shstrtab
                 .get(&section.name)
                 .expect("could not find section name") as u32;
+            let mut size = section.data.len() as u64;
+            let mut link = section.link;
+            // section header 0 holds what doesn't fit in the ELF header
+            if i == 0 {
+                if shnum_field(sections.len()) == 0 {
+                    size = sections.len() as u64;
+                }
+                if shndx_to_raw(shstrndx as u32) == SHN_XINDEX as u16 {
+                    link = shstrndx as u32;
+                }
+            }
 
             output.write_u32::<T>(name)?;
             output.write_u32::<T>(section.r#type.into())?;
-            output.write_u64::<T>(section.flags)?;
-            output.write_u64::<T>(section.addr)?;
-            output.write_u64::<T>(offset as u64)?;
-            output.write_u64::<T>(section.data.len() as u64)?;
-            output.write_u32::<T>(section.link)?;
+            write_word::<T, _>(output, class, section.flags)?;
+            write_word::<T, _>(output, class, section.addr)?;
+            write_word::<T, _>(output, class, *offset)?;
+            write_word::<T, _>(output, class, size)?;
+            output.write_u32::<T>(link)?;
             output.write_u32::<T>(section.info)?;
-            output.write_u64::<T>(section.addralign)?;
+            write_word::<T, _>(output, class, section.addralign)?;
             let entsize = match section.r#type {
-                Symtab => 24,
-                Rela => 24,
-                _ => 0,
+                Symtab | Dynsym if class == ELFCLASS32 => 16,
+                Symtab | Dynsym => 24,
+                Rela => 3 * word,
+                Rel => 2 * word,
+                Dynamic => 2 * word,
+                Hash => 4,
+                GnuVersym => 2,
+                SymtabShndx => 4,
+                _ => section.entsize,
             };
-            output.write_u64::<T>(entsize)?;
-
-            offset += section.data.len();
+            write_word::<T, _>(output, class, entsize)?;
         }
 
         Ok(())
     }
 }
 
-fn build_string_table(strings: Vec<String>) -> HashMap<String, usize> {
+pub(crate) fn build_string_table(strings: Vec<String>) -> HashMap<String, usize> {
     let mut retval = HashMap::new();
     retval.insert("".to_string(), 0);
 
     let mut offset = 1;
     for string in strings {
-        if retval.contains_key(&string) {
-            continue;
-        } else {
-            let len = string.len();
-            retval.insert(string, offset);
+        if let Entry::Vacant(entry) = retval.entry(string) {
+            let len = strtab::encode(entry.key()).len();
+            entry.insert(offset);
             offset += len + 1;
         }
     }
@@ -179,6 +327,15 @@ fn build_string_table(strings: Vec<String>) -> HashMap<String, usize> {
     retval
 }
 
+// `e_shnum`, 0 if there are too many sections for it
+fn shnum_field(num_sections: usize) -> u16 {
+    if num_sections < SHN_LORESERVE as u16 as usize {
+        num_sections as u16
+    } else {
+        0
+    }
+}
+
 fn serialize_endianness(e: nom::number::Endianness) -> u8 {
     use nom::number::Endianness::*;
 
@@ -189,11 +346,19 @@ fn serialize_endianness(e: nom::number::Endianness) -> u8 {
     }
 }
 
-fn get_section_data_size(sections: &Vec<Section64>) -> usize {
-    let mut size = 0;
-    for section in sections {
-        size += section.data.len();
+// number of bytes a section takes up in the file, .bss and friends have a size
+// but no data
+fn get_file_size(section: &Section64) -> usize {
+    if section.r#type == SectionType64::Nobits {
+        0
+    } else {
+        section.data.len()
     }
+}
+
+// padding, written without holding all of it at once
+fn write_zeroes<W: io::Write>(output: &mut W, len: u64) -> io::Result<()> {
+    io::copy(&mut io::Read::take(io::repeat(0), len), output)?;
 
-    size
+    Ok(())
 }
@@ -0,0 +1,65 @@
+use crate::elf::section::{Section64, SectionType64, SHF_EXECINSTR};
+use crate::elf::ElfFile64;
+
+// the empty section compilers put in every object, executable if its code
+// needs an executable stack (like GCC's trampolines for nested functions)
+pub(crate) const NOTE: &str = ".note.GNU-stack";
+
+// why an input needs an executable stack, if it does
+fn reason(file: &ElfFile64) -> Option<&'static str> {
+    match file.unorganized_sections.iter().find(|s| s.name == NOTE) {
+        None => Some("missing .note.GNU-stack section implies executable stack"),
+        Some(note) if note.flags & SHF_EXECINSTR != 0 => {
+            Some("requires executable stack (because the .note.GNU-stack section is executable)")
+        }
+        Some(_) => None,
+    }
+}
+
+// whether the output's stack is executable: what `-z execstack` or
+// `-z noexecstack` say, otherwise whether any input needs it, adding a
+// warning to `warnings` naming each one that does
+pub(crate) fn is_executable(
+    files: &[ElfFile64],
+    exec_stack: Option<bool>,
+    warnings: &mut Vec<String>,
+) -> bool {
+    if let Some(exec_stack) = exec_stack {
+        return exec_stack;
+    }
+
+    let mut executable = false;
+    for file in files {
+        if let Some(reason) = reason(file) {
+            warnings.push(format!("{}: {}", &file.name, reason));
+            executable = true;
+        }
+    }
+
+    executable
+}
+
+// leave a relocatable output with one .note.GNU-stack saying whether the
+// stack is executable, for whatever it's linked into next
+pub(crate) fn set_note(file: &mut ElfFile64, executable: bool) {
+    let flags = if executable { SHF_EXECINSTR } else { 0 };
+    match file
+        .unorganized_sections
+        .iter_mut()
+        .find(|s| s.name == NOTE)
+    {
+        Some(note) => note.flags = flags,
+        None => file.unorganized_sections.push(Section64 {
+            name: NOTE.to_string(),
+            r#type: SectionType64::Progbits,
+            flags,
+            addr: 0,
+            link: 0,
+            info: 0,
+            addralign: 1,
+            entsize: 0,
+            data: Vec::new(),
+            relocations: None,
+        }),
+    }
+}
@@ -0,0 +1,2131 @@
+use std::collections::HashMap;
+
+use crate::arch::aarch64;
+use crate::arch::riscv::{self, R_RISCV_GOT_HI20};
+use crate::arch::x86_64::{self, *};
+use crate::arch::{machine_name, Arch, RelocationValues};
+use crate::eh_frame;
+use crate::elf::dynamic::*;
+use crate::elf::relocation::RelocationA64;
+use crate::elf::section::{
+    word_align, Section64, SectionType64, SHF_ALLOC, SHF_EXECINSTR, SHF_TLS, SHF_WRITE, SHN_ABS,
+    SHN_LORESERVE,
+};
+use crate::elf::segment::{phentsize, Segment64, SegmentType64, PF_R, PF_W, PF_X};
+use crate::elf::shared_object::{SharedObject64, SharedSymbol64};
+use crate::elf::symbol::*;
+use crate::elf::version::*;
+use crate::elf::{
+    build_string_table, ehsize, strtab, write_word, ElfFile64, ELFCLASS64, ET_DYN, ET_EXEC,
+};
+use crate::exec_stack;
+use crate::link::{LinkError, LinkOptions, OutputKind};
+use crate::relax::Relaxation;
+use crate::utils;
+use crate::version_script::VersionAssignment;
+
+// where static executables are loaded
+const EXECUTABLE_BASE: u64 = 0x400000;
+const ENTRY_SYMBOL: &str = "_start";
+
+// defined by the linker at the start of .got when it's referenced
+const GOT_SYMBOL: &str = "_GLOBAL_OFFSET_TABLE_";
+
+// the page size RELRO is padded to unless told otherwise, the smallest any of
+// the architectures run with
+const COMMON_PAGE_SIZE: u64 = 0x1000;
+
+// indexes of the sections the linker synthesizes, 0 for ones left out
+struct SyntheticSections {
+    hash: usize,
+    gnu_hash: usize,
+    dynsym: usize,
+    dynstr: usize,
+    versym: usize,
+    verdef: usize,
+    verneed: usize,
+    rela_dyn: usize,
+    plt: usize,
+    dynamic: usize,
+    got: usize,
+    eh_frame_hdr: usize,
+    interp: usize,
+}
+
+// the kinds of things stored in the GOT, by symbol index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum GotEntry {
+    // the address of a symbol
+    Address(usize),
+    // a TLS module ID and offset, for the general dynamic model
+    TlsGd(usize),
+    // the TLS module ID of the output itself, for the local dynamic model
+    TlsLd,
+    // an offset from the thread pointer, for the initial exec model
+    TlsIe(usize),
+}
+
+// what the relocations of the merged file need from the linker
+struct RelocationScan {
+    got_entries: Vec<GotEntry>,
+    // GOT slot of the start of each entry
+    got_map: HashMap<GotEntry, usize>,
+    num_got_slots: usize,
+    plt_slots: Vec<usize>,
+    plt_map: HashMap<usize, usize>,
+    num_dynamic_relas: usize,
+    num_relative_relas: usize,
+    textrel: bool,
+    static_tls: bool,
+}
+
+// the version of every symbol in .dynsym, and the versions defined by the
+// output and needed from the shared objects it's linked against
+struct SymbolVersions {
+    versyms: HashMap<usize, u16>,
+    definitions: Vec<VersionDefinition>,
+    requirements: Vec<VersionRequirement>,
+}
+
+impl ElfFile64 {
+    // turn the merged relocatable file into a loadable shared object: lay out
+    // the allocated sections in memory, apply relocations, and build the
+    // sections the dynamic linker needs
+    pub(crate) fn finalize(
+        &mut self,
+        shared_objects: &[SharedObject64],
+        options: &LinkOptions,
+        exec_stack: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), LinkError> {
+        use nom::number::Endianness::*;
+
+        match self.header.identifier.endianness {
+            Big => self.finalize_endian::<byteorder::BigEndian>(
+                shared_objects,
+                options,
+                exec_stack,
+                warnings,
+            ),
+            Little => self.finalize_endian::<byteorder::LittleEndian>(
+                shared_objects,
+                options,
+                exec_stack,
+                warnings,
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn finalize_endian<T: byteorder::ByteOrder>(
+        &mut self,
+        shared_objects: &[SharedObject64],
+        options: &LinkOptions,
+        exec_stack: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), LinkError> {
+        use SectionType64::*;
+
+        let arch = Arch::from_machine(self.header.machine).ok_or_else(|| {
+            LinkError::Unsupported(format!(
+                "final links can only be done for x86-64, AArch64 and RISC-V, not {}",
+                machine_name(self.header.machine)
+            ))
+        })?;
+
+        // static executables don't need anything from the dynamic linker
+        let dynamic = options.output_kind == OutputKind::Shared;
+        if dynamic && arch != Arch::X86_64 {
+            return Err(LinkError::Unsupported(
+                "shared objects can only be linked for x86-64".to_string(),
+            ));
+        }
+        let class = self.class();
+        // GOT entries are addresses
+        let got_entry_size = word_align(class) as usize;
+
+        // the symbols the linker defines aren't exported, or imported
+        let input_sections = &self.unorganized_sections;
+        for symbol in self.symbols.iter_mut() {
+            let defined = match marker(&symbol.name) {
+                Some(Marker::Start(name)) | Some(Marker::Stop(name)) => {
+                    input_sections.iter().any(|s| s.name == name)
+                }
+                Some(Marker::Dynamic) => dynamic,
+                Some(_) => true,
+                None => symbol.name == GOT_SYMBOL,
+            };
+            if symbol.shndx == 0 && defined {
+                symbol.set_visibility(SymbolVisibility::Hidden);
+            }
+        }
+
+        // this can make symbols local, so it has to happen before anything
+        // looks at which symbols are preemptible
+        let versions = self.assign_versions(shared_objects, options)?;
+
+        if options.relax && arch == Arch::X86_64 {
+            self.relax_got_loads(options);
+        }
+
+        let scan = self.scan_relocations(arch, options)?;
+
+        // .dynsym names don't include the version, that's in .gnu.version
+        let dynamic_name = |symbol: &Symbol64| split_versioned_name(&symbol.name).0.to_string();
+
+        // undefined symbols are not hashed in .gnu.hash, so they go first
+        let (undefined, mut defined): (Vec<usize>, Vec<usize>) = (0..self.symbols.len())
+            .filter(|i| dynamic && self.symbols[*i].is_visible_outside())
+            .partition(|i| self.symbols[*i].shndx == 0);
+        let symoffset = undefined.len() + 1;
+        let nbuckets = gnu_hash_buckets(defined.len()) as u32;
+        defined.sort_by_key(|i| gnu_hash(&dynamic_name(&self.symbols[*i])) % nbuckets);
+        let dynsym_order: Vec<usize> = undefined.into_iter().chain(defined).collect();
+        let dynsym_map: HashMap<usize, usize> = dynsym_order
+            .iter()
+            .enumerate()
+            .map(|(new_index, old_index)| (*old_index, new_index + 1))
+            .collect();
+
+        let mut dynstr_names: Vec<String> = dynsym_order
+            .iter()
+            .map(|i| dynamic_name(&self.symbols[*i]))
+            .collect();
+        dynstr_names.extend(shared_objects.iter().map(|so| so.soname.clone()));
+        dynstr_names.extend(options.soname.iter().cloned());
+        dynstr_names.extend(options.rpath.iter().cloned());
+        for definition in versions.definitions.iter() {
+            dynstr_names.push(definition.name.clone());
+            dynstr_names.extend(definition.parents.iter().cloned());
+        }
+        for requirement in versions.requirements.iter() {
+            dynstr_names.extend(requirement.versions.iter().map(|(name, _)| name.clone()));
+        }
+        let dynstr = build_string_table(dynstr_names);
+
+        // every .dynsym entry gets a version if any of them have one
+        let versyms: Vec<u16> = if versions.is_empty() {
+            Vec::new()
+        } else {
+            std::iter::once(VER_NDX_LOCAL)
+                .chain(
+                    dynsym_order
+                        .iter()
+                        .map(|i| *versions.versyms.get(i).unwrap_or(&VER_NDX_GLOBAL)),
+                )
+                .collect()
+        };
+
+        let dynsyms = |symbols: &Vec<Symbol64>| {
+            let mut retval = vec![Symbol64 {
+                name: "".to_string(),
+                info: 0,
+                other: 0,
+                shndx: 0,
+                value: 0,
+                size: 0,
+            }];
+            retval.extend(dynsym_order.iter().map(|i| Symbol64 {
+                name: dynamic_name(&symbols[*i]),
+                ..symbols[*i].clone()
+            }));
+            retval
+        };
+
+        // put synthetic sections (sized, but mostly not yet filled in) after
+        // the input sections, then sort everything into its final order
+        let mut sections = std::mem::take(&mut self.unorganized_sections);
+        let num_input_sections = sections.len();
+        let mut dynstr_section = Section64::from_strtab(&dynstr, ".dynstr".to_string());
+        dynstr_section.flags = SHF_ALLOC;
+        sections.push(Section64::from_hash::<T>(&dynsyms(&self.symbols), 0));
+        sections.push(Section64::from_gnu_hash::<T>(
+            &dynsyms(&self.symbols),
+            symoffset,
+            0,
+        ));
+        sections.push(Section64::from_dynsym::<T>(
+            &dynsyms(&self.symbols),
+            &dynstr,
+            0,
+        ));
+        sections.push(dynstr_section);
+        sections.push(Section64::from_versym::<T>(&versyms, 0));
+        sections.push(Section64::from_verdef::<T>(
+            &versions.definitions,
+            &dynstr,
+            0,
+        ));
+        sections.push(Section64::from_verneed::<T>(
+            &versions.requirements,
+            &dynstr,
+            0,
+        ));
+        sections.push(synthetic_section(
+            ".rela.dyn",
+            Rela,
+            SHF_ALLOC,
+            8,
+            scan.num_dynamic_relas * 24,
+        ));
+        sections.push(synthetic_section(
+            ".plt",
+            Progbits,
+            SHF_ALLOC | SHF_EXECINSTR,
+            16,
+            scan.plt_slots.len() * PLT_ENTRY_SIZE,
+        ));
+        sections.push(Section64::from_dynamic::<T>(&[], 0));
+        sections.push(synthetic_section(
+            ".got",
+            Progbits,
+            SHF_ALLOC | SHF_WRITE,
+            got_entry_size as u64,
+            scan.num_got_slots * got_entry_size,
+        ));
+        // sized now, filled in once .eh_frame has been relocated
+        let eh_frame_hdr_size = match sections.iter().find(|s| s.name == ".eh_frame") {
+            Some(eh_frame) if options.eh_frame_hdr => eh_frame::header_size::<T>(&eh_frame.data),
+            _ => 0,
+        };
+        sections.push(synthetic_section(
+            ".eh_frame_hdr",
+            Progbits,
+            SHF_ALLOC,
+            4,
+            eh_frame_hdr_size,
+        ));
+        // the path of the program that loads the output if it's run (which
+        // only a dynamic one can be)
+        let mut interp = synthetic_section(".interp", Progbits, SHF_ALLOC, 1, 0);
+        if let (Some(dynamic_linker), true) = (&options.dynamic_linker, dynamic) {
+            interp.data = strtab::encode(dynamic_linker);
+            interp.data.push(0);
+        }
+        sections.push(interp);
+
+        let synthetic_index = |n: usize| num_input_sections + n;
+        let sections_ref = &sections;
+        // .note.GNU-stack has said what it has to in PT_GNU_STACK
+        let inputs_with_rank = |rank: usize| {
+            (1..num_input_sections).filter(move |i| {
+                section_rank(&sections_ref[*i]) == rank && sections_ref[*i].name != exec_stack::NOTE
+            })
+        };
+
+        let mut order = vec![0, synthetic_index(12)];
+        order.extend(inputs_with_rank(0));
+        order.extend((0..8).map(synthetic_index));
+        order.extend(inputs_with_rank(1));
+        order.push(synthetic_index(11));
+        order.extend(inputs_with_rank(2));
+        order.push(synthetic_index(8));
+        order.extend(inputs_with_rank(3));
+        order.extend(inputs_with_rank(4));
+        order.extend(inputs_with_rank(5));
+        order.extend((9..11).map(synthetic_index));
+        order.extend(inputs_with_rank(6));
+        order.extend(inputs_with_rank(7));
+        order.extend(inputs_with_rank(8));
+        // drop the version sections, .rela.dyn, .plt, .eh_frame_hdr and
+        // .interp if they turned out not to be needed, and everything but
+        // .got from static executables
+        let optional: Vec<usize> = (4..9).chain(11..13).map(synthetic_index).collect();
+        order.retain(|i| !optional.contains(i) || !sections[*i].data.is_empty());
+        if !dynamic {
+            let unneeded: Vec<usize> = (0..10).map(synthetic_index).collect();
+            order.retain(|i| !unneeded.contains(i));
+        }
+
+        let notes: Vec<usize> = (1..num_input_sections)
+            .filter(|i| sections[*i].name == exec_stack::NOTE)
+            .collect();
+
+        let mut index_map = HashMap::new();
+        let mut slots: Vec<Option<Section64>> = sections.into_iter().map(Some).collect();
+        let mut out = Vec::new();
+        for (new_index, old_index) in order.iter().enumerate() {
+            index_map.insert(*old_index, new_index);
+            out.push(slots[*old_index].take().unwrap());
+        }
+
+        let new_index = |n: usize| *index_map.get(&synthetic_index(n)).unwrap_or(&0);
+        let syn = SyntheticSections {
+            hash: new_index(0),
+            gnu_hash: new_index(1),
+            dynsym: new_index(2),
+            dynstr: new_index(3),
+            versym: new_index(4),
+            verdef: new_index(5),
+            verneed: new_index(6),
+            rela_dyn: new_index(7),
+            plt: new_index(8),
+            dynamic: new_index(9),
+            got: new_index(10),
+            eh_frame_hdr: new_index(11),
+            interp: new_index(12),
+        };
+        let links = [
+            (syn.hash, syn.dynsym),
+            (syn.gnu_hash, syn.dynsym),
+            (syn.dynsym, syn.dynstr),
+            (syn.dynamic, syn.dynstr),
+            (syn.rela_dyn, syn.dynsym),
+            (syn.versym, syn.dynsym),
+            (syn.verdef, syn.dynstr),
+            (syn.verneed, syn.dynstr),
+        ];
+        for (section, link) in links.iter() {
+            if *section != 0 {
+                out[*section].link = *link as u32;
+            }
+        }
+
+        // RISC-V code reaches small data relative to the global pointer, which
+        // is 2KiB into it so that all of a 12-bit signed offset's range is used
+        let small_data = out.iter().position(|s| s.name == ".sdata").or_else(|| {
+            out.iter().position(|s| {
+                s.flags & (SHF_ALLOC | SHF_WRITE) == SHF_ALLOC | SHF_WRITE && s.flags & SHF_TLS == 0
+            })
+        });
+
+        for symbol in self.symbols.iter_mut() {
+            if symbol.shndx != 0 && symbol.shndx < SHN_LORESERVE {
+                symbol.shndx = match index_map.get(&(symbol.shndx as usize)) {
+                    Some(new_index) => *new_index as u32,
+                    // the note is empty, so its symbols are all at 0
+                    None if notes.contains(&(symbol.shndx as usize)) => SHN_ABS,
+                    None => {
+                        return Err(LinkError::InvalidSymbol(format!(
+                            "symbol `{}` is in section {}, which isn't in the output",
+                            &symbol.name, symbol.shndx
+                        )))
+                    }
+                };
+            } else if symbol.shndx == 0 && symbol.name == GOT_SYMBOL {
+                symbol.shndx = syn.got as u32;
+            } else if symbol.shndx == 0
+                && symbol.name == riscv::GLOBAL_POINTER_SYMBOL
+                && arch == Arch::Riscv
+            {
+                if let Some(small_data) = small_data {
+                    symbol.shndx = small_data as u32;
+                    symbol.value = 0x800;
+                }
+            }
+        }
+
+        // symbols the linker defines, by the section they're in and where
+        // in it they are, which is only known once it's laid out
+        let mut markers = Vec::new();
+        for (i, symbol) in self.symbols.iter_mut().enumerate() {
+            if symbol.shndx != 0 {
+                continue;
+            }
+            let marked = match marker(&symbol.name) {
+                Some(marker) => marked_section(&marker, &out, syn.dynamic),
+                None => None,
+            };
+            if let Some((shndx, position)) = marked {
+                symbol.shndx = shndx as u32;
+                markers.push((i, position));
+            }
+        }
+
+        // only default visibility symbols can come from another module
+        for symbol in self.symbols.iter() {
+            let visibility = symbol.visibility();
+            if symbol.shndx == 0 && visibility != SymbolVisibility::Default {
+                return Err(LinkError::UndefinedSymbol(format!(
+                    "{:?} symbol `{}` is not defined",
+                    visibility, &symbol.name
+                )));
+            }
+        }
+
+        // now that every section is in place, the number of .dynamic entries
+        // is known
+        if dynamic {
+            let num_dynamic =
+                dynamic_entries(&out, &syn, shared_objects, options, &dynstr, &scan).len();
+            out[syn.dynamic].data = vec![0; num_dynamic * 16];
+        }
+
+        // what's only written by relocations, which can be made read-only
+        // once they're done: everything writable up to the end of .got
+        let relro_start = (1..=syn.got)
+            .find(|i| out[*i].flags & (SHF_WRITE | SHF_EXECINSTR) == SHF_WRITE)
+            .unwrap();
+        let relro = options.relro
+            && out[relro_start..=syn.got]
+                .iter()
+                .any(|s| !s.data.is_empty());
+
+        let notes = note_runs(&out);
+        let has_tls = out.iter().any(|s| s.flags & SHF_TLS != 0);
+        // PT_PHDR and PT_INTERP, PT_DYNAMIC, PT_NOTE, PT_TLS,
+        // PT_GNU_EH_FRAME, PT_GNU_STACK and PT_GNU_RELRO
+        let num_other_phdrs = 2 * (syn.interp != 0) as usize
+            + dynamic as usize
+            + notes.len()
+            + has_tls as usize
+            + (syn.eh_frame_hdr != 0) as usize
+            + 1
+            + relro as usize;
+        let page_size = options.max_page_size.unwrap_or_else(|| arch.page_size());
+        let common_page_size = options
+            .common_page_size
+            .unwrap_or(COMMON_PAGE_SIZE)
+            .min(page_size);
+        let params = Layout {
+            base: if dynamic { 0 } else { EXECUTABLE_BASE },
+            page_size,
+            separate_code: options.separate_code,
+            relro_end: if relro {
+                Some((syn.got, common_page_size))
+            } else {
+                None
+            },
+        };
+        let base = params.base;
+        // adding thunks makes sections bigger, which can put more branches
+        // out of range, and relaxing makes them smaller, which can bring
+        // more into range
+        let mut thunks = HashMap::new();
+        let mut relaxation = match arch {
+            Arch::Riscv => Some(Relaxation::new(&out)),
+            _ => None,
+        };
+        let loads = loop {
+            let loads = layout(&mut out, class, &params, num_other_phdrs);
+            let changed = match (arch, relaxation.as_mut()) {
+                (Arch::Aarch64, _) => self.add_thunks(&mut out, &mut thunks)?,
+                (_, Some(relaxation)) => relaxation.pass(self, &mut out, options.relax)?,
+                _ => false,
+            };
+            if !changed {
+                break loads;
+            }
+        };
+        if let Some(relaxation) = relaxation {
+            relaxation.finish(self, &mut out);
+        }
+        let tls = tls_segment(&out, base);
+
+        for (i, position) in markers {
+            let symbol = &mut self.symbols[i];
+            let section = &out[symbol.shndx as usize];
+            symbol.value = match position {
+                Position::Start => 0,
+                Position::End => section.data.len() as u64,
+                Position::Header => base.wrapping_sub(section.addr),
+            };
+        }
+
+        for symbol in self.symbols.iter_mut() {
+            if symbol.shndx != 0 && symbol.shndx < SHN_LORESERVE {
+                symbol.value = symbol.value.wrapping_add(out[symbol.shndx as usize].addr);
+            }
+        }
+
+        // the thread pointer points just past the (aligned) TLS block
+        let tls_addr = tls.as_ref().map(|t| t.vaddr).unwrap_or(0);
+        let tp_addr = tls
+            .as_ref()
+            .map(|t| t.vaddr + utils::align_up(t.memsz, t.align))
+            .unwrap_or(0);
+
+        // apply relocations, collecting the ones that must be done at load
+        // time
+        let got_addr = out[syn.got].addr;
+        let gp_addr = self
+            .symbols
+            .iter()
+            .find(|s| s.name == riscv::GLOBAL_POINTER_SYMBOL && s.shndx != 0)
+            .map(|s| s.value)
+            .unwrap_or(0);
+        let got_entry = |entry: GotEntry| {
+            scan.got_map
+                .get(&entry)
+                .map(|slot| got_addr + (slot * got_entry_size) as u64)
+        };
+        let plt_addr = out[syn.plt].addr;
+        let plt_entry = |sym_idx: usize| {
+            scan.plt_map
+                .get(&sym_idx)
+                .map(|slot| plt_addr + (slot * PLT_ENTRY_SIZE) as u64)
+        };
+
+        let mut dynamic_relas = Vec::new();
+        let mut relaxations = Vec::new();
+        let mut applied = Vec::new();
+        for (i, section) in out.iter().enumerate() {
+            let relas = match &section.relocations {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            let alloc = section.flags & SHF_ALLOC != 0;
+            // the HI20 relocations PCREL_LO12 ones pair with, by offset
+            let mut pcrel_his = HashMap::new();
+            if arch == Arch::Riscv {
+                for hi in relas.iter().filter(|r| riscv::is_pcrel_hi(r.get_type())) {
+                    pcrel_his.entry(hi.offset).or_insert(hi);
+                }
+            }
+            // set when relaxing a TLS sequence removes the call to
+            // __tls_get_addr, which has a relocation of its own
+            let mut skip_next = false;
+            for rela in relas {
+                if skip_next {
+                    skip_next = false;
+                    continue;
+                }
+
+                let sym_idx = rela.get_sym();
+                let r#type = rela.get_type();
+                let symbol = &self.symbols[sym_idx];
+                let preemptible = is_preemptible(symbol, options);
+                let offset = rela.offset as usize;
+
+                if !dynamic && symbol.shndx == 0 && sym_bind(symbol) != STB_WEAK {
+                    return Err(LinkError::UndefinedSymbol(format!(
+                        "undefined reference to `{}`",
+                        &symbol.name
+                    )));
+                }
+
+                let g = match r#type {
+                    _ if arch != Arch::X86_64 => got_entry(GotEntry::Address(sym_idx)),
+                    R_X86_64_TLSGD => got_entry(GotEntry::TlsGd(sym_idx)),
+                    R_X86_64_TLSLD => got_entry(GotEntry::TlsLd),
+                    R_X86_64_GOTTPOFF => got_entry(GotEntry::TlsIe(sym_idx)),
+                    _ => got_entry(GotEntry::Address(sym_idx)),
+                };
+                let mut values = RelocationValues {
+                    s: plt_entry(sym_idx).unwrap_or(symbol.value),
+                    a: rela.addend as i64,
+                    p: section.addr + rela.offset,
+                    got: got_addr,
+                    g: g.unwrap_or(0),
+                    z: symbol.size,
+                    tls: tls_addr,
+                    tp: tp_addr,
+                    gp: gp_addr,
+                };
+
+                // the symbol of a PCREL_LO12 relocation labels the `auipc`
+                // it pairs with, and the value is that of its HI20 relocation
+                if arch == Arch::Riscv && riscv::is_pcrel_lo(r#type) {
+                    let label = symbol.value.wrapping_add(rela.addend);
+                    let hi = pcrel_his
+                        .get(&label.wrapping_sub(section.addr))
+                        .ok_or_else(|| {
+                            LinkError::InvalidRelocation(format!(
+                                "{}: {} without a matching PCREL_HI20 at `{}`",
+                                &section.name,
+                                arch.name(r#type),
+                                &symbol.name
+                            ))
+                        })?;
+                    let hi_sym_idx = hi.get_sym();
+                    let target = match hi.get_type() {
+                        R_RISCV_GOT_HI20 => got_entry(GotEntry::Address(hi_sym_idx)).unwrap(),
+                        _ => self.symbols[hi_sym_idx].value,
+                    };
+                    values.s = target.wrapping_add(hi.addend).wrapping_sub(label);
+                    values.a = 0;
+                }
+
+                // like ld, a call to a function that isn't there does
+                // nothing, it goes to the next instruction
+                if arch == Arch::Aarch64 && aarch64::is_branch(r#type) && is_undefined_weak(symbol)
+                {
+                    values.s = values.p + 4;
+                    values.a = 0;
+                }
+
+                // executables use the local exec TLS model, so code using
+                // the other models is rewritten
+                let (r#type, offset) = match r#type {
+                    _ if arch != Arch::X86_64 => (r#type, offset),
+                    R_X86_64_TLSGD if !dynamic => {
+                        relaxations.push((i, r#type, offset));
+                        skip_next = true;
+                        values.a = 0;
+                        (R_X86_64_TPOFF32, offset + 8)
+                    }
+                    R_X86_64_TLSLD if !dynamic => {
+                        relaxations.push((i, r#type, offset));
+                        skip_next = true;
+                        continue;
+                    }
+                    R_X86_64_GOTTPOFF if g.is_none() => {
+                        relaxations.push((i, r#type, offset));
+                        values.a = 0;
+                        (R_X86_64_TPOFF32, offset)
+                    }
+                    // after relaxing TLSLD, offsets in code are from the
+                    // thread pointer, not the start of the TLS block. The
+                    // ones in debug info stay relative to the block.
+                    R_X86_64_DTPOFF32 if !dynamic && section.flags & SHF_EXECINSTR != 0 => {
+                        (R_X86_64_TPOFF32, offset)
+                    }
+                    _ => (r#type, offset),
+                };
+
+                if dynamic && alloc && r#type == R_X86_64_64 {
+                    if preemptible {
+                        let dynsym_idx = *dynsym_map.get(&sym_idx).unwrap();
+                        dynamic_relas.push(dynamic_rela(
+                            values.p,
+                            dynsym_idx,
+                            R_X86_64_64,
+                            rela.addend,
+                        ));
+                        continue;
+                    }
+
+                    let addend = values.s.wrapping_add(rela.addend);
+                    dynamic_relas.push(dynamic_rela(values.p, 0, R_X86_64_RELATIVE, addend));
+                }
+
+                applied.push((i, r#type, values, offset));
+            }
+        }
+
+        for (i, r#type, offset) in relaxations {
+            let data = &mut out[i].data;
+            match r#type {
+                R_X86_64_TLSGD => x86_64::relax_tls_gd_to_le(data, offset)?,
+                R_X86_64_TLSLD => x86_64::relax_tls_ld_to_le(data, offset)?,
+                _ => x86_64::relax_tls_ie_to_le(data, offset)?,
+            }
+        }
+
+        for (i, r#type, values, offset) in applied {
+            arch.apply::<T>(r#type, &values, &mut out[i].data, offset)?;
+        }
+
+        if syn.eh_frame_hdr != 0 {
+            let eh_frame = out.iter().find(|s| s.name == ".eh_frame").unwrap();
+            let header = eh_frame::header::<T>(
+                &eh_frame.data,
+                eh_frame.addr,
+                out[syn.eh_frame_hdr].addr,
+                got_entry_size,
+            );
+            out[syn.eh_frame_hdr].data[..header.len()].copy_from_slice(&header);
+        }
+
+        for section in out.iter_mut() {
+            section.relocations = None;
+        }
+
+        // fill in the synthetic sections. GOT entries of preemptible symbols
+        // are filled in by the dynamic linker, the rest are known now (but
+        // addresses still need relocating if the output can be loaded
+        // anywhere)
+        for entry in scan.got_entries.iter() {
+            let offset = scan.got_map[entry] * got_entry_size;
+            let addr = got_addr + offset as u64;
+            let got = &mut out[syn.got].data;
+            let dynsym_idx = |sym_idx: &usize| *dynsym_map.get(sym_idx).unwrap();
+
+            match entry {
+                GotEntry::Address(sym_idx) => {
+                    let symbol = &self.symbols[*sym_idx];
+                    if is_preemptible(symbol, options) {
+                        let dynsym_idx = dynsym_idx(sym_idx);
+                        dynamic_relas.push(dynamic_rela(addr, dynsym_idx, R_X86_64_GLOB_DAT, 0));
+                    } else {
+                        write_word::<T, _>(&mut &mut got[offset..], class, symbol.value)
+                            .expect("could not write");
+                        if dynamic {
+                            dynamic_relas.push(dynamic_rela(
+                                addr,
+                                0,
+                                R_X86_64_RELATIVE,
+                                symbol.value,
+                            ));
+                        }
+                    }
+                }
+                // a module ID, and an offset in that module's TLS block
+                GotEntry::TlsGd(sym_idx) => {
+                    let symbol = &self.symbols[*sym_idx];
+                    if is_preemptible(symbol, options) {
+                        let dynsym_idx = dynsym_idx(sym_idx);
+                        dynamic_relas.push(dynamic_rela(addr, dynsym_idx, R_X86_64_DTPMOD64, 0));
+                        dynamic_relas.push(dynamic_rela(
+                            addr + 8,
+                            dynsym_idx,
+                            R_X86_64_DTPOFF64,
+                            0,
+                        ));
+                    } else {
+                        dynamic_relas.push(dynamic_rela(addr, 0, R_X86_64_DTPMOD64, 0));
+                        T::write_u64(&mut got[offset + 8..], symbol.value - tls_addr);
+                    }
+                }
+                GotEntry::TlsLd => {
+                    dynamic_relas.push(dynamic_rela(addr, 0, R_X86_64_DTPMOD64, 0));
+                }
+                // an offset from the thread pointer
+                GotEntry::TlsIe(sym_idx) => {
+                    let symbol = &self.symbols[*sym_idx];
+                    if !dynamic {
+                        T::write_u64(&mut got[offset..], symbol.value.wrapping_sub(tp_addr));
+                    } else if is_preemptible(symbol, options) {
+                        let dynsym_idx = dynsym_idx(sym_idx);
+                        dynamic_relas.push(dynamic_rela(addr, dynsym_idx, R_X86_64_TPOFF64, 0));
+                    } else {
+                        let addend = symbol.value - tls_addr;
+                        dynamic_relas.push(dynamic_rela(addr, 0, R_X86_64_TPOFF64, addend));
+                    }
+                }
+            }
+        }
+
+        for (slot, sym_idx) in scan.plt_slots.iter().enumerate() {
+            let entry = plt_entry(*sym_idx).unwrap();
+            let got_entry = got_entry(GotEntry::Address(*sym_idx)).unwrap();
+            let offset = slot * PLT_ENTRY_SIZE;
+            out[syn.plt].data[offset..offset + PLT_ENTRY_SIZE]
+                .copy_from_slice(&x86_64::plt_entry(entry, got_entry)?);
+        }
+
+        // in executables and shared objects, TLS symbols are offsets into the
+        // TLS segment
+        for symbol in self.symbols.iter_mut() {
+            if sym_type(symbol) == STT_TLS && symbol.shndx != 0 && symbol.shndx < SHN_LORESERVE {
+                symbol.value -= tls_addr;
+            }
+        }
+
+        // RELATIVE relocations go first so the dynamic linker can process
+        // them quickly (see DT_RELACOUNT)
+        dynamic_relas.sort_by_key(|rela| rela.get_type() != R_X86_64_RELATIVE);
+        if syn.rela_dyn != 0 {
+            out[syn.rela_dyn].data =
+                Section64::from_rela::<T>(&dynamic_relas, ELFCLASS64, 0, 0, "".to_string()).data;
+        }
+
+        let mut segments = loads;
+        if dynamic {
+            out[syn.dynsym].data =
+                Section64::from_dynsym::<T>(&dynsyms(&self.symbols), &dynstr, 0).data;
+
+            let entries = dynamic_entries(&out, &syn, shared_objects, options, &dynstr, &scan);
+            out[syn.dynamic].data = Section64::from_dynamic::<T>(&entries, 0).data;
+
+            segments.push(Segment64 {
+                r#type: SegmentType64::Dynamic,
+                flags: PF_R | PF_W,
+                offset: out[syn.dynamic].addr - base,
+                vaddr: out[syn.dynamic].addr,
+                filesz: out[syn.dynamic].data.len() as u64,
+                memsz: out[syn.dynamic].data.len() as u64,
+                align: 8,
+            });
+        }
+        for (first, last) in notes {
+            let end = out[last].addr + out[last].data.len() as u64;
+            segments.push(Segment64 {
+                r#type: SegmentType64::Note,
+                flags: PF_R,
+                offset: out[first].addr - base,
+                vaddr: out[first].addr,
+                filesz: end - out[first].addr,
+                memsz: end - out[first].addr,
+                align: out[first].addralign,
+            });
+        }
+        segments.extend(tls);
+        if syn.eh_frame_hdr != 0 {
+            let eh_frame_hdr = &out[syn.eh_frame_hdr];
+            segments.push(Segment64 {
+                r#type: SegmentType64::GnuEhFrame,
+                flags: PF_R,
+                offset: eh_frame_hdr.addr - base,
+                vaddr: eh_frame_hdr.addr,
+                filesz: eh_frame_hdr.data.len() as u64,
+                memsz: eh_frame_hdr.data.len() as u64,
+                align: eh_frame_hdr.addralign,
+            });
+        }
+        segments.push(Segment64 {
+            r#type: SegmentType64::GnuStack,
+            flags: if exec_stack {
+                PF_R | PF_W | PF_X
+            } else {
+                PF_R | PF_W
+            },
+            offset: 0,
+            vaddr: 0,
+            filesz: 0,
+            memsz: 0,
+            align: 16,
+        });
+        if relro {
+            // up to the page boundary after .got, so the dynamic linker
+            // protects all of its last page
+            let start = out[relro_start].addr;
+            let end = utils::align_up(
+                out[syn.got].addr + out[syn.got].data.len() as u64,
+                common_page_size,
+            );
+            let load = segments
+                .iter()
+                .find(|s| s.r#type == SegmentType64::Load && s.contains(start))
+                .unwrap();
+            let file_end = load.vaddr + load.filesz;
+            segments.push(Segment64 {
+                r#type: SegmentType64::GnuRelro,
+                flags: PF_R,
+                offset: start - base,
+                vaddr: start,
+                filesz: std::cmp::min(end, file_end).saturating_sub(start),
+                memsz: end - start,
+                align: 1,
+            });
+        }
+
+        // the program loader goes by PT_PHDR to find the program headers,
+        // which has to come before any PT_LOAD, and the first one maps them
+        if syn.interp != 0 {
+            let phdrs_size = ((segments.len() + 2) * phentsize(class)) as u64;
+            let interp = &out[syn.interp];
+            let headers = vec![
+                Segment64 {
+                    r#type: SegmentType64::Phdr,
+                    flags: PF_R,
+                    offset: ehsize(class) as u64,
+                    vaddr: base + ehsize(class) as u64,
+                    filesz: phdrs_size,
+                    memsz: phdrs_size,
+                    align: 8,
+                },
+                Segment64 {
+                    r#type: SegmentType64::Interp,
+                    flags: PF_R,
+                    offset: interp.addr - base,
+                    vaddr: interp.addr,
+                    filesz: interp.data.len() as u64,
+                    memsz: interp.data.len() as u64,
+                    align: 1,
+                },
+            ];
+            segments.splice(0..0, headers);
+        }
+
+        // hidden and internal symbols are not visible outside the output, so
+        // they become local, and locals have to come first in .symtab.
+        // Relocations have all been applied, so symbols can be moved around.
+        for symbol in self.symbols.iter_mut() {
+            if sym_bind(symbol) != STB_LOCAL && !symbol.is_visible_outside() {
+                symbol.make_local();
+            }
+        }
+        let symbols = std::mem::take(&mut self.symbols);
+        let (mut local, mut nonlocal): (Vec<Symbol64>, _) =
+            symbols.into_iter().partition(|s| sym_bind(s) == STB_LOCAL);
+        local.append(&mut nonlocal);
+        self.symbols = local;
+
+        // shared objects only have one if they can be run like an executable
+        let entry = self
+            .symbols
+            .iter()
+            .find(|s| s.name == ENTRY_SYMBOL && s.shndx != 0);
+        let entry = match entry {
+            Some(entry) => entry.value,
+            None if dynamic => 0,
+            None => {
+                warnings.push(format!("cannot find entry symbol {}", ENTRY_SYMBOL));
+                0
+            }
+        };
+
+        self.unorganized_sections = out;
+        self.segments = segments;
+        self.header.r#type = if dynamic { ET_DYN } else { ET_EXEC };
+        self.header.entry = entry;
+
+        Ok(())
+    }
+
+    // give every exported symbol a version, from its name (`foo@@VERS`) or
+    // the version script, and localise the symbols the script says to. Every
+    // undefined symbol is bound to the version of its definition in the first
+    // shared object that has one.
+    fn assign_versions(
+        &mut self,
+        shared_objects: &[SharedObject64],
+        options: &LinkOptions,
+    ) -> Result<SymbolVersions, LinkError> {
+        let mut definitions = Vec::new();
+        let mut node_versions = HashMap::new();
+        if let Some(script) = &options.version_script {
+            // the base version is the name of the output itself
+            if !script.version_names().is_empty() {
+                let output_name = options.output.file_name().unwrap_or_default();
+                definitions.push(VersionDefinition {
+                    name: options
+                        .soname
+                        .clone()
+                        .unwrap_or_else(|| output_name.to_string_lossy().into_owned()),
+                    index: VER_NDX_GLOBAL,
+                    flags: VER_FLG_BASE,
+                    parents: Vec::new(),
+                });
+            }
+
+            for (i, node) in script.nodes.iter().enumerate() {
+                let index = match &node.name {
+                    Some(name) => {
+                        let index = definitions.len() as u16 + 1;
+                        definitions.push(VersionDefinition {
+                            name: name.clone(),
+                            index,
+                            flags: 0,
+                            parents: node.parents.clone(),
+                        });
+                        index
+                    }
+                    None => VER_NDX_GLOBAL,
+                };
+                node_versions.insert(i, index);
+            }
+        }
+
+        // needed versions are numbered after the defined ones
+        let mut next_index = std::cmp::max(definitions.len() as u16, VER_NDX_GLOBAL) + 1;
+        let mut requirements: Vec<VersionRequirement> = shared_objects
+            .iter()
+            .map(|so| VersionRequirement {
+                file: so.soname.clone(),
+                versions: Vec::new(),
+            })
+            .collect();
+
+        let mut versyms = HashMap::new();
+        for (i, symbol) in self.symbols.iter_mut().enumerate() {
+            if !symbol.is_visible_outside() {
+                continue;
+            }
+
+            let (name, version, is_default) = split_versioned_name(&symbol.name);
+            let versym = if symbol.shndx == 0 {
+                let definition = shared_objects
+                    .iter()
+                    .enumerate()
+                    .find_map(|(j, so)| so.lookup(name, version).map(|s| (j, s)));
+
+                match definition {
+                    Some((
+                        j,
+                        SharedSymbol64 {
+                            version: Some(version),
+                            ..
+                        },
+                    )) => {
+                        let versions = &mut requirements[j].versions;
+                        match versions.iter().find(|(v, _)| **v == **version) {
+                            Some((_, index)) => *index,
+                            None => {
+                                versions.push((version.to_string(), next_index));
+                                next_index += 1;
+                                next_index - 1
+                            }
+                        }
+                    }
+                    Some(_) => VER_NDX_GLOBAL,
+                    None => match version {
+                        Some(version) => {
+                            return Err(LinkError::UndefinedSymbol(format!(
+                                "symbol `{}` version `{}` is not defined by any shared object",
+                                name, version
+                            )))
+                        }
+                        None => VER_NDX_GLOBAL,
+                    },
+                }
+            } else if let Some(version) = version {
+                let definition = definitions
+                    .iter()
+                    .find(|d| d.flags & VER_FLG_BASE == 0 && d.name == version)
+                    .ok_or_else(|| {
+                        LinkError::InvalidSymbol(format!(
+                            "symbol `{}` has version `{}`, which no version script defines",
+                            name, version
+                        ))
+                    })?;
+
+                if is_default {
+                    definition.index
+                } else {
+                    definition.index | VERSYM_HIDDEN
+                }
+            } else {
+                let assignment = options
+                    .version_script
+                    .as_ref()
+                    .and_then(|script| script.assign(name));
+
+                match assignment {
+                    Some(VersionAssignment::Global(node)) => node_versions[&node],
+                    Some(VersionAssignment::Local) => {
+                        symbol.make_local();
+                        continue;
+                    }
+                    None => VER_NDX_GLOBAL,
+                }
+            };
+
+            versyms.insert(i, versym);
+        }
+
+        requirements.retain(|r| !r.versions.is_empty());
+
+        Ok(SymbolVersions {
+            versyms,
+            definitions,
+            requirements,
+        })
+    }
+
+    // loading the address of a symbol that can't be preempted doesn't need
+    // to go through the GOT, rewrite the instructions that do (when the
+    // compiler marked them as rewritable) to use the address directly
+    fn relax_got_loads(&mut self, options: &LinkOptions) {
+        let symbols = &self.symbols;
+        for section in self.unorganized_sections.iter_mut() {
+            let relas = match section.relocations.as_mut() {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            for i in 0..relas.len() {
+                // the call to __tls_get_addr (through the GOT with -fno-plt)
+                // goes along with the TLS sequence it's part of
+                let tls_call =
+                    i > 0 && matches!(relas[i - 1].get_type(), R_X86_64_TLSGD | R_X86_64_TLSLD);
+                let rela = &mut relas[i];
+                let r#type = rela.get_type();
+                if (r#type != R_X86_64_GOTPCRELX && r#type != R_X86_64_REX_GOTPCRELX) || tls_call {
+                    continue;
+                }
+
+                let symbol = &symbols[rela.get_sym()];
+                let defined = symbol.shndx != 0 && symbol.shndx < SHN_LORESERVE;
+                if !defined || is_preemptible(symbol, options) {
+                    continue;
+                }
+
+                if let Some(offset) =
+                    x86_64::relax_gotpcrelx(&mut section.data, rela.offset as usize)
+                {
+                    let sym_idx = rela.get_sym();
+                    rela.set_info(sym_idx, R_X86_64_PC32);
+                    rela.offset = offset as u64;
+                }
+            }
+        }
+    }
+
+    // AArch64 branches only reach +-128MiB, one to anything further away is
+    // redirected to a thunk added to the end of the branch's section, which
+    // gets there with an ADRP (so within +-4GiB). Thunks are local symbols,
+    // shared by the branches in a section to the same place. Returns whether
+    // any were added, in which case the sections need laying out again.
+    fn add_thunks(
+        &mut self,
+        sections: &mut [Section64],
+        thunks: &mut HashMap<(usize, usize, u64), usize>,
+    ) -> Result<bool, LinkError> {
+        let addrs: Vec<u64> = sections.iter().map(|s| s.addr).collect();
+        let mut added = false;
+        for (i, section) in sections.iter_mut().enumerate() {
+            let Section64 {
+                addr,
+                data,
+                relocations,
+                ..
+            } = section;
+            let relas = match relocations.as_mut() {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            let mut thunk_relas = Vec::new();
+            for rela in relas.iter_mut() {
+                if !aarch64::is_branch(rela.get_type()) {
+                    continue;
+                }
+
+                let sym_idx = rela.get_sym();
+                let symbol = &self.symbols[sym_idx];
+                // these branch to the next instruction instead
+                if is_undefined_weak(symbol) {
+                    continue;
+                }
+                let mut target = symbol.value.wrapping_add(rela.addend);
+                if symbol.shndx != 0 && symbol.shndx < SHN_LORESERVE {
+                    target = target.wrapping_add(addrs[symbol.shndx as usize]);
+                }
+                if aarch64::branch_in_range(*addr + rela.offset, target) {
+                    continue;
+                }
+                if thunks.values().any(|thunk| *thunk == sym_idx) {
+                    return Err(LinkError::Overflow(format!(
+                        "{}: too big for branches to reach its thunks",
+                        &section.name
+                    )));
+                }
+
+                let key = (i, sym_idx, rela.addend);
+                let thunk = match thunks.get(&key) {
+                    Some(thunk) => *thunk,
+                    None => {
+                        let offset = utils::align_up(data.len() as u64, 4);
+                        data.resize(offset as usize, 0);
+                        data.extend_from_slice(&aarch64::THUNK);
+                        for (thunk_offset, r#type) in aarch64::THUNK_RELOCATIONS.iter() {
+                            let mut thunk_rela = rela.clone();
+                            thunk_rela.offset = offset + *thunk_offset as u64;
+                            thunk_rela.set_info(sym_idx, *r#type);
+                            thunk_relas.push(thunk_rela);
+                        }
+
+                        self.symbols.push(Symbol64 {
+                            name: format!("__AArch64ADRPThunk_{}", &symbol.name),
+                            info: (STB_LOCAL << 4) | STT_FUNC,
+                            other: 0,
+                            shndx: i as u32,
+                            value: offset,
+                            size: aarch64::THUNK.len() as u64,
+                        });
+                        thunks.insert(key, self.symbols.len() - 1);
+                        added = true;
+                        self.symbols.len() - 1
+                    }
+                };
+
+                let r#type = rela.get_type();
+                rela.set_info(thunk, r#type);
+                rela.addend = 0;
+            }
+
+            relas.append(&mut thunk_relas);
+        }
+
+        Ok(added)
+    }
+
+    // decide which symbols need GOT and PLT entries, and how many dynamic
+    // relocations will be emitted
+    fn scan_relocations(
+        &self,
+        arch: Arch,
+        options: &LinkOptions,
+    ) -> Result<RelocationScan, LinkError> {
+        let dynamic = options.output_kind == OutputKind::Shared;
+        let mut scan = RelocationScan {
+            got_entries: Vec::new(),
+            got_map: HashMap::new(),
+            num_got_slots: 0,
+            plt_slots: Vec::new(),
+            plt_map: HashMap::new(),
+            num_dynamic_relas: 0,
+            num_relative_relas: 0,
+            textrel: false,
+            static_tls: false,
+        };
+
+        for section in self.unorganized_sections.iter() {
+            let relas = match &section.relocations {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            let alloc = section.flags & SHF_ALLOC != 0;
+            // set when a TLS sequence will be relaxed, the call to
+            // __tls_get_addr in it goes away
+            let mut skip_next = false;
+            for rela in relas {
+                if skip_next {
+                    skip_next = false;
+                    continue;
+                }
+
+                let sym_idx = rela.get_sym();
+                let r#type = rela.get_type();
+                let symbol = &self.symbols[sym_idx];
+                let preemptible = is_preemptible(symbol, options);
+                let via_plt = arch.needs_plt(r#type) && preemptible;
+                skip_next = arch == Arch::X86_64
+                    && !dynamic
+                    && matches!(r#type, R_X86_64_TLSGD | R_X86_64_TLSLD);
+
+                // executables relax TLS accesses to the local exec model
+                // instead, where possible
+                let got_entry = match r#type {
+                    _ if arch.needs_got(r#type) || via_plt => Some(GotEntry::Address(sym_idx)),
+                    _ if arch != Arch::X86_64 => None,
+                    R_X86_64_TLSGD if dynamic => Some(GotEntry::TlsGd(sym_idx)),
+                    R_X86_64_TLSLD if dynamic => Some(GotEntry::TlsLd),
+                    R_X86_64_GOTTPOFF
+                        if dynamic
+                            || !x86_64::can_relax_tls_ie(&section.data, rela.offset as usize) =>
+                    {
+                        Some(GotEntry::TlsIe(sym_idx))
+                    }
+                    _ => None,
+                };
+                if let Some(entry) = got_entry {
+                    scan.add_got_entry(entry);
+                }
+
+                if via_plt {
+                    let slots = &mut scan.plt_slots;
+                    scan.plt_map.entry(sym_idx).or_insert_with(|| {
+                        slots.push(sym_idx);
+                        slots.len() - 1
+                    });
+                }
+
+                if !alloc || !dynamic {
+                    continue;
+                }
+
+                let position_dependent = arch.is_absolute(r#type) && r#type != R_X86_64_64;
+                let local_exec = r#type == R_X86_64_TPOFF32;
+                if position_dependent || local_exec || (r#type == R_X86_64_PC32 && preemptible) {
+                    return Err(LinkError::Incompatible {
+                        file: rela.input.as_deref().unwrap_or_default().to_string(),
+                        reason: format!(
+                            "relocation {} against `{}` in {} can not be used when making a shared object; recompile with -fPIC",
+                            arch.name(r#type),
+                            &symbol.name,
+                            &section.name
+                        ),
+                    });
+                }
+
+                if r#type == R_X86_64_64 {
+                    scan.num_dynamic_relas += 1;
+                    scan.num_relative_relas += !preemptible as usize;
+                    scan.textrel |= section.flags & SHF_WRITE == 0;
+                }
+                // the output can't be loaded with dlopen
+                scan.static_tls |= r#type == R_X86_64_GOTTPOFF;
+            }
+        }
+
+        // the dynamic linker fills in GOT entries of shared objects
+        if dynamic {
+            for entry in scan.got_entries.iter() {
+                match entry {
+                    GotEntry::Address(sym_idx) => {
+                        let preemptible = is_preemptible(&self.symbols[*sym_idx], options);
+                        scan.num_dynamic_relas += 1;
+                        scan.num_relative_relas += !preemptible as usize;
+                    }
+                    GotEntry::TlsGd(sym_idx) => {
+                        let preemptible = is_preemptible(&self.symbols[*sym_idx], options);
+                        scan.num_dynamic_relas += 1 + preemptible as usize;
+                    }
+                    GotEntry::TlsLd | GotEntry::TlsIe(_) => scan.num_dynamic_relas += 1,
+                }
+            }
+        }
+
+        Ok(scan)
+    }
+}
+
+impl RelocationScan {
+    fn add_got_entry(&mut self, entry: GotEntry) {
+        if self.got_map.contains_key(&entry) {
+            return;
+        }
+
+        self.got_map.insert(entry, self.num_got_slots);
+        self.got_entries.push(entry);
+        self.num_got_slots += match entry {
+            GotEntry::TlsGd(_) | GotEntry::TlsLd => 2,
+            GotEntry::Address(_) | GotEntry::TlsIe(_) => 1,
+        };
+    }
+}
+
+impl SymbolVersions {
+    fn is_empty(&self) -> bool {
+        self.definitions.is_empty() && self.requirements.is_empty()
+    }
+}
+
+// can a definition from another module take the place of this symbol at
+// runtime? Protected symbols are visible outside, but always bind locally,
+// and nothing in a static executable can be replaced.
+fn is_preemptible(symbol: &Symbol64, options: &LinkOptions) -> bool {
+    options.output_kind == OutputKind::Shared
+        && sym_bind(symbol) != STB_LOCAL
+        && symbol.visibility() == SymbolVisibility::Default
+}
+
+fn is_undefined_weak(symbol: &Symbol64) -> bool {
+    symbol.shndx == 0 && sym_bind(symbol) == STB_WEAK
+}
+
+fn dynamic_rela(offset: u64, sym_idx: usize, r#type: usize, addend: u64) -> RelocationA64 {
+    let mut rela = RelocationA64 {
+        offset,
+        info: 0,
+        addend,
+        merged: false,
+        implicit_addend: false,
+        input: None,
+    };
+    rela.set_info(sym_idx, r#type);
+
+    rela
+}
+
+// where a section goes in the output, sections are laid out in rank order
+//   0 - notes
+//   1 - read-only data
+//   2 - code
+//   3 - thread-local data
+//   4 - zero-initialized thread-local data
+//   5 - data only written by relocations, which goes in RELRO
+//   6 - writable data
+//   7 - zero-initialized writable data
+//   8 - not loaded
+fn section_rank(section: &Section64) -> usize {
+    use SectionType64::*;
+
+    let nobits = section.r#type == Nobits;
+    let relro = matches!(section.r#type, InitArray | FiniArray | PreinitArray)
+        || section.name == ".data.rel.ro";
+    if section.flags & SHF_ALLOC == 0 {
+        8
+    } else if section.flags & SHF_EXECINSTR != 0 {
+        2
+    } else if section.flags & SHF_TLS != 0 {
+        3 + nobits as usize
+    } else if section.flags & SHF_WRITE != 0 && relro && !nobits {
+        5
+    } else if section.flags & SHF_WRITE != 0 {
+        6 + nobits as usize
+    } else if section.r#type == Note {
+        0
+    } else {
+        1
+    }
+}
+
+// the first and last sections of each run of allocated notes with the same
+// alignment, which get a PT_NOTE each
+fn note_runs(sections: &[Section64]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, section) in sections.iter().enumerate() {
+        if section.r#type != SectionType64::Note || section.flags & SHF_ALLOC == 0 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((first, last))
+                if *last + 1 == i && sections[*first].addralign == section.addralign =>
+            {
+                *last = i
+            }
+            _ => runs.push((i, i)),
+        }
+    }
+
+    runs
+}
+
+// the things symbols the linker defines (if they're used) mark
+enum Marker {
+    // __ehdr_start, the ELF header
+    Header,
+    // _DYNAMIC, .dynamic
+    Dynamic,
+    // __start_SECTION and __stop_SECTION, for sections named like C
+    // identifiers
+    Start(String),
+    Stop(String),
+    // etext, the end of code
+    EndOfCode,
+    // edata, the end of data in the file
+    EndOfData,
+    // __bss_start, the start of zero-initialized data
+    StartOfBss,
+    // end, the end of everything
+    End,
+}
+
+// where in its section a symbol the linker defines is
+enum Position {
+    Start,
+    End,
+    // at the ELF header, before the section
+    Header,
+}
+
+fn marker(name: &str) -> Option<Marker> {
+    let is_c_identifier = |s: &str| {
+        !s.is_empty()
+            && !s.starts_with(|c: char| c.is_ascii_digit())
+            && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+
+    let marker = match name {
+        "__ehdr_start" => Marker::Header,
+        "_DYNAMIC" => Marker::Dynamic,
+        "etext" | "_etext" | "__etext" => Marker::EndOfCode,
+        "edata" | "_edata" => Marker::EndOfData,
+        "__bss_start" => Marker::StartOfBss,
+        "end" | "_end" => Marker::End,
+        _ => {
+            if let Some(section) = name.strip_prefix("__start_") {
+                if !is_c_identifier(section) {
+                    return None;
+                }
+                Marker::Start(section.to_string())
+            } else if let Some(section) = name.strip_prefix("__stop_") {
+                if !is_c_identifier(section) {
+                    return None;
+                }
+                Marker::Stop(section.to_string())
+            } else {
+                return None;
+            }
+        }
+    };
+
+    Some(marker)
+}
+
+// the section (of the output, in order) `marker` is in, and where in it
+fn marked_section(
+    marker: &Marker,
+    sections: &[Section64],
+    dynamic: usize,
+) -> Option<(usize, Position)> {
+    let allocated: Vec<usize> = (1..sections.len())
+        .take_while(|i| sections[*i].flags & SHF_ALLOC != 0)
+        .collect();
+    let last = |f: &dyn Fn(&Section64) -> bool| {
+        allocated
+            .iter()
+            .rev()
+            .find(|i| f(&sections[**i]))
+            .map(|i| (*i, Position::End))
+    };
+    let nobits = |s: &Section64| s.r#type == SectionType64::Nobits;
+
+    match marker {
+        Marker::Header => allocated.first().map(|i| (*i, Position::Header)),
+        Marker::Dynamic if dynamic != 0 => Some((dynamic, Position::Start)),
+        Marker::Dynamic => None,
+        Marker::Start(name) => sections
+            .iter()
+            .position(|s| &s.name == name)
+            .map(|i| (i, Position::Start)),
+        Marker::Stop(name) => sections
+            .iter()
+            .position(|s| &s.name == name)
+            .map(|i| (i, Position::End)),
+        Marker::EndOfCode => last(&|s| s.flags & SHF_EXECINSTR != 0),
+        Marker::EndOfData => last(&|s| !nobits(s)),
+        Marker::StartOfBss => allocated
+            .iter()
+            .find(|i| nobits(&sections[**i]) && sections[**i].flags & SHF_TLS == 0)
+            .map(|i| (*i, Position::Start))
+            .or_else(|| marked_section(&Marker::EndOfData, sections, dynamic)),
+        Marker::End => last(&|_| true),
+    }
+}
+
+// without separate code, read-only data shares a segment with code
+fn segment_flags(section: &Section64, separate_code: bool) -> u32 {
+    let mut flags = PF_R;
+    if section.flags & SHF_WRITE != 0 {
+        flags |= PF_W;
+    }
+    if section.flags & SHF_EXECINSTR != 0 || (!separate_code && flags & PF_W == 0) {
+        flags |= PF_X;
+    }
+
+    flags
+}
+
+fn synthetic_section(
+    name: &str,
+    r#type: SectionType64,
+    flags: u64,
+    addralign: u64,
+    size: usize,
+) -> Section64 {
+    Section64 {
+        name: name.to_string(),
+        r#type,
+        flags,
+        addr: 0,
+        link: 0,
+        info: 0,
+        addralign,
+        entsize: 0,
+        data: vec![0; size],
+        relocations: None,
+    }
+}
+
+// how the allocated sections are laid out in memory
+struct Layout {
+    // where the output is loaded, file offsets are addresses relative to it
+    base: u64,
+    // what PT_LOAD segments are aligned to
+    page_size: u64,
+    separate_code: bool,
+    // the last section of RELRO and the page size the address after it is
+    // aligned to, so what follows isn't protected along with it
+    relro_end: Option<(usize, u64)>,
+}
+
+// assign addresses to every allocated section, starting a new page-aligned
+// PT_LOAD segment whenever permissions change
+fn layout(
+    sections: &mut [Section64],
+    class: u8,
+    params: &Layout,
+    num_other_phdrs: usize,
+) -> Vec<Segment64> {
+    let base = params.base;
+    let page_size = params.page_size;
+    let allocated = sections
+        .iter()
+        .skip(1)
+        .take_while(|s| s.flags & SHF_ALLOC != 0);
+    let mut num_loads = 0;
+    let mut last_flags = None;
+    for section in allocated {
+        let flags = segment_flags(section, params.separate_code);
+        if last_flags != Some(flags) {
+            num_loads += 1;
+            last_flags = Some(flags);
+        }
+    }
+
+    let num_phdrs = num_loads + num_other_phdrs;
+    let mut addr = base + (ehsize(class) + num_phdrs * phentsize(class)) as u64;
+
+    let mut loads: Vec<Segment64> = Vec::new();
+    // the page size to align to after RELRO, once there's something to put
+    // after it
+    let mut relro_padding = None;
+    for (i, section) in sections.iter_mut().enumerate().skip(1) {
+        if section.flags & SHF_ALLOC == 0 {
+            break;
+        }
+        if !section.data.is_empty() {
+            if let Some(common_page_size) = relro_padding.take() {
+                addr = utils::align_up(addr, common_page_size);
+            }
+        }
+
+        let flags = segment_flags(section, params.separate_code);
+        if loads.last().map(|l| l.flags) != Some(flags) {
+            // the first segment also maps the ELF header and program headers
+            let vaddr = if loads.is_empty() {
+                base
+            } else {
+                utils::align_up(addr, page_size)
+            };
+            loads.push(Segment64 {
+                r#type: SegmentType64::Load,
+                flags,
+                offset: vaddr - base,
+                vaddr,
+                filesz: 0,
+                memsz: 0,
+                align: page_size,
+            });
+            addr = std::cmp::max(addr, vaddr);
+        }
+
+        addr = utils::align_up(addr, section.addralign);
+        section.addr = addr;
+        // .tbss is only a template for each thread's TLS block, it takes up
+        // no space in the image
+        if section.flags & SHF_TLS == 0 || section.r#type != SectionType64::Nobits {
+            addr += section.data.len() as u64;
+        }
+
+        let load = loads.last_mut().unwrap();
+        load.memsz = addr - load.vaddr;
+        if section.r#type != SectionType64::Nobits {
+            load.filesz = load.memsz;
+        }
+
+        if let Some((relro_end, common_page_size)) = params.relro_end {
+            if i == relro_end {
+                relro_padding = Some(common_page_size);
+            }
+        }
+    }
+
+    loads
+}
+
+// the PT_TLS segment covering the (contiguous) TLS sections, if there are any
+fn tls_segment(sections: &[Section64], base: u64) -> Option<Segment64> {
+    let tls: Vec<&Section64> = sections.iter().filter(|s| s.flags & SHF_TLS != 0).collect();
+    let first = tls.first()?;
+
+    let end = |s: &&Section64| s.addr + s.data.len() as u64;
+    let file_end = tls
+        .iter()
+        .filter(|s| s.r#type != SectionType64::Nobits)
+        .map(end)
+        .max()
+        .unwrap_or(first.addr);
+    let mem_end = tls.iter().map(end).max().unwrap();
+
+    Some(Segment64 {
+        r#type: SegmentType64::Tls,
+        flags: PF_R,
+        offset: first.addr - base,
+        vaddr: first.addr,
+        filesz: file_end - first.addr,
+        memsz: mem_end - first.addr,
+        align: tls.iter().map(|s| s.addralign).max().unwrap_or(1),
+    })
+}
+
+fn dynamic_entries(
+    sections: &[Section64],
+    syn: &SyntheticSections,
+    shared_objects: &[SharedObject64],
+    options: &LinkOptions,
+    dynstr: &HashMap<String, usize>,
+    scan: &RelocationScan,
+) -> Vec<Dynamic64> {
+    use SectionType64::*;
+
+    let mut entries = Vec::new();
+    let mut push = |tag: u64, val: u64| entries.push(Dynamic64 { tag, val });
+
+    for shared_object in shared_objects {
+        push(
+            DT_NEEDED,
+            *dynstr.get(&shared_object.soname).unwrap() as u64,
+        );
+    }
+    if let Some(soname) = &options.soname {
+        push(DT_SONAME, *dynstr.get(soname).unwrap() as u64);
+    }
+    if let Some(rpath) = &options.rpath {
+        push(DT_RUNPATH, *dynstr.get(rpath).unwrap() as u64);
+    }
+
+    push(DT_HASH, sections[syn.hash].addr);
+    push(DT_GNU_HASH, sections[syn.gnu_hash].addr);
+    push(DT_STRTAB, sections[syn.dynstr].addr);
+    push(DT_SYMTAB, sections[syn.dynsym].addr);
+    push(DT_STRSZ, sections[syn.dynstr].data.len() as u64);
+    push(DT_SYMENT, 24);
+
+    if syn.versym != 0 {
+        push(DT_VERSYM, sections[syn.versym].addr);
+    }
+    if syn.verdef != 0 {
+        push(DT_VERDEF, sections[syn.verdef].addr);
+        push(DT_VERDEFNUM, sections[syn.verdef].info as u64);
+    }
+    if syn.verneed != 0 {
+        push(DT_VERNEED, sections[syn.verneed].addr);
+        push(DT_VERNEEDNUM, sections[syn.verneed].info as u64);
+    }
+
+    if syn.rela_dyn != 0 {
+        let rela_dyn = &sections[syn.rela_dyn];
+        push(DT_RELA, rela_dyn.addr);
+        push(DT_RELASZ, rela_dyn.data.len() as u64);
+        push(DT_RELAENT, 24);
+        push(DT_RELACOUNT, scan.num_relative_relas as u64);
+    }
+
+    let arrays = [
+        (PreinitArray, DT_PREINIT_ARRAY, DT_PREINIT_ARRAYSZ),
+        (InitArray, DT_INIT_ARRAY, DT_INIT_ARRAYSZ),
+        (FiniArray, DT_FINI_ARRAY, DT_FINI_ARRAYSZ),
+    ];
+    for (r#type, tag, size_tag) in arrays.iter() {
+        if let Some(section) = sections.iter().find(|s| s.r#type == *r#type) {
+            push(*tag, section.addr);
+            push(*size_tag, section.data.len() as u64);
+        }
+    }
+
+    let mut flags = 0;
+    if scan.textrel {
+        push(DT_TEXTREL, 0);
+        flags |= DF_TEXTREL;
+    }
+    if scan.static_tls {
+        flags |= DF_STATIC_TLS;
+    }
+    if options.bind_now {
+        flags |= DF_BIND_NOW;
+    }
+    if flags != 0 {
+        push(DT_FLAGS, flags);
+    }
+    if options.bind_now {
+        push(DT_FLAGS_1, DF_1_NOW);
+    }
+
+    push(DT_NULL, 0);
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+    fn static_link(input: &[u8]) -> ElfFile64 {
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            ..LinkOptions::default()
+        };
+        let (output, _) = crate::link(vec![ElfFile64::parse(input).unwrap()], &[], &options)
+            .expect("link failed");
+
+        output
+    }
+
+    fn section<'a>(file: &'a ElfFile64, name: &str) -> &'a Section64 {
+        file.unorganized_sections
+            .iter()
+            .find(|s| s.name == name)
+            .unwrap()
+    }
+
+    fn symbol(file: &ElfFile64, name: &str) -> u64 {
+        file.symbols.iter().find(|s| s.name == name).unwrap().value
+    }
+
+    fn check_aarch64_relocations<T: ByteOrder>(input: &[u8]) {
+        let output = static_link(input);
+        let start = symbol(&output, "_start");
+        let near = symbol(&output, "near");
+        let text = section(&output, ".text");
+        let data = section(&output, ".data");
+
+        // `bl missing` and `b missing` go to the next instruction, always
+        // little endian
+        let insn = |addr: u64| LittleEndian::read_u32(&text.data[(addr - text.addr) as usize..]);
+        assert_eq!(insn(start), 0x94000001);
+        assert_eq!(insn(start + 4), 0x14000001);
+        assert!(!output.symbols.iter().any(|s| s.name.contains("Thunk")));
+        let prel16 = T::read_i16(&text.data[(near - text.addr) as usize..]);
+        assert_eq!(prel16 as i64, start as i64 - near as i64);
+
+        // the rest are data, in the file's byte order
+        let d = &data.data;
+        let prel = |offset: u64| start as i64 - (data.addr + offset) as i64;
+        assert_eq!(T::read_u64(d), start + 1);
+        assert_eq!(T::read_u32(&d[8..]) as u64, start + 2);
+        assert_eq!(T::read_u16(&d[12..]), 0x1234);
+        assert_eq!(T::read_i64(&d[16..]), prel(16));
+        assert_eq!(T::read_i32(&d[24..]) as i64, prel(24));
+    }
+
+    #[test]
+    fn aarch64_relocations() {
+        check_aarch64_relocations::<LittleEndian>(include_bytes!(
+            "../test-files/04-aarch64-relocations/relocations.o"
+        ));
+        check_aarch64_relocations::<BigEndian>(include_bytes!(
+            "../test-files/04-aarch64-relocations/relocations-be.o"
+        ));
+    }
+
+    const LIB_O: &[u8] = include_bytes!("../test-files/06-shared-object/lib.o");
+
+    fn link_shared(input: &[u8]) -> Result<ElfFile64, LinkError> {
+        link_shared_all(&[input])
+    }
+
+    fn link_shared_all(inputs: &[&[u8]]) -> Result<ElfFile64, LinkError> {
+        let inputs = inputs
+            .iter()
+            .map(|i| ElfFile64::parse(i).unwrap())
+            .collect();
+        link_shared_with(inputs, shared_options())
+    }
+
+    fn shared_options() -> LinkOptions {
+        LinkOptions {
+            output_kind: OutputKind::Shared,
+            soname: Some("libtest.so".to_string()),
+            ..LinkOptions::default()
+        }
+    }
+
+    fn link_shared_with(
+        inputs: Vec<ElfFile64>,
+        options: LinkOptions,
+    ) -> Result<ElfFile64, LinkError> {
+        crate::link(inputs, &[], &options).map(|(output, _)| output)
+    }
+
+    // writes the output out and reads it back as a shared object
+    fn reparse(file: ElfFile64) -> SharedObject64 {
+        let mut bytes = Vec::new();
+        ElfFile64::write_out(file, &mut bytes).unwrap();
+        SharedObject64::parse(&bytes, "", &Default::default()).unwrap()
+    }
+
+    fn exported(so: &SharedObject64) -> Vec<&str> {
+        let mut names: Vec<_> = so.symbols.iter().map(|s| s.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    // (tag, value) pairs of .dynamic, up to DT_NULL
+    fn dynamic_entries(file: &ElfFile64) -> Vec<(u64, u64)> {
+        section(file, ".dynamic")
+            .data
+            .chunks(16)
+            .map(|e| (LittleEndian::read_u64(e), LittleEndian::read_u64(&e[8..])))
+            .take_while(|&(tag, _)| tag != DT_NULL)
+            .collect()
+    }
+
+    #[test]
+    fn shared_object_output() {
+        let output = link_shared(LIB_O).unwrap();
+        let dynamic = dynamic_entries(&output);
+        let dynamic_value = |tag| dynamic.iter().find(|e| e.0 == tag).map(|e| e.1);
+        let dynsym = section(&output, ".dynsym");
+        let hash = section(&output, ".hash");
+        let got = section(&output, ".got");
+        let plt = section(&output, ".plt");
+
+        // .dynamic points at the dynamic sections
+        assert_eq!(dynamic_value(DT_HASH), Some(hash.addr));
+        assert_eq!(dynamic_value(DT_SYMTAB), Some(dynsym.addr));
+        assert_eq!(
+            dynamic_value(DT_STRTAB),
+            Some(section(&output, ".dynstr").addr)
+        );
+        assert!(dynamic_value(DT_SONAME).is_some());
+
+        // .hash has a chain for every dynamic symbol
+        let symbols = dynsym.data.len() / 24;
+        assert_eq!(symbols, 5);
+        assert_eq!(LittleEndian::read_u32(&hash.data[4..]) as usize, symbols);
+
+        // both entries of `pointers` point into the output, so they're
+        // relative, and come first as DT_RELACOUNT says; the one to `counter`
+        // is symbolic, and so are the GOT entries of `counter` and `external`
+        let rela_dyn = &section(&output, ".rela.dyn").data;
+        let types: Vec<_> = rela_dyn
+            .chunks(24)
+            .map(|r| LittleEndian::read_u64(&r[8..]) as u32 as usize)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                R_X86_64_RELATIVE,
+                R_X86_64_RELATIVE,
+                R_X86_64_64,
+                R_X86_64_GLOB_DAT,
+                R_X86_64_GLOB_DAT
+            ]
+        );
+        assert_eq!(dynamic_value(DT_RELACOUNT), Some(2));
+        assert_eq!(dynamic_value(DT_RELASZ), Some(rela_dyn.len() as u64));
+        let pointers = symbol(&output, "pointers");
+        assert_eq!(LittleEndian::read_u64(rela_dyn), pointers);
+        assert_eq!(
+            LittleEndian::read_u64(&rela_dyn[16..]),
+            symbol(&output, "local_value")
+        );
+
+        // one GOT entry each for `external` and `counter`, and a PLT entry
+        // that jumps through the first
+        assert_eq!(got.data.len(), 16);
+        assert_eq!(plt.data.len(), PLT_ENTRY_SIZE);
+        assert_eq!(plt.data[..2], [0xff, 0x25]);
+        let target = plt.addr as i64 + 6 + LittleEndian::read_i32(&plt.data[2..]) as i64;
+        assert_eq!(target as u64, got.addr);
+
+        // written out, the shared object exports what isn't hidden
+        let so = reparse(output);
+        assert_eq!(so.soname, "libtest.so");
+        assert_eq!(exported(&so), ["exported", "pointers"]);
+    }
+
+    const DEF_O: &[u8] = include_bytes!("../test-files/07-visibility/def.o");
+    const REF_O: &[u8] = include_bytes!("../test-files/07-visibility/ref.o");
+    const UNDEFINED_O: &[u8] = include_bytes!("../test-files/07-visibility/undefined.o");
+
+    #[test]
+    fn hidden_symbols_are_not_exported() {
+        // `value` is hidden by ref.o's reference, `get` stays protected
+        let output = link_shared_all(&[DEF_O, REF_O]).unwrap();
+        assert_eq!(exported(&reparse(output)), ["get", "use"]);
+
+        // without a definition a hidden symbol can't come from elsewhere
+        let result = link_shared_all(&[DEF_O, UNDEFINED_O]);
+        assert!(matches!(result, Err(LinkError::UndefinedSymbol(_))));
+    }
+
+    #[test]
+    fn undefined_and_unsupported_outputs_are_errors() {
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            ..LinkOptions::default()
+        };
+        let result = crate::link(vec![ElfFile64::parse(LIB_O).unwrap()], &[], &options);
+        assert!(matches!(result, Err(LinkError::UndefinedSymbol(_))));
+
+        let aarch64 = include_bytes!("../test-files/04-aarch64-relocations/relocations.o");
+        assert!(matches!(
+            link_shared(aarch64),
+            Err(LinkError::Unsupported(_))
+        ));
+    }
+
+    fn rename(file: &mut ElfFile64, from: &str, to: &str) {
+        let symbol = file.symbols.iter_mut().find(|s| s.name == from).unwrap();
+        symbol.name = to.to_string();
+    }
+
+    fn dynamic_string(file: &ElfFile64, offset: u32) -> String {
+        let string = &section(file, ".dynstr").data[offset as usize..];
+        let end = string.iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(string[..end].to_vec()).unwrap()
+    }
+
+    // the names of .dynsym's symbols, in order
+    fn dynsym_names(file: &ElfFile64) -> Vec<String> {
+        section(file, ".dynsym")
+            .data
+            .chunks(24)
+            .map(|sym| dynamic_string(file, LittleEndian::read_u32(sym)))
+            .collect()
+    }
+
+    #[test]
+    fn symbol_versions() {
+        let script = "VERS_1 { global: get; local: *; }; VERS_2 { global: use; } VERS_1;";
+        let options = LinkOptions {
+            version_script: Some(crate::version_script::VersionScript::parse(script).unwrap()),
+            ..shared_options()
+        };
+        let inputs = vec![
+            ElfFile64::parse(DEF_O).unwrap(),
+            ElfFile64::parse(REF_O).unwrap(),
+        ];
+        let output = link_shared_with(inputs, options).unwrap();
+
+        // .gnu.version has the index of each .dynsym symbol's version
+        let versym = &section(&output, ".gnu.version").data;
+        let versions: Vec<_> = dynsym_names(&output)
+            .into_iter()
+            .zip(versym.chunks(2).map(LittleEndian::read_u16))
+            .collect();
+        assert_eq!(
+            versions,
+            [
+                ("".to_string(), VER_NDX_LOCAL),
+                ("get".to_string(), 2),
+                ("use".to_string(), 3)
+            ]
+        );
+
+        // .gnu.version_d has the output itself and both nodes, VERS_2 with
+        // VERS_1 as its parent
+        let verdef = &section(&output, ".gnu.version_d").data;
+        let mut definitions = Vec::new();
+        let mut offset = 0;
+        loop {
+            let def = &verdef[offset..];
+            let index = LittleEndian::read_u16(&def[4..]);
+            let count = LittleEndian::read_u16(&def[6..]);
+            let mut aux = offset + LittleEndian::read_u32(&def[12..]) as usize;
+            let mut names = Vec::new();
+            for _ in 0..count {
+                names.push(dynamic_string(
+                    &output,
+                    LittleEndian::read_u32(&verdef[aux..]),
+                ));
+                aux += LittleEndian::read_u32(&verdef[aux + 4..]) as usize;
+            }
+            definitions.push((index, names));
+            match LittleEndian::read_u32(&def[16..]) {
+                0 => break,
+                next => offset += next as usize,
+            }
+        }
+        assert_eq!(
+            definitions,
+            [
+                (1, vec!["libtest.so".to_string()]),
+                (2, vec!["VERS_1".to_string()]),
+                (3, vec!["VERS_2".to_string(), "VERS_1".to_string()])
+            ]
+        );
+        let dynamic = dynamic_entries(&output);
+        assert!(dynamic.contains(&(DT_VERDEFNUM, 3)));
+        assert!(dynamic.contains(&(DT_VERSYM, section(&output, ".gnu.version").addr)));
+
+        // and they're read back as the versions of the symbols
+        let so = reparse(output);
+        let version = |name: &str| {
+            let symbol = so.symbols.iter().find(|s| s.name == name).unwrap();
+            symbol.version.clone()
+        };
+        assert_eq!(version("get").as_deref(), Some("VERS_1"));
+        assert_eq!(version("use").as_deref(), Some("VERS_2"));
+    }
+
+    #[test]
+    fn unknown_versions_are_errors() {
+        // defined with a version no script gives
+        let mut def = ElfFile64::parse(DEF_O).unwrap();
+        rename(&mut def, "get", "get@@VERS_9");
+        let result = link_shared_with(vec![def], shared_options());
+        assert!(matches!(result, Err(LinkError::InvalidSymbol(m)) if m.contains("VERS_9")));
+
+        // needed with a version no shared object has
+        let mut lib = ElfFile64::parse(LIB_O).unwrap();
+        rename(&mut lib, "external", "external@VERS_9");
+        let result = link_shared_with(vec![lib], shared_options());
+        assert!(matches!(result, Err(LinkError::UndefinedSymbol(m)) if m.contains("VERS_9")));
+    }
+
+    #[test]
+    fn position_dependent_code_is_an_error() {
+        let input = include_bytes!("../test-files/02-combine-objects/fibonacci.o");
+        let mut fibonacci = ElfFile64::parse(input).unwrap();
+        fibonacci.name = "fibonacci.o".to_string();
+        let result = link_shared_with(vec![fibonacci], shared_options());
+        match result {
+            Err(LinkError::Incompatible { file, reason }) => {
+                assert_eq!(file, "fibonacci.o");
+                assert!(reason.contains("recompile with -fPIC"));
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+}
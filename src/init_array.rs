@@ -0,0 +1,292 @@
+use crate::elf::section::{word_align, Section64, SectionType64, SHF_ALLOC, SHF_WRITE};
+use crate::elf::symbol::{sym_type, SymbolVisibility, STT_SECTION};
+use crate::elf::ElfFile64;
+use crate::link::LinkError;
+
+// an output section of pointers to functions run when the output is loaded
+// or unloaded, and the input sections that go in it
+struct Array {
+    name: &'static str,
+    r#type: SectionType64,
+    // GCC's name for the same thing before there were SHT_INIT_ARRAY
+    // sections, whose entries are run last to first
+    legacy: Option<&'static str>,
+    // whether `.name.N` sections are sorted in by priority
+    prioritized: bool,
+    start_symbol: &'static str,
+    end_symbol: &'static str,
+}
+
+const ARRAYS: [Array; 3] = [
+    Array {
+        name: ".preinit_array",
+        r#type: SectionType64::PreinitArray,
+        legacy: None,
+        prioritized: false,
+        start_symbol: "__preinit_array_start",
+        end_symbol: "__preinit_array_end",
+    },
+    Array {
+        name: ".init_array",
+        r#type: SectionType64::InitArray,
+        legacy: Some(".ctors"),
+        prioritized: true,
+        start_symbol: "__init_array_start",
+        end_symbol: "__init_array_end",
+    },
+    Array {
+        name: ".fini_array",
+        r#type: SectionType64::FiniArray,
+        legacy: Some(".dtors"),
+        prioritized: true,
+        start_symbol: "__fini_array_start",
+        end_symbol: "__fini_array_end",
+    },
+];
+
+// the priority of `.init_array.N` and `.ctors.N` sections, the highest
+// priority is 0 and runs first. Unnumbered sections run after all of them.
+const UNNUMBERED: u32 = 65536;
+
+// `.ctors.N` has priority 65535 - N, since they're run backwards
+const MAX_LEGACY_PRIORITY: u32 = 65535;
+
+// what's after `prefix` in `name`: `Some(None)` for `prefix` itself,
+// `Some(Some(suffix))` for `prefix.suffix`
+fn suffix<'a>(name: &'a str, prefix: &str) -> Option<Option<&'a str>> {
+    let rest = name.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        Some(None)
+    } else {
+        rest.strip_prefix('.').map(Some)
+    }
+}
+
+// turn each `.ctors` and `.dtors` section of an input of a final link into
+// the `.init_array` or `.fini_array` section that runs the same functions in
+// the same order, and give every section of an array its proper type. This
+// has to happen before inputs are merged, as each one is reversed on its own,
+// and goes in with the input's other sections of the array, in the order the
+// input has them.
+pub(crate) fn convert_legacy(file: &mut ElfFile64, fill: Option<&[u8]>) -> Result<(), LinkError> {
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for array in ARRAYS.iter() {
+        for i in 0..file.unorganized_sections.len() {
+            let name = &file.unorganized_sections[i].name;
+            let name = match (suffix(name, array.name), array.legacy) {
+                (Some(_), _) => name.clone(),
+                (None, Some(legacy)) => match suffix(name, legacy) {
+                    Some(None) => array.name.to_string(),
+                    Some(Some(n)) => match n.parse::<u32>() {
+                        Ok(n) if n <= MAX_LEGACY_PRIORITY => {
+                            format!("{}.{:05}", array.name, MAX_LEGACY_PRIORITY - n)
+                        }
+                        _ => format!("{}.{}", array.name, n),
+                    },
+                    None => continue,
+                },
+                (None, None) => continue,
+            };
+            if name != file.unorganized_sections[i].name {
+                reverse(file, i)?;
+            }
+            file.unorganized_sections[i].r#type = array.r#type;
+
+            match groups.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, pieces)) => pieces.push(i),
+                None => groups.push((name, vec![i])),
+            }
+        }
+    }
+
+    file.combine_sections(&groups, fill)
+}
+
+// combine the sections of each array in a merged file into one, sorted by
+// priority, and define the symbols marking its bounds if they're used
+pub(crate) fn combine(file: &mut ElfFile64, fill: Option<&[u8]>) -> Result<(), LinkError> {
+    let word = word_align(file.class());
+    for array in ARRAYS.iter() {
+        let mut pieces: Vec<(u32, usize)> = file
+            .unorganized_sections
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| {
+                let priority = match suffix(&s.name, array.name)? {
+                    None => UNNUMBERED,
+                    Some(_) if !array.prioritized => return None,
+                    Some(n) => n.parse::<u32>().unwrap_or(UNNUMBERED).min(UNNUMBERED),
+                };
+                Some((priority, i))
+            })
+            .collect();
+        // sections of the same priority stay in the order they were merged
+        pieces.sort_by_key(|(priority, _)| *priority);
+
+        let referenced = file
+            .symbols
+            .iter()
+            .any(|s| s.shndx == 0 && (s.name == array.start_symbol || s.name == array.end_symbol));
+        if pieces.is_empty() && referenced {
+            // the symbols still need somewhere to be
+            file.unorganized_sections.push(Section64 {
+                name: array.name.to_string(),
+                r#type: array.r#type,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: 0,
+                link: 0,
+                info: 0,
+                addralign: word,
+                entsize: 0,
+                data: Vec::new(),
+                relocations: None,
+            });
+            pieces.push((UNNUMBERED, file.unorganized_sections.len() - 1));
+        } else if pieces.is_empty() {
+            continue;
+        }
+
+        let pieces = pieces.into_iter().map(|(_, i)| i).collect();
+        file.combine_sections(&[(array.name.to_string(), pieces)], fill)?;
+
+        let shndx = file
+            .unorganized_sections
+            .iter()
+            .position(|s| s.name == array.name)
+            .unwrap();
+        let section = &mut file.unorganized_sections[shndx];
+        section.entsize = word;
+        let len = section.data.len() as u64;
+
+        for symbol in file.symbols.iter_mut() {
+            if symbol.shndx != 0 {
+                continue;
+            }
+            let value = if symbol.name == array.start_symbol {
+                0
+            } else if symbol.name == array.end_symbol {
+                len
+            } else {
+                continue;
+            };
+            symbol.shndx = shndx as u32;
+            symbol.value = value;
+            symbol.set_visibility(SymbolVisibility::Hidden);
+        }
+    }
+
+    Ok(())
+}
+
+// reverse the order of the pointers in section `shndx`, along with their
+// relocations and any symbols labelling them
+fn reverse(file: &mut ElfFile64, shndx: usize) -> Result<(), LinkError> {
+    let word = word_align(file.class()) as usize;
+    let section = &mut file.unorganized_sections[shndx];
+    let len = section.data.len();
+    if !len.is_multiple_of(word) {
+        return Err(LinkError::Incompatible {
+            file: file.name.clone(),
+            reason: format!("{} isn't made of {}-byte pointers", &section.name, word),
+        });
+    }
+
+    let moved = |offset: u64| {
+        let offset = offset as usize;
+        let within = offset % word;
+        (len - word - (offset - within) + within) as u64
+    };
+
+    section.data = section.data.chunks(word).rev().flatten().copied().collect();
+    if let Some(relas) = section.relocations.as_mut() {
+        for rela in relas.iter_mut() {
+            rela.offset = moved(rela.offset);
+        }
+        relas.sort_by_key(|rela| rela.offset);
+    }
+    for symbol in file.symbols.iter_mut() {
+        if symbol.shndx as usize == shndx
+            && sym_type(symbol) != STT_SECTION
+            && symbol.value < len as u64
+        {
+            symbol.value = moved(symbol.value);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::{LinkOptions, OutputKind};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    const INIT_O: &[u8] = include_bytes!("../test-files/08-init-array/init.o");
+
+    fn static_link(input: ElfFile64) -> Result<ElfFile64, LinkError> {
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            ..LinkOptions::default()
+        };
+        crate::link(vec![input], &[], &options).map(|(output, _)| output)
+    }
+
+    #[test]
+    fn arrays_are_sorted_by_priority() {
+        let output = static_link(ElfFile64::parse(INIT_O).unwrap()).unwrap();
+        let section = |name: &str| {
+            let sections = output.unorganized_sections.iter();
+            sections.filter(|s| s.name == name).collect::<Vec<_>>()
+        };
+        let symbol = |name: &str| {
+            output
+                .symbols
+                .iter()
+                .find(|s| s.name == name)
+                .unwrap()
+                .value
+        };
+
+        // .init_array.N by N, then .ctors.65000 as priority 535 with its
+        // pointers reversed, then the unnumbered .init_array and .ctors
+        let init_array = section(".init_array");
+        assert_eq!(init_array.len(), 1);
+        assert!(section(".ctors").is_empty());
+        let init_array = init_array[0];
+        assert_eq!(init_array.r#type, SectionType64::InitArray);
+        let order: Vec<_> = init_array
+            .data
+            .chunks(8)
+            .map(LittleEndian::read_u64)
+            .collect();
+        let expected: Vec<_> = ["c", "b", "e", "d", "a", "g", "f"].map(symbol).to_vec();
+        assert_eq!(order, expected);
+
+        // the bounds are the start and end of the combined section
+        let data = &section(".data")[0].data;
+        assert_eq!(LittleEndian::read_u64(data), init_array.addr);
+        assert_eq!(
+            LittleEndian::read_u64(&data[8..]),
+            init_array.addr + init_array.data.len() as u64
+        );
+    }
+
+    #[test]
+    fn legacy_arrays_must_be_pointers() {
+        let mut input = ElfFile64::parse(INIT_O).unwrap();
+        input.name = "init.o".to_string();
+        let ctors = input
+            .unorganized_sections
+            .iter_mut()
+            .find(|s| s.name == ".ctors");
+        ctors.unwrap().data.push(0);
+        match static_link(input) {
+            Err(LinkError::Incompatible { file, reason }) => {
+                assert_eq!(file, "init.o");
+                assert_eq!(reason, ".ctors isn't made of 8-byte pointers");
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+}
@@ -1,5 +1,14 @@
+mod arch;
+mod eh_frame;
 pub mod elf;
+mod exec_stack;
+mod final_link;
+mod init_array;
 pub mod link;
+mod mergeable;
+mod output_sections;
+mod relax;
 mod utils;
+pub mod version_script;
 
 pub use link::link;
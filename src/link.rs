@@ -1,12 +1,193 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::elf::section::Section64;
-use crate::elf::symbol::{sym_bind, Symbol64};
-use crate::elf::ElfFile64;
+use crate::arch;
+use crate::elf::relocation::relocation_section_type;
+use crate::elf::section::{
+    Section64, SectionType64, SHF_ALLOC, SHF_EXECINSTR, SHF_MERGE, SHF_STRINGS, SHF_TLS,
+    SHN_LORESERVE,
+};
+use crate::elf::shared_object::SharedObject64;
+use crate::elf::symbol::{sym_bind, sym_type, Symbol64, STB_LOCAL, STT_SECTION};
+use crate::elf::{ElfFile64, ElfFile64HeaderRaw, ELFCLASS32, ELFCLASS64, ELFOSABI_NONE};
+use crate::exec_stack;
+use crate::init_array;
+use crate::mergeable;
+use crate::output_sections;
 use crate::utils;
+use crate::version_script::VersionScript;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputKind {
+    Relocatable,
+    Shared,
+    StaticExecutable,
+}
+
+#[derive(Debug)]
+pub struct LinkOptions {
+    pub output_kind: OutputKind,
+    pub soname: Option<String>,
+    pub rpath: Option<String>,
+    pub version_script: Option<VersionScript>,
+    // rewrite instructions to avoid indirection where possible
+    pub relax: bool,
+    // repeated to fill the padding between inputs merged into a section, by
+    // default code is padded with nops and everything else with zeroes
+    pub fill: Option<Vec<u8>>,
+    // make what's only written by relocations read-only once they're done
+    // (-z relro)
+    pub relro: bool,
+    // have the dynamic linker bind every symbol at load time (-z now)
+    pub bind_now: bool,
+    // keep code in pages of its own, away from the headers and read-only
+    // data (-z separate-code)
+    pub separate_code: bool,
+    // whether the stack should be executable (-z execstack/noexecstack),
+    // by default it is if any input needs it
+    pub exec_stack: Option<bool>,
+    // what segments are aligned to, by default the architecture's biggest
+    // page size (-z max-page-size)
+    pub max_page_size: Option<u64>,
+    // the page size RELRO is padded to (-z common-page-size)
+    pub common_page_size: Option<u64>,
+    // build .eh_frame_hdr, the index of .eh_frame unwinders look things up
+    // in (--eh-frame-hdr)
+    pub eh_frame_hdr: bool,
+    // the program that loads a shared object run as an executable, put in
+    // its PT_INTERP (--dynamic-linker)
+    pub dynamic_linker: Option<String>,
+    // the file being written, its name is the base version of a shared
+    // object without a soname
+    pub output: PathBuf,
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        LinkOptions {
+            output_kind: OutputKind::Relocatable,
+            soname: None,
+            rpath: None,
+            version_script: None,
+            relax: true,
+            fill: None,
+            relro: true,
+            bind_now: false,
+            separate_code: true,
+            exec_stack: None,
+            max_page_size: None,
+            common_page_size: None,
+            eh_frame_hdr: false,
+            dynamic_linker: None,
+            output: PathBuf::from("output.o"),
+        }
+    }
+}
+
+// why a link couldn't be done, each with a message saying where
+#[derive(Debug)]
+pub enum LinkError {
+    // a relocation type the linker doesn't know how to apply
+    UnsupportedRelocation(String),
+    // a relocation whose instructions or partner relocations aren't what its
+    // type needs
+    InvalidRelocation(String),
+    // a value that doesn't fit where it goes, like a branch out of range
+    Overflow(String),
+    // an input that can't be linked with the others, like one for another
+    // machine
+    Incompatible { file: String, reason: String },
+    // an output the linker can't make, like a shared object for AArch64
+    Unsupported(String),
+    // a reference to a symbol nothing defines
+    UndefinedSymbol(String),
+    // a symbol that isn't anywhere it can be
+    InvalidSymbol(String),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::UnsupportedRelocation(message)
+            | LinkError::InvalidRelocation(message)
+            | LinkError::Overflow(message)
+            | LinkError::Unsupported(message)
+            | LinkError::UndefinedSymbol(message)
+            | LinkError::InvalidSymbol(message) => write!(f, "{}", message),
+            LinkError::Incompatible { file, reason } => write!(f, "{}: {}", file, reason),
+        }
+    }
+}
 
 impl ElfFile64 {
-    fn link(&mut self, other: &ElfFile64) {
+    // check that the input `name` can be linked with everything linked so
+    // far, and fold its `e_flags` and OS/ABI into the output's
+    fn merge_header(&mut self, name: &str, other: &ElfFile64HeaderRaw) -> Result<(), LinkError> {
+        let ours = &self.header.identifier;
+        let theirs = &other.identifier;
+        if ours.class != theirs.class {
+            return Err(incompatible(
+                name,
+                &self.name,
+                "class",
+                class_name(theirs.class),
+                class_name(ours.class),
+            ));
+        }
+        if ours.endianness != theirs.endianness {
+            return Err(incompatible(
+                name,
+                &self.name,
+                "byte order",
+                endianness_name(theirs.endianness),
+                endianness_name(ours.endianness),
+            ));
+        }
+        if self.header.machine != other.machine {
+            return Err(incompatible(
+                name,
+                &self.name,
+                "machine",
+                &arch::machine_name(other.machine),
+                &arch::machine_name(self.header.machine),
+            ));
+        }
+        // System V objects work with any OS, the output is for the OS of
+        // whichever input says
+        if ours.os_abi != theirs.os_abi && theirs.os_abi != ELFOSABI_NONE {
+            if ours.os_abi != ELFOSABI_NONE {
+                return Err(incompatible(
+                    name,
+                    &self.name,
+                    "OS/ABI",
+                    &theirs.os_abi.to_string(),
+                    &ours.os_abi.to_string(),
+                ));
+            }
+            self.header.identifier.os_abi = theirs.os_abi;
+            self.header.identifier.abi_version = theirs.abi_version;
+        }
+
+        self.header.flags = arch::merge_flags(self.header.machine, self.header.flags, other.flags)
+            .map_err(|reason| LinkError::Incompatible {
+                file: name.to_string(),
+                reason,
+            })?;
+
+        Ok(())
+    }
+
+    // `origins` is the input each output section was first read from
+    fn link(
+        &mut self,
+        other: &ElfFile64,
+        origins: &mut HashMap<String, String>,
+        fill: Option<&[u8]>,
+    ) -> Result<(), LinkError> {
+        self.merge_header(&other.name, &other.header)?;
+
         // clean up so many usages of hashmap, must be a cleaner way
         let mut section_name_map = utils::build_section_name_map(self);
         let mut symbol_map = utils::build_symbol_name_map(self);
@@ -17,43 +198,112 @@ impl ElfFile64 {
         for (i, section) in other.unorganized_sections.iter().enumerate() {
             if section_name_map.contains_key(&section.name) {
                 let existing_idx = section_name_map.get(&section.name).unwrap();
+                let padding = padding(self.header.machine, fill);
                 let existing = &mut self.unorganized_sections[*existing_idx];
-                value_offsets.insert(i, existing.merge(&section));
+                let offset = existing.merge(section, padding).map_err(|(theirs, ours)| {
+                    LinkError::Incompatible {
+                        file: other.name.clone(),
+                        reason: format!(
+                            "{} is {}, but it is {} in {}, they can't be merged",
+                            &section.name, theirs, ours, &origins[&section.name]
+                        ),
+                    }
+                })?;
+                value_offsets.insert(i, offset);
                 section_merge_map.insert(i, *existing_idx);
             } else {
+                origins.insert(section.name.clone(), other.name.clone());
                 section_name_map.insert(section.name.clone(), self.unorganized_sections.len());
                 section_merge_map.insert(i, self.unorganized_sections.len());
                 value_offsets.insert(i, 0);
-                self.unorganized_sections.push(section.clone());
+                let mut to_push = section.clone();
+                // relocations still reference symbols by their index in `other`
+                if let Some(relas) = to_push.relocations.as_mut() {
+                    for rela in relas {
+                        rela.merged = true;
+                    }
+                }
+                self.unorganized_sections.push(to_push);
             }
         }
 
+        // like ld, each output section gets one section symbol, and
+        // relocations against another input's section symbol use it instead,
+        // with their addend moved by how far into the section that input went.
+        // Moving an addend that's stored in the place means reading it first,
+        // section symbols with one that can't be read are kept as they are.
+        let unreadable = unreadable_addends(other);
+        let mut section_symbols = HashMap::new();
+        for (i, symbol) in self.symbols.iter().enumerate() {
+            if sym_type(symbol) == STT_SECTION {
+                section_symbols.entry(symbol.shndx).or_insert(i);
+            }
+        }
+        let mut addend_offsets = HashMap::new();
+
         for (i, symbol) in other.symbols.iter().enumerate() {
-            if symbol.name != "" && symbol_map.contains_key(&symbol.name) {
+            // every input starts with a null symbol, the output only needs one
+            if i == 0 {
+                symbol_merge_map.insert(i, 0);
+                continue;
+            }
+            if sym_type(symbol) == STT_SECTION
+                && symbol.shndx < SHN_LORESERVE
+                && !unreadable.contains(&i)
+            {
+                let shndx = *section_merge_map.get(&(symbol.shndx as usize)).unwrap() as u32;
+                if let Some(&existing_idx) = section_symbols.get(&shndx) {
+                    let value = symbol.value
+                        + *value_offsets
+                            .get(&(symbol.shndx as usize))
+                            .expect("could not get value offset") as u64;
+                    let existing = &self.symbols[existing_idx];
+                    addend_offsets.insert(i, value.wrapping_sub(existing.value));
+                    symbol_merge_map.insert(i, existing_idx);
+                    continue;
+                }
+                section_symbols.insert(shndx, self.symbols.len());
+            }
+
+            // local symbols (e.g. AArch64's `$x` mapping symbols) can share a
+            // name without being the same symbol
+            let local = sym_bind(symbol) == STB_LOCAL;
+            if !local && !symbol.name.is_empty() && symbol_map.contains_key(&symbol.name) {
                 let existing_idx = symbol_map.get(&symbol.name).unwrap();
                 let existing = &mut self.symbols[*existing_idx];
+                // the most restrictive visibility of any reference or
+                // definition wins
+                let visibility = existing.visibility().most_restrictive(symbol.visibility());
                 if existing.shndx != 0 && symbol.shndx != 0 {
-                    panic!("symbol defined multiple times");
+                    return Err(LinkError::Incompatible {
+                        file: other.name.clone(),
+                        reason: format!("`{}` is already defined by another input", &symbol.name),
+                    });
                 } else if existing.shndx == 0 && symbol.shndx != 0 {
                     *existing = symbol.clone();
-                    existing.shndx =
-                        *section_merge_map.get(&(symbol.shndx as usize)).unwrap() as u16;
-                    existing.value += *value_offsets
-                        .get(&(symbol.shndx as usize))
-                        .expect("could not get value offset")
-                        as u64;
+                    if symbol.shndx < SHN_LORESERVE {
+                        existing.shndx =
+                            *section_merge_map.get(&(symbol.shndx as usize)).unwrap() as u32;
+                        existing.value += *value_offsets
+                            .get(&(symbol.shndx as usize))
+                            .expect("could not get value offset")
+                            as u64;
+                    }
                     symbol_merge_map.insert(i, *existing_idx);
                 } else {
                     symbol_merge_map.insert(i, *existing_idx);
                 }
+                existing.set_visibility(visibility);
             } else {
-                symbol_map.insert(symbol.name.clone(), self.symbols.len());
+                if !local {
+                    symbol_map.insert(symbol.name.clone(), self.symbols.len());
+                }
                 symbol_merge_map.insert(i, self.symbols.len());
                 let mut to_push = symbol.clone();
-                if to_push.shndx != 0xfff1 {
-                    // SHN_ABS
+                // SHN_ABS etc. aren't sections
+                if to_push.shndx < SHN_LORESERVE {
                     to_push.shndx =
-                        *section_merge_map.get(&(symbol.shndx as usize)).unwrap() as u16;
+                        *section_merge_map.get(&(symbol.shndx as usize)).unwrap() as u32;
                     to_push.value += *value_offsets
                         .get(&(symbol.shndx as usize))
                         .expect("could not get value offset")
@@ -63,92 +313,345 @@ impl ElfFile64 {
             }
         }
 
+        // the order ld uses: the null symbol, section symbols, each input's
+        // STT_FILE symbol followed by its locals in input order (which
+        // debuggers rely on to tell which file a local is from), then
+        // globals. Sorting is stable, so inputs keep their order.
         let symbols = std::mem::take(&mut self.symbols);
-        let (mut local, mut nonlocal): (Vec<(usize, Symbol64)>, _) = symbols
-            .into_iter()
-            .enumerate()
-            .partition(|(_, s)| sym_bind(s) == 0);
-        local.append(&mut nonlocal);
+        let mut ordered: Vec<(usize, Symbol64)> = symbols.into_iter().enumerate().collect();
+        ordered.sort_by_key(|(i, s)| {
+            if *i == 0 {
+                0
+            } else if sym_bind(s) != STB_LOCAL {
+                3
+            } else if sym_type(s) == STT_SECTION {
+                1
+            } else {
+                2
+            }
+        });
 
-        let mut new_indices = vec![0; local.len()];
+        let mut new_indices = vec![0; ordered.len()];
         let mut symbols = Vec::new();
-        for (new_index, (old_index, s)) in local.into_iter().enumerate() {
+        for (new_index, (old_index, s)) in ordered.into_iter().enumerate() {
             new_indices[old_index] = new_index;
             symbols.push(s);
         }
 
         std::mem::swap(&mut self.symbols, &mut symbols);
 
+        let machine = self.header.machine;
+        let endianness = self.header.identifier.endianness;
         for section in self.unorganized_sections.iter_mut() {
-            if section.relocations.is_some() {
-                for rela in section.relocations.as_mut().unwrap() {
-                    if rela.merged {
-                        let old_index = rela.get_sym();
-                        let old_type = rela.get_type();
+            let Section64 {
+                data, relocations, ..
+            } = section;
+            if let Some(relas) = relocations.as_mut() {
+                for i in 0..relas.len() {
+                    if relas[i].merged {
+                        let old_index = relas[i].get_sym();
+                        let old_type = relas[i].get_type();
+                        if let Some(offset) = addend_offsets.get(&old_index) {
+                            // the place keeps the old addend, so this one has
+                            // to be written out in a SHT_RELA
+                            if relas[i].implicit_addend {
+                                let addend =
+                                    arch::implicit_addend(machine, endianness, relas, i, data)
+                                        .expect("addend was read when merging section symbols");
+                                relas[i].addend = addend as u64;
+                                relas[i].implicit_addend = false;
+                            }
+                            relas[i].addend = relas[i].addend.wrapping_add(*offset);
+                        }
                         let new_index_pre_shuffle = *symbol_merge_map.get(&old_index).unwrap();
                         let new_index = new_indices[new_index_pre_shuffle];
-                        rela.set_info(new_index, old_type);
+                        relas[i].set_info(new_index, old_type);
+                        relas[i].merged = false;
                     } else {
-                        let old_index = rela.get_sym();
-                        let old_type = rela.get_type();
+                        let old_index = relas[i].get_sym();
+                        let old_type = relas[i].get_type();
                         let new_index = new_indices[old_index];
-                        rela.set_info(new_index, old_type);
+                        relas[i].set_info(new_index, old_type);
                     }
                 }
             }
         }
+
+        Ok(())
+    }
+
+    // concatenate groups of sections into one section each, given as its
+    // name and the sections that go in it in order. It takes the place of
+    // the first of them, the rest are dropped.
+    pub(crate) fn combine_sections(
+        &mut self,
+        groups: &[(String, Vec<usize>)],
+        fill: Option<&[u8]>,
+    ) -> Result<(), LinkError> {
+        let padding = padding(self.header.machine, fill);
+        let mut slots: Vec<Option<Section64>> = std::mem::take(&mut self.unorganized_sections)
+            .into_iter()
+            .map(Some)
+            .collect();
+
+        // the slot each section that went in another ends up in, and where
+        // in it
+        let mut moved = HashMap::new();
+        for (name, pieces) in groups.iter() {
+            let slot = match pieces.iter().min() {
+                Some(slot) => *slot,
+                None => continue,
+            };
+            let mut combined = slots[pieces[0]].take().unwrap();
+            for piece in pieces.iter().skip(1) {
+                let section = slots[*piece].take().unwrap();
+                let offset = combined
+                    .merge(&section, &padding)
+                    .map_err(|(theirs, ours)| LinkError::Incompatible {
+                        file: self.name.clone(),
+                        reason: format!(
+                            "{} is {}, but {} is {}, they can't be combined",
+                            &section.name, theirs, &combined.name, ours
+                        ),
+                    })?;
+                moved.insert(*piece, (slot, offset as u64));
+            }
+            moved.insert(pieces[0], (slot, 0));
+            // the relocations still refer to this file's symbols
+            for rela in combined.relocations.iter_mut().flatten() {
+                rela.merged = false;
+            }
+            combined.name = name.clone();
+            slots[slot] = Some(combined);
+        }
+
+        let mut new_indices = vec![0; slots.len()];
+        for (i, slot) in slots.into_iter().enumerate() {
+            if let Some(section) = slot {
+                new_indices[i] = self.unorganized_sections.len();
+                self.unorganized_sections.push(section);
+            }
+        }
+        for symbol in self.symbols.iter_mut() {
+            if symbol.shndx == 0 || symbol.shndx >= SHN_LORESERVE {
+                continue;
+            }
+            let shndx = symbol.shndx as usize;
+            let (slot, offset) = moved.get(&shndx).copied().unwrap_or((shndx, 0));
+            symbol.shndx = new_indices[slot] as u32;
+            symbol.value += offset;
+        }
+
+        Ok(())
+    }
+
+    // read implicit addends out of the data they relocate. A final link needs
+    // every addend, a relocatable output only needs them for sections where
+    // both kinds of relocation were merged, since those are written as
+    // SHT_RELA
+    fn make_addends_explicit(&mut self, mixed_only: bool) -> Result<(), LinkError> {
+        let machine = self.header.machine;
+        let endianness = self.header.identifier.endianness;
+        for section in self.unorganized_sections.iter_mut() {
+            let Section64 {
+                data, relocations, ..
+            } = section;
+            let relas = match relocations {
+                Some(relas) => relas,
+                None => continue,
+            };
+            if mixed_only && relocation_section_type(relas) == SectionType64::Rel {
+                continue;
+            }
+
+            for i in 0..relas.len() {
+                if relas[i].implicit_addend {
+                    let addend = arch::implicit_addend(machine, endianness, relas, i, data)?;
+                    relas[i].addend = addend as u64;
+                    relas[i].implicit_addend = false;
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-pub fn link(mut object_files: Vec<ElfFile64>) -> ElfFile64 {
+// link `object_files` (and, for a shared object, `shared_objects`) into one
+// output, returned along with any warnings about it for the caller to show
+pub fn link(
+    mut object_files: Vec<ElfFile64>,
+    shared_objects: &[SharedObject64],
+    options: &LinkOptions,
+) -> Result<(ElfFile64, Vec<String>), LinkError> {
+    if object_files.is_empty() {
+        return Err(LinkError::Unsupported(
+            "there are no object files to link".to_string(),
+        ));
+    }
+    if options.output_kind != OutputKind::Shared && !shared_objects.is_empty() {
+        return Err(LinkError::Unsupported(
+            "shared objects can only be linked into shared objects".to_string(),
+        ));
+    }
+
+    // relocations remember their input, which merging loses track of
+    for object_file in object_files.iter_mut() {
+        let name: Rc<str> = object_file.name.as_str().into();
+        for section in object_file.unorganized_sections.iter_mut() {
+            for rela in section.relocations.iter_mut().flatten() {
+                rela.input = Some(name.clone());
+            }
+        }
+    }
+
+    let final_link = options.output_kind != OutputKind::Relocatable;
+    let fill = options.fill.as_deref();
+    if final_link {
+        for object_file in object_files.iter_mut() {
+            init_array::convert_legacy(object_file, fill)?;
+            output_sections::group_inputs(object_file, fill)?;
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let exec_stack = exec_stack::is_executable(&object_files, options.exec_stack, &mut warnings);
+
     let mut result = object_files.remove(0);
+    for shared_object in shared_objects {
+        result.merge_header(&shared_object.soname, &shared_object.header)?;
+    }
 
+    let mut origins = result
+        .unorganized_sections
+        .iter()
+        .map(|s| (s.name.clone(), result.name.clone()))
+        .collect();
     for object_file in object_files.iter() {
-        result.link(object_file);
+        result.link(object_file, &mut origins, fill)?;
+    }
+
+    result.make_addends_explicit(options.output_kind == OutputKind::Relocatable)?;
+    mergeable::deduplicate(&mut result);
+
+    if final_link {
+        output_sections::combine(&mut result, fill)?;
+        init_array::combine(&mut result, fill)?;
+        result.finalize(shared_objects, options, exec_stack, &mut warnings)?;
+    } else {
+        exec_stack::set_note(&mut result, exec_stack);
     }
 
-    result
+    Ok((result, warnings))
 }
 
-impl Section64 {
-    pub fn merge(&mut self, other: &Section64) -> usize {
-        // TODO: can alignment be different?
-        if self.addralign != other.addralign {
-            panic!(
-                "{}: cannot merge sections with different alignment",
-                &other.name
-            );
+// the symbols in `file` that a SHT_REL relocation whose addend can't be read
+// (for a machine or relocation type we don't know) is against
+fn unreadable_addends(file: &ElfFile64) -> HashSet<usize> {
+    let machine = file.header.machine;
+    let endianness = file.header.identifier.endianness;
+    let mut retval = HashSet::new();
+    for section in file.unorganized_sections.iter() {
+        let relas = match section.relocations.as_deref() {
+            Some(relas) => relas,
+            None => continue,
+        };
+        for (i, rela) in relas.iter().enumerate() {
+            if rela.implicit_addend
+                && arch::implicit_addend(machine, endianness, relas, i, &section.data).is_err()
+            {
+                retval.insert(rela.get_sym());
+            }
         }
+    }
 
-        // TODO: can type be different?
-        if self.r#type != other.r#type {
-            panic!("{}: cannot merge sections with different type", &other.name);
-        }
+    retval
+}
 
-        // TODO: can flags be different?
-        if self.flags != other.flags {
-            panic!(
-                "{}: cannot merge sections with different flags",
-                &other.name
-            );
-        }
+// the bytes put between inputs merged into a section: `fill` repeated if
+// it's given, otherwise nops in code and zeroes in data
+fn padding(machine: u16, fill: Option<&[u8]>) -> impl Fn(&Section64, usize) -> Vec<u8> + '_ {
+    move |section, len| match fill {
+        Some(pattern) => pattern.iter().cycle().take(len).copied().collect(),
+        None if section.flags & SHF_EXECINSTR != 0 => arch::code_fill(machine, len),
+        None => vec![0; len],
+    }
+}
 
-        // TODO: can addr be different?
-        if self.addr != other.addr {
-            panic!("{}: cannot merge sections with different addr", &other.name);
-        }
+fn incompatible(name: &str, first: &str, what: &str, theirs: &str, ours: &str) -> LinkError {
+    LinkError::Incompatible {
+        file: name.to_string(),
+        reason: format!(
+            "{} is {}, but {} is {}, they can't be linked together",
+            what, theirs, first, ours
+        ),
+    }
+}
 
-        // append data to existing section after padding
-        let align = self.addralign as usize;
-        let existing_len = self.data.len();
-        let new_len = if existing_len.trailing_zeros() != align.trailing_zeros() {
-            let new_len = utils::next_aligned_value(existing_len, align);
-            self.data.resize(new_len, 0xff);
+fn class_name(class: u8) -> &'static str {
+    match class {
+        ELFCLASS32 => "32-bit",
+        ELFCLASS64 => "64-bit",
+        _ => "unknown",
+    }
+}
+
+fn endianness_name(endianness: nom::number::Endianness) -> &'static str {
+    match endianness {
+        nom::number::Endianness::Big => "big endian",
+        _ => "little endian",
+    }
+}
+
+impl Section64 {
+    // append `other` to this section, returning the offset it ends up at, or
+    // how the two differ (theirs, ours) if they can't be merged. The output
+    // is aligned enough for every input, and each input is placed at its own
+    // alignment, after the bytes `padding` gives for the merged section.
+    pub fn merge(
+        &mut self,
+        other: &Section64,
+        padding: impl FnOnce(&Section64, usize) -> Vec<u8>,
+    ) -> Result<usize, (String, String)> {
+        use SectionType64::*;
+
+        // .bss-like input in a section with data just takes up zeroes
+        let r#type = match (self.r#type, other.r#type) {
+            (ours, theirs) if ours == theirs => ours,
+            (Progbits, Nobits) | (Nobits, Progbits) => Progbits,
+            (ours, theirs) => return Err((format!("{:?}", theirs), format!("{:?}", ours))),
+        };
 
-            new_len
+        // allocated and thread-local sections are laid out differently,
+        // nothing else stops inputs from being merged
+        for (flag, name) in [(SHF_ALLOC, "allocated"), (SHF_TLS, "thread-local")] {
+            if self.flags & flag != other.flags & flag {
+                let describe = |flags: u64| {
+                    if flags & flag != 0 {
+                        name.to_string()
+                    } else {
+                        format!("not {}", name)
+                    }
+                };
+                return Err((describe(other.flags), describe(self.flags)));
+            }
+        }
+        // the contents can only be merged as strings or constants if they
+        // all can be, in pieces of the same size
+        let both = SHF_MERGE | SHF_STRINGS;
+        let mergeable = if self.entsize == other.entsize {
+            self.flags & other.flags & both
         } else {
-            existing_len
+            0
         };
+        self.flags = (self.flags | other.flags) & !both | mergeable;
+        self.r#type = r#type;
+        self.addralign = std::cmp::max(self.addralign, other.addralign);
+
+        // append data to existing section after padding
+        let new_len = utils::align_up(self.data.len() as u64, other.addralign) as usize;
+        let padding = padding(self, new_len - self.data.len());
+        self.data.extend(padding);
         self.data.append(&mut other.data.clone());
 
         match &other.relocations {
@@ -168,6 +671,291 @@ impl Section64 {
             }
         }
 
-        new_len
+        Ok(new_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::aarch64::EM_AARCH64;
+    use crate::elf::relocation::RelocationA64;
+    use crate::elf::section::SHF_WRITE;
+    use crate::elf::symbol::SymbolVisibility;
+
+    const MAIN_O: &[u8] = include_bytes!("../test-files/01-combine-objects/main.o");
+    const GREET_O: &[u8] = include_bytes!("../test-files/01-combine-objects/greet.o");
+
+    fn parse(input: &[u8], name: &str) -> ElfFile64 {
+        let mut elf = ElfFile64::parse(input).unwrap();
+        elf.name = name.to_string();
+        elf
+    }
+
+    #[test]
+    fn mismatched_headers_are_errors() {
+        type Mismatch = (&'static str, fn(&mut ElfFile64HeaderRaw));
+        let mismatches: [Mismatch; 3] = [
+            ("machine", |h| h.machine = EM_AARCH64),
+            ("class", |h| h.identifier.class = ELFCLASS32),
+            ("OS/ABI", |h| h.identifier.os_abi = 3),
+        ];
+        for (what, mismatch) in mismatches.iter() {
+            let mut main = parse(MAIN_O, "main.o");
+            main.header.identifier.os_abi = 9;
+            let mut greet = parse(GREET_O, "greet.o");
+            mismatch(&mut greet.header);
+
+            match link(vec![main, greet], &[], &LinkOptions::default()) {
+                Err(LinkError::Incompatible { file, reason }) => {
+                    assert_eq!(file, "greet.o");
+                    assert!(reason.starts_with(what), "{}", reason);
+                    assert!(reason.contains("main.o"), "{}", reason);
+                }
+                other => panic!("{} mismatch gave {:?}", what, other.map(|_| ())),
+            }
+        }
+    }
+
+    fn section(r#type: SectionType64, flags: u64, addralign: u64, data: &[u8]) -> Section64 {
+        Section64 {
+            name: ".data".to_string(),
+            r#type,
+            flags,
+            addr: 0,
+            link: 0,
+            info: 0,
+            addralign,
+            entsize: 0,
+            data: data.to_vec(),
+            relocations: None,
+        }
+    }
+
+    fn pad_with_ff(_: &Section64, len: usize) -> Vec<u8> {
+        vec![0xff; len]
+    }
+
+    #[test]
+    fn merged_sections_are_aligned_for_every_input() {
+        let mut ours = section(SectionType64::Progbits, SHF_ALLOC, 4, &[1, 2, 3]);
+        let theirs = section(SectionType64::Progbits, SHF_ALLOC, 16, &[4]);
+        assert_eq!(ours.merge(&theirs, pad_with_ff), Ok(16));
+        assert_eq!(ours.addralign, 16);
+        assert_eq!(ours.data.len(), 17);
+        assert_eq!(&ours.data[..4], &[1, 2, 3, 0xff]);
+
+        // a less aligned input goes straight after
+        let theirs = section(SectionType64::Progbits, SHF_ALLOC, 1, &[5]);
+        assert_eq!(ours.merge(&theirs, pad_with_ff), Ok(17));
+        assert_eq!(ours.addralign, 16);
+    }
+
+    #[test]
+    fn nobits_merged_with_progbits_is_progbits() {
+        let flags = SHF_ALLOC | SHF_WRITE;
+        let mut ours = section(SectionType64::Nobits, flags, 8, &[0; 8]);
+        let theirs = section(SectionType64::Progbits, flags, 8, &[7; 8]);
+        assert_eq!(ours.merge(&theirs, pad_with_ff), Ok(8));
+        assert_eq!(ours.r#type, SectionType64::Progbits);
+        assert_eq!(&ours.data[..8], &[0; 8]);
+
+        let mut ours = section(SectionType64::Progbits, flags, 8, &[7; 8]);
+        let theirs = section(SectionType64::Nobits, flags, 8, &[0; 8]);
+        assert_eq!(ours.merge(&theirs, pad_with_ff), Ok(8));
+        assert_eq!(ours.r#type, SectionType64::Progbits);
+    }
+
+    #[test]
+    fn merging_needs_pieces_of_the_same_size() {
+        let flags = SHF_ALLOC | SHF_MERGE;
+        let mut ours = section(SectionType64::Progbits, flags, 4, &[0; 4]);
+        ours.entsize = 4;
+        let mut theirs = section(SectionType64::Progbits, flags, 4, &[0; 4]);
+        theirs.entsize = 4;
+        ours.merge(&theirs, pad_with_ff).unwrap();
+        assert_eq!(ours.flags, flags);
+
+        theirs.entsize = 8;
+        ours.merge(&theirs, pad_with_ff).unwrap();
+        assert_eq!(ours.flags, SHF_ALLOC);
+    }
+
+    #[test]
+    fn allocated_and_thread_local_sections_are_not_merged() {
+        let mut ours = section(SectionType64::Progbits, SHF_ALLOC, 1, &[]);
+        let theirs = section(SectionType64::Progbits, 0, 1, &[]);
+        assert_eq!(
+            ours.merge(&theirs, pad_with_ff),
+            Err(("not allocated".to_string(), "allocated".to_string()))
+        );
+        let theirs = section(SectionType64::Progbits, SHF_ALLOC | SHF_TLS, 1, &[]);
+        assert!(ours.merge(&theirs, pad_with_ff).is_err());
+
+        // linking them names both inputs
+        let main = parse(MAIN_O, "main.o");
+        let mut greet = parse(GREET_O, "greet.o");
+        let text = greet
+            .unorganized_sections
+            .iter_mut()
+            .find(|s| s.name == ".text")
+            .unwrap();
+        text.flags &= !SHF_ALLOC;
+        match link(vec![main, greet], &[], &LinkOptions::default()) {
+            Err(LinkError::Incompatible { file, reason }) => {
+                assert_eq!(file, "greet.o");
+                assert!(reason.starts_with(".text is not allocated"), "{}", reason);
+                assert!(reason.contains("main.o"), "{}", reason);
+            }
+            other => panic!("merging gave {:?}", other.map(|_| ())),
+        }
+    }
+
+    // main.o on its own, with its call to greet() made a call to itself and
+    // `change`d
+    fn link_main_with(change: impl Fn(&mut Section64)) -> Result<(), LinkError> {
+        let mut main = parse(MAIN_O, "main.o");
+        let text = main
+            .unorganized_sections
+            .iter_mut()
+            .find(|s| s.name == ".text")
+            .unwrap();
+        let call = &mut text.relocations.as_mut().unwrap()[0];
+        call.set_info(2, call.get_type());
+        change(text);
+
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            ..LinkOptions::default()
+        };
+        link(vec![main], &[], &options).map(|_| ())
+    }
+
+    #[test]
+    fn relocations_are_checked_when_applied() {
+        assert!(link_main_with(|_| {}).is_ok());
+
+        // a 4-byte relocation 2 bytes from the end of .text
+        let result = link_main_with(|text| {
+            text.relocations.as_mut().unwrap()[0].offset = text.data.len() as u64 - 2;
+        });
+        assert!(matches!(result, Err(LinkError::InvalidRelocation(_))));
+
+        let result = link_main_with(|text| {
+            text.relocations.as_mut().unwrap()[0].addend = i64::MAX as u64;
+        });
+        assert!(matches!(result, Err(LinkError::Overflow(_))));
+    }
+
+    const DEF_O: &[u8] = include_bytes!("../test-files/07-visibility/def.o");
+    const REF_O: &[u8] = include_bytes!("../test-files/07-visibility/ref.o");
+
+    #[test]
+    fn most_restrictive_visibility_wins() {
+        let options = LinkOptions {
+            output_kind: OutputKind::Relocatable,
+            ..LinkOptions::default()
+        };
+        // whichever input comes first
+        let orders = [[DEF_O, REF_O], [REF_O, DEF_O]];
+        for [first, second] in orders {
+            let inputs = vec![parse(first, "first.o"), parse(second, "second.o")];
+            let (output, _) = link(inputs, &[], &options).unwrap();
+            let visibility = |name: &str| {
+                let symbol = output.symbols.iter().find(|s| s.name == name).unwrap();
+                assert_ne!(symbol.shndx, 0, "`{}` is undefined", name);
+                symbol.visibility()
+            };
+            // a hidden reference hides a default definition, a default
+            // reference leaves a protected definition protected
+            assert_eq!(visibility("value"), SymbolVisibility::Hidden);
+            assert_eq!(visibility("get"), SymbolVisibility::Protected);
+            assert_eq!(visibility("use"), SymbolVisibility::Default);
+        }
+    }
+
+    #[test]
+    fn relocatable_links_keep_section_symbol_addends() {
+        let inputs = vec![
+            parse(include_bytes!("../test-files/09-relocatable/a.o"), "a.o"),
+            parse(include_bytes!("../test-files/09-relocatable/b.o"), "b.o"),
+        ];
+        let options = LinkOptions {
+            output_kind: OutputKind::Relocatable,
+            ..LinkOptions::default()
+        };
+        let (output, _) = link(inputs, &[], &options).unwrap();
+        let section = |name: &str| {
+            let sections = output.unorganized_sections.iter();
+            sections.filter(|s| s.name == name).collect::<Vec<_>>()
+        };
+        let text = section(".text");
+        let data = section(".data");
+        assert_eq!((text.len(), data.len()), (1, 1));
+        let relocations = |section: &Section64| {
+            let relas = section.relocations.as_ref().unwrap();
+            // section symbols go by their section's name, like readelf shows
+            let name = |rela: &RelocationA64| {
+                let symbol = &output.symbols[rela.get_sym()];
+                match sym_type(symbol) {
+                    STT_SECTION => output.unorganized_sections[symbol.shndx as usize]
+                        .name
+                        .clone(),
+                    _ => symbol.name.clone(),
+                }
+            };
+            relas
+                .iter()
+                .map(|rela| (rela.offset, name(rela), rela.addend, rela.implicit_addend))
+                .collect::<Vec<_>>()
+        };
+
+        // both inputs' .text are one section with one section symbol, b.o's
+        // pointer into it is moved past a.o's 7 bytes and the padding to 8.
+        // Moving it meant reading it, so .data's relocations are all
+        // written with their addends.
+        let b = output.symbols.iter().find(|s| s.name == "b").unwrap();
+        assert_eq!(b.value, 8);
+        assert_eq!(
+            relocations(data[0]),
+            [
+                (0, ".text".to_string(), 6, false),
+                (4, ".text".to_string(), 11, false),
+                (8, "a".to_string(), 0, false),
+            ]
+        );
+        let relas = data[0].relocations.as_ref().unwrap();
+        assert_eq!(relocation_section_type(relas), SectionType64::Rela);
+
+        // nothing in .text moved, its addends stay in the places
+        let relas = text[0].relocations.as_ref().unwrap();
+        assert!(relas.iter().all(|rela| rela.implicit_addend));
+        assert_eq!(relocation_section_type(relas), SectionType64::Rel);
+        assert_eq!(relas[1].offset, 10);
+        assert_eq!(text[0].data[10..14], (-4i32).to_le_bytes());
+    }
+
+    #[test]
+    fn conflicting_inputs_are_errors() {
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            ..LinkOptions::default()
+        };
+
+        // `main` twice
+        let inputs = vec![parse(MAIN_O, "main.o"), parse(MAIN_O, "again.o")];
+        match link(inputs, &[], &options) {
+            Err(LinkError::Incompatible { file, reason }) => {
+                assert_eq!(file, "again.o");
+                assert_eq!(reason, "`main` is already defined by another input");
+            }
+            _ => panic!("expected an error"),
+        }
+
+        // a shared object in a static executable
+        let libgreet = include_bytes!("../test-files/03-versioned-library/libgreet.so");
+        let so = SharedObject64::parse(libgreet, "libgreet.so", &Default::default()).unwrap();
+        let result = link(vec![parse(MAIN_O, "main.o")], &[so], &options);
+        assert!(matches!(result, Err(LinkError::Unsupported(_))));
     }
 }
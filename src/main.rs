@@ -1,16 +1,142 @@
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
-use ld_rs::elf::ElfFile64;
+use ld_rs::elf::shared_object::SharedObject64;
+use ld_rs::elf::{name_from_bytes, ElfFile64, ElfFileError, ParseLimits};
 use ld_rs::link;
+use ld_rs::link::{LinkOptions, OutputKind};
+use ld_rs::version_script::VersionScript;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 struct Opt {
     #[structopt(name = "FILE", parse(from_os_str))]
     filenames: Vec<PathBuf>,
+
+    /// Write the result to this file
+    #[structopt(short, long, default_value = "output.o", parse(from_os_str))]
+    output: PathBuf,
+
+    /// Produce a shared object instead of a relocatable file
+    #[structopt(long)]
+    shared: bool,
+
+    /// Produce a statically linked executable instead of a relocatable file
+    #[structopt(long = "static", conflicts_with = "shared")]
+    static_executable: bool,
+
+    /// Set DT_SONAME of a shared object
+    #[structopt(long, short = "h", parse(from_os_str = name))]
+    soname: Option<String>,
+
+    /// Set DT_RUNPATH of a shared object
+    #[structopt(long, parse(from_os_str = name))]
+    rpath: Option<String>,
+
+    /// Give a shared object this program loader in PT_INTERP, so that it
+    /// can be run like an executable
+    #[structopt(long, short = "I", parse(from_os_str = name))]
+    dynamic_linker: Option<String>,
+
+    /// Read version information for a shared object from this script
+    #[structopt(long, parse(from_os_str))]
+    version_script: Option<PathBuf>,
+
+    /// Don't rewrite instructions to avoid going through the GOT
+    #[structopt(long)]
+    no_relax: bool,
+
+    /// Pad between inputs merged into a section with this pattern, in hex
+    /// (e.g. 0xcc), instead of nops for code and zeroes for data
+    #[structopt(long)]
+    fill: Option<String>,
+
+    /// Build .eh_frame_hdr and a PT_GNU_EH_FRAME segment for it
+    #[structopt(long)]
+    eh_frame_hdr: bool,
+
+    /// Linker keywords: relro, norelro, now, lazy, separate-code,
+    /// noseparate-code, execstack, noexecstack, max-page-size=SIZE and
+    /// common-page-size=SIZE
+    #[structopt(short = "z", number_of_values = 1)]
+    keywords: Vec<String>,
+
+    /// Refuse input files with more sections than this
+    #[structopt(long, default_value = "1048576")]
+    max_sections: usize,
+
+    /// Refuse to read more than this many bytes of input files in total,
+    /// or to hold more than this for any one of them
+    #[structopt(long, default_value = "4294967296")]
+    max_input_size: u64,
+
+    /// Refuse input files with sections aligned to more than this
+    #[structopt(long, default_value = "1048576")]
+    max_align: u64,
+
+    /// Refuse to write an output file bigger than this
+    #[structopt(long, default_value = "4294967296")]
+    max_output_size: u64,
+}
+
+// names can be any bytes but NUL, like in ELF files
+fn name(s: &OsStr) -> String {
+    name_from_bytes(s.as_bytes())
+}
+
+fn parse_fill(s: &str) -> Vec<u8> {
+    let digits = s.trim_start_matches("0x");
+    match hex::decode(digits) {
+        Ok(pattern) if !pattern.is_empty() => pattern,
+        _ => {
+            eprintln!("{} is not a fill pattern, it should be hex bytes", s);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn parse_page_size(keyword: &str, s: &str) -> u64 {
+    let size = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    match size {
+        Ok(size) if size.is_power_of_two() => size,
+        _ => {
+            eprintln!("-z {}: {} is not a power of two", keyword, s);
+            std::process::exit(1)
+        }
+    }
+}
+
+// apply the `-z` keywords to `options`, like ld, ones we don't know are
+// ignored
+fn apply_keywords(keywords: &[String], options: &mut LinkOptions) {
+    for keyword in keywords {
+        match keyword.split_once('=') {
+            Some(("max-page-size", size)) => {
+                options.max_page_size = Some(parse_page_size(keyword, size))
+            }
+            Some(("common-page-size", size)) => {
+                options.common_page_size = Some(parse_page_size(keyword, size))
+            }
+            _ => match keyword.as_str() {
+                "relro" => options.relro = true,
+                "norelro" => options.relro = false,
+                "now" => options.bind_now = true,
+                "lazy" => options.bind_now = false,
+                "separate-code" => options.separate_code = true,
+                "noseparate-code" => options.separate_code = false,
+                "execstack" => options.exec_stack = Some(true),
+                "noexecstack" => options.exec_stack = Some(false),
+                _ => eprintln!("warning: -z {} ignored", keyword),
+            },
+        }
+    }
 }
 
 fn generic_error(action: &str) -> ! {
@@ -18,21 +144,57 @@ fn generic_error(action: &str) -> ! {
     std::process::exit(1)
 }
 
+fn too_big(f: &Path) -> ! {
+    eprintln!(
+        "{}: input goes over --max-sections, --max-input-size or --max-align",
+        f.display()
+    );
+    std::process::exit(1)
+}
+
 fn main() {
     let opt = Opt::from_args();
 
     let mut elfs = Vec::new();
+    let mut shared_objects = Vec::new();
 
+    let limits = ParseLimits {
+        max_sections: opt.max_sections,
+        max_size: opt.max_input_size,
+        max_align: opt.max_align,
+        max_output_size: opt.max_output_size,
+    };
+    let mut input_left = opt.max_input_size;
     for f in opt.filenames.iter() {
         let mut buf = Vec::new();
-        let mut file = File::open(f).unwrap_or_else(|_| generic_error("opening"));
-        file.read_to_end(&mut buf)
+        let file = File::open(f).unwrap_or_else(|_| generic_error("opening"));
+        // read one byte past the limit to tell if the file goes over it
+        file.take(input_left.saturating_add(1))
+            .read_to_end(&mut buf)
             .unwrap_or_else(|_| generic_error("reading"));
+        input_left = input_left
+            .checked_sub(buf.len() as u64)
+            .unwrap_or_else(|| too_big(f));
+
+        if SharedObject64::is_shared_object(&buf[..]) {
+            let filename = f.file_name().unwrap_or_default().to_string_lossy();
+            match SharedObject64::parse(&buf[..], &filename, &limits) {
+                Ok(shared_object) => shared_objects.push(shared_object),
+                Err(ElfFileError::LimitError) => too_big(f),
+                Err(_) => {
+                    eprintln!("That is not a valid shared object!");
+                    std::process::exit(1);
+                }
+            }
+            continue;
+        }
 
-        match ElfFile64::parse(&buf[..]) {
-            Ok(elf) => {
+        match ElfFile64::parse_with_limits(&buf[..], &limits) {
+            Ok(mut elf) => {
+                elf.name = f.display().to_string();
                 elfs.push(elf);
             }
+            Err(ElfFileError::LimitError) => too_big(f),
             Err(_) => {
                 eprintln!("That is not an ELF file!");
                 std::process::exit(1);
@@ -40,6 +202,56 @@ fn main() {
         };
     }
 
-    let mut file = File::create("output.o").expect("could not create file");
-    ElfFile64::write_out(link(elfs), &mut file).expect("could not write file");
+    let version_script = opt.version_script.as_ref().map(|f| {
+        let script = std::fs::read(f).unwrap_or_else(|_| generic_error("reading"));
+        VersionScript::parse(&name_from_bytes(&script)).unwrap_or_else(|_| {
+            eprintln!("That is not a valid version script!");
+            std::process::exit(1);
+        })
+    });
+
+    let mut options = LinkOptions {
+        output_kind: if opt.shared {
+            OutputKind::Shared
+        } else if opt.static_executable {
+            OutputKind::StaticExecutable
+        } else {
+            OutputKind::Relocatable
+        },
+        soname: opt.soname,
+        rpath: opt.rpath,
+        version_script,
+        relax: !opt.no_relax,
+        fill: opt.fill.as_deref().map(parse_fill),
+        eh_frame_hdr: opt.eh_frame_hdr,
+        dynamic_linker: opt.dynamic_linker,
+        output: opt.output.clone(),
+        ..LinkOptions::default()
+    };
+    apply_keywords(&opt.keywords, &mut options);
+
+    let (output, warnings) = link(elfs, &shared_objects, &options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    let mut file = File::create(&opt.output).expect("could not create file");
+    ElfFile64::write_out_with_limits(output, &mut file, &limits).unwrap_or_else(|e| {
+        eprintln!("{}: {}", options.output.display(), e);
+        let _ = std::fs::remove_file(&options.output);
+        std::process::exit(1)
+    });
+
+    // executables need to be, well, executable, and so do shared objects
+    // with a PT_INTERP to run them
+    if options.output_kind == OutputKind::StaticExecutable
+        || options.output_kind == OutputKind::Shared && options.dynamic_linker.is_some()
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        file.set_permissions(std::fs::Permissions::from_mode(0o755))
+            .expect("could not set permissions");
+    }
 }
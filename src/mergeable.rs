@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use crate::elf::section::{Section64, SHF_MERGE, SHF_STRINGS};
+use crate::elf::symbol::{sym_type, STT_SECTION};
+use crate::elf::ElfFile64;
+use crate::utils;
+
+// SHF_MERGE sections hold strings (with SHF_STRINGS) or constants of
+// `sh_entsize` bytes that nothing cares the address of beyond their contents,
+// so each distinct one only has to be kept once no matter how many inputs
+// had it. A string that is the end of another string is kept as part of it.
+pub(crate) fn deduplicate(file: &mut ElfFile64) {
+    for shndx in 0..file.unorganized_sections.len() {
+        let section = &file.unorganized_sections[shndx];
+        if section.flags & SHF_MERGE == 0 || section.relocations.is_some() {
+            continue;
+        }
+        let pieces = match split(section) {
+            Some(pieces) => pieces,
+            None => continue,
+        };
+
+        // relocations against the section symbol point into it with their
+        // addend, which has to have been read already
+        let symbols = &file.symbols;
+        let against_section = |sym_idx: usize| {
+            let symbol = &symbols[sym_idx];
+            sym_type(symbol) == STT_SECTION && symbol.shndx as usize == shndx
+        };
+        let implicit = file
+            .unorganized_sections
+            .iter()
+            .filter_map(|s| s.relocations.as_ref())
+            .flatten()
+            .any(|rela| rela.implicit_addend && against_section(rela.get_sym()));
+        if implicit {
+            continue;
+        }
+
+        let (data, new_offsets) = lay_out(section, &pieces);
+        let old_len = section.data.len() as u64;
+        let new_len = data.len() as u64;
+        // where a byte of the section ends up, anything past the end stays
+        // as far past it
+        let map = |offset: u64| {
+            let i = pieces.partition_point(|(start, _)| *start as u64 <= offset);
+            if offset >= old_len || i == 0 {
+                offset.wrapping_sub(old_len).wrapping_add(new_len)
+            } else {
+                new_offsets[i - 1] + offset - pieces[i - 1].0 as u64
+            }
+        };
+
+        for section in file.unorganized_sections.iter_mut() {
+            let relas = match section.relocations.as_mut() {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            for rela in relas.iter_mut() {
+                if against_section(rela.get_sym()) {
+                    let symbol = &symbols[rela.get_sym()];
+                    rela.addend = map(symbol.value.wrapping_add(rela.addend));
+                }
+            }
+        }
+
+        for symbol in file.symbols.iter_mut() {
+            if symbol.shndx as usize != shndx {
+                continue;
+            }
+            symbol.value = if sym_type(symbol) == STT_SECTION {
+                0
+            } else {
+                map(symbol.value)
+            };
+        }
+
+        file.unorganized_sections[shndx].data = data;
+    }
+}
+
+// the (offset, length) of each string or constant in a mergeable section, if
+// the section really is made of them
+fn split(section: &Section64) -> Option<Vec<(usize, usize)>> {
+    let size = section.entsize as usize;
+    let data = &section.data;
+    if size == 0 || !data.len().is_multiple_of(size) {
+        return None;
+    }
+
+    if section.flags & SHF_STRINGS == 0 {
+        return Some((0..data.len()).step_by(size).map(|i| (i, size)).collect());
+    }
+
+    // strings of `size`-byte characters, each ending with a NUL character
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (i, c) in data.chunks(size).enumerate() {
+        if c.iter().all(|b| *b == 0) {
+            let end = (i + 1) * size;
+            pieces.push((start, end - start));
+            start = end;
+        }
+    }
+    if start != data.len() {
+        return None;
+    }
+
+    Some(pieces)
+}
+
+// the deduplicated contents, and the offset each piece ends up at in them
+fn lay_out(section: &Section64, pieces: &[(usize, usize)]) -> (Vec<u8>, Vec<u64>) {
+    let size = section.entsize as usize;
+    let bytes = |(start, len): (usize, usize)| &section.data[start..start + len];
+    // pieces aligned beyond their size (like GCC's `.rodata.str1.8`) stay
+    // aligned, and so can't be placed at the end of another
+    let align = if section.addralign > section.entsize {
+        section.addralign
+    } else {
+        1
+    };
+
+    // distinct pieces, in the order they first appear
+    let mut unique: Vec<&[u8]> = Vec::new();
+    let mut indexes = HashMap::new();
+    let piece_indexes: Vec<usize> = pieces
+        .iter()
+        .map(|piece| {
+            *indexes.entry(bytes(*piece)).or_insert_with(|| {
+                unique.push(bytes(*piece));
+                unique.len() - 1
+            })
+        })
+        .collect();
+
+    // sorted backwards by their reversed characters, a string comes after
+    // every string it's the end of, so it's the end of the last one kept
+    // unless it isn't the end of any
+    let mut suffix_of = vec![None; unique.len()];
+    if section.flags & SHF_STRINGS != 0 && align == 1 {
+        let mut order: Vec<usize> = (0..unique.len()).collect();
+        order.sort_by(|a, b| {
+            let reversed = |i: &usize| unique[*i].chunks(size).rev();
+            reversed(b).cmp(reversed(a))
+        });
+
+        let mut kept: Option<usize> = None;
+        for i in order {
+            match kept {
+                Some(k) if unique[k].ends_with(unique[i]) => suffix_of[i] = Some(k),
+                _ => kept = Some(i),
+            }
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut offsets = vec![0; unique.len()];
+    for (i, piece) in unique.iter().enumerate() {
+        if suffix_of[i].is_none() {
+            let offset = utils::align_up(data.len() as u64, align);
+            data.resize(offset as usize, 0);
+            data.extend_from_slice(piece);
+            offsets[i] = offset;
+        }
+    }
+    for (i, piece) in unique.iter().enumerate() {
+        if let Some(k) = suffix_of[i] {
+            offsets[i] = offsets[k] + (unique[k].len() - piece.len()) as u64;
+        }
+    }
+
+    let new_offsets = piece_indexes.iter().map(|i| offsets[*i]).collect();
+
+    (data, new_offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::R_X86_64_64;
+    use crate::elf::relocation::RelocationA64;
+    use crate::elf::section::{SectionType64, SHF_ALLOC, SHF_EXECINSTR};
+    use crate::elf::symbol::Symbol64;
+
+    const MAIN_O: &[u8] = include_bytes!("../test-files/01-combine-objects/main.o");
+
+    // main.o's header with a .text section (0) and a mergeable section (1)
+    // of `data`, its section symbol (0), a symbol at each of `offsets` and a
+    // relocation in .text against the section symbol for each of `addends`
+    fn merge_file(
+        data: &[u8],
+        entsize: u64,
+        strings: bool,
+        offsets: &[u64],
+        addends: &[u64],
+    ) -> ElfFile64 {
+        let mut file = ElfFile64::parse(MAIN_O).unwrap();
+        let section = |name: &str, flags, data: &[u8], relocations| Section64 {
+            name: name.to_string(),
+            r#type: SectionType64::Progbits,
+            flags,
+            addr: 0,
+            link: 0,
+            info: 0,
+            addralign: 1,
+            entsize,
+            data: data.to_vec(),
+            relocations,
+        };
+        let relocations = addends
+            .iter()
+            .map(|&addend| {
+                let mut rela = RelocationA64 {
+                    offset: 0,
+                    info: 0,
+                    addend,
+                    merged: false,
+                    implicit_addend: false,
+                    input: None,
+                };
+                rela.set_info(0, R_X86_64_64);
+                rela
+            })
+            .collect();
+        let flags = SHF_ALLOC | SHF_MERGE | if strings { SHF_STRINGS } else { 0 };
+        let text = SHF_ALLOC | SHF_EXECINSTR;
+        file.unorganized_sections = vec![
+            section(".text", text, &[0; 8], Some(relocations)),
+            section(".rodata.merge", flags, data, None),
+        ];
+
+        let symbol = |name: String, info, value| Symbol64 {
+            name,
+            info,
+            other: 0,
+            shndx: 1,
+            value,
+            size: 0,
+        };
+        file.symbols = vec![symbol(String::new(), STT_SECTION, 0)];
+        for offset in offsets {
+            file.symbols
+                .push(symbol(format!("at_{}", offset), 0, *offset));
+        }
+
+        file
+    }
+
+    // the values of the symbols `merge_file` put at `offsets`
+    fn values(file: &ElfFile64) -> Vec<u64> {
+        file.symbols[1..].iter().map(|s| s.value).collect()
+    }
+
+    fn addends(file: &ElfFile64) -> Vec<u64> {
+        let relas = file.unorganized_sections[0].relocations.as_ref().unwrap();
+        relas.iter().map(|rela| rela.addend).collect()
+    }
+
+    #[test]
+    fn strings_are_deduplicated() {
+        let mut file = merge_file(b"hello\0world\0hello\0", 1, true, &[0, 6, 12], &[]);
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, b"hello\0world\0");
+        assert_eq!(values(&file), [0, 6, 0]);
+    }
+
+    #[test]
+    fn strings_go_at_the_end_of_those_they_end() {
+        let mut file = merge_file(b"lo\0hello\0", 1, true, &[0, 3], &[]);
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, b"hello\0");
+        assert_eq!(values(&file), [3, 0]);
+
+        // unless they're aligned beyond their size
+        let mut file = merge_file(b"lo\0hello\0", 1, true, &[0, 3], &[]);
+        file.unorganized_sections[1].addralign = 8;
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, b"lo\0\0\0\0\0\0hello\0");
+        assert_eq!(values(&file), [0, 8]);
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0];
+        let mut file = merge_file(&data, 4, false, &[0, 4, 8], &[]);
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, data[..8]);
+        assert_eq!(values(&file), [0, 4, 0]);
+
+        // a section that isn't made of them is left alone
+        let mut file = merge_file(&data[..10], 4, false, &[0, 4, 8], &[]);
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, data[..10]);
+        assert_eq!(values(&file), [0, 4, 8]);
+    }
+
+    #[test]
+    fn section_symbol_addends_follow_their_piece() {
+        // into the second "hello", into "world", and past the end
+        let data = b"hello\0world\0hello\0";
+        let mut file = merge_file(data, 1, true, &[], &[13, 8, 22]);
+        deduplicate(&mut file);
+        assert_eq!(addends(&file), [1, 8, 16]);
+        assert_eq!(file.symbols[0].value, 0);
+    }
+
+    #[test]
+    fn sections_that_cant_be_rewritten_are_left_alone() {
+        let data = b"hello\0hello\0";
+
+        // relocations of its own could point anywhere in it
+        let mut file = merge_file(data, 1, true, &[6], &[]);
+        file.unorganized_sections[1].relocations = Some(Vec::new());
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, data);
+        assert_eq!(values(&file), [6]);
+
+        // an addend in the place hasn't been read
+        let mut file = merge_file(data, 1, true, &[6], &[6]);
+        let relas = file.unorganized_sections[0].relocations.as_mut().unwrap();
+        relas[0].implicit_addend = true;
+        deduplicate(&mut file);
+        assert_eq!(file.unorganized_sections[1].data, data);
+        assert_eq!(values(&file), [6]);
+        assert_eq!(addends(&file), [6]);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::elf::section::SHF_MERGE;
+use crate::elf::ElfFile64;
+use crate::link::LinkError;
+
+// the sections of a final link that inputs named `.name` or `.name.anything`
+// go in, as ld and lld name them, more specific names first
+const OUTPUT_SECTIONS: [&str; 12] = [
+    ".data.rel.ro",
+    ".data",
+    ".rodata",
+    ".bss.rel.ro",
+    ".bss",
+    ".tdata",
+    ".tbss",
+    ".sdata",
+    ".sbss",
+    ".srodata",
+    ".gcc_except_table",
+    ".text",
+];
+
+// code the compiler knows is rarely run, only run at exit or startup, or run
+// a lot is kept together at the start of .text, in this order
+const TEXT_GROUPS: [&str; 4] = [".text.unlikely", ".text.exit", ".text.startup", ".text.hot"];
+
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+        None => false,
+    }
+}
+
+// the section, or group of .text, that an input section goes in
+fn group_name(name: &str) -> Option<&'static str> {
+    TEXT_GROUPS
+        .iter()
+        .chain(OUTPUT_SECTIONS.iter())
+        .find(|prefix| has_prefix(name, prefix))
+        .copied()
+}
+
+// gather each input of a final link's sections (like `.text.foo` from
+// `-ffunction-sections`) into the section or group of .text they go in,
+// keeping the order the input has them in. SHF_MERGE sections are left alone
+// until they've been deduplicated.
+pub(crate) fn group_inputs(file: &mut ElfFile64, fill: Option<&[u8]>) -> Result<(), LinkError> {
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, section) in file.unorganized_sections.iter().enumerate() {
+        if section.flags & SHF_MERGE != 0 {
+            continue;
+        }
+        let name = match group_name(&section.name) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        match groups.iter_mut().find(|(n, _)| n == name) {
+            Some((_, pieces)) => pieces.push(i),
+            None => groups.push((name.to_string(), vec![i])),
+        }
+    }
+
+    file.combine_sections(&groups, fill)
+}
+
+// put the groups of .text and the SHF_MERGE sections of a merged file in the
+// sections they go in
+pub(crate) fn combine(file: &mut ElfFile64, fill: Option<&[u8]>) -> Result<(), LinkError> {
+    let mut groups: Vec<(String, Vec<(usize, usize)>)> = Vec::new();
+    for (i, section) in file.unorganized_sections.iter().enumerate() {
+        let group = match group_name(&section.name) {
+            Some(group) => group,
+            None => continue,
+        };
+        let rank = TEXT_GROUPS
+            .iter()
+            .position(|g| *g == group)
+            .unwrap_or(TEXT_GROUPS.len());
+        let name = if rank < TEXT_GROUPS.len() {
+            ".text"
+        } else {
+            group
+        };
+
+        match groups.iter_mut().find(|(n, _)| n == name) {
+            Some((_, pieces)) => pieces.push((rank, i)),
+            None => groups.push((name.to_string(), vec![(rank, i)])),
+        }
+    }
+
+    let groups: Vec<(String, Vec<usize>)> = groups
+        .into_iter()
+        .map(|(name, mut pieces)| {
+            pieces.sort();
+            (name, pieces.into_iter().map(|(_, i)| i).collect())
+        })
+        .collect();
+    file.combine_sections(&groups, fill)
+}
@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashMap};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::arch::riscv::*;
+use crate::elf::relocation::RelocationA64;
+use crate::elf::section::{Section64, SHN_LORESERVE};
+use crate::elf::symbol::{sym_type, STT_SECTION};
+use crate::elf::ElfFile64;
+use crate::link::LinkError;
+use crate::utils;
+
+// if the layout hasn't settled after this many passes, calls and accesses
+// near the global pointer are left alone and only alignment is redone
+const MAX_PASSES: usize = 16;
+
+// a change to the code at a relocation, which can delete bytes after it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edit {
+    // an `auipc`/`jalr` call becomes a `jal`, deleting the `jalr`
+    Call,
+    // the `lui` of an address near the global pointer is deleted
+    DeleteLui,
+    // a load, store or `addi` uses the global pointer as its base
+    UseGp,
+    // R_RISCV_ALIGN padding is cut down to the given number of bytes
+    Align(u64),
+}
+
+// a section being relaxed, as it was read. Edits are planned against these
+// offsets and only made once the layout has settled.
+struct RelaxedSection {
+    data: Vec<u8>,
+    relocations: Vec<RelocationA64>,
+    // by index into `relocations`
+    edits: BTreeMap<usize, Edit>,
+    // the byte ranges the edits delete, as (offset, length), in order
+    deletions: Vec<(u64, u64)>,
+}
+
+impl Edit {
+    // the bytes at a relocation the edit reads or changes, from its offset
+    fn len(self, rela: &RelocationA64) -> u64 {
+        match self {
+            Edit::Call => 8,
+            Edit::DeleteLui | Edit::UseGp => 4,
+            Edit::Align(_) => rela.addend,
+        }
+    }
+}
+
+impl RelaxedSection {
+    fn set_edits(&mut self, edits: BTreeMap<usize, Edit>) {
+        let relocations = &self.relocations;
+        self.deletions = edits
+            .iter()
+            .filter_map(|(i, edit)| {
+                let rela = &relocations[*i];
+                match edit {
+                    Edit::Call => Some((rela.offset + 4, 4)),
+                    Edit::DeleteLui => Some((rela.offset, 4)),
+                    Edit::UseGp => None,
+                    Edit::Align(keep) => Some((rela.offset + keep, rela.addend - keep)),
+                }
+            })
+            .collect();
+        self.deletions.sort_unstable();
+        self.edits = edits;
+    }
+
+    // where `offset` ends up once the deletions are made
+    fn adjust(&self, offset: u64) -> u64 {
+        let deleted: u64 = self
+            .deletions
+            .iter()
+            .take_while(|(start, _)| *start < offset)
+            .map(|(start, len)| std::cmp::min(*len, offset - start))
+            .sum();
+
+        offset - deleted
+    }
+
+    fn len(&self) -> usize {
+        let deleted: u64 = self.deletions.iter().map(|(_, len)| len).sum();
+
+        self.data.len() - deleted as usize
+    }
+}
+
+// RISC-V linker relaxation. Every pass plans the edits the current layout
+// allows, and resizes the sections to match, until the plan stops changing.
+pub(crate) struct Relaxation {
+    sections: HashMap<usize, RelaxedSection>,
+    passes: usize,
+}
+
+impl Relaxation {
+    // the sections with R_RISCV_RELAX or R_RISCV_ALIGN relocations
+    pub(crate) fn new(sections: &[Section64]) -> Relaxation {
+        let mut relaxed = HashMap::new();
+        for (i, section) in sections.iter().enumerate() {
+            let relas = match &section.relocations {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            let relaxable = relas
+                .iter()
+                .any(|rela| rela.get_type() == R_RISCV_RELAX || rela.get_type() == R_RISCV_ALIGN);
+            if relaxable {
+                relaxed.insert(
+                    i,
+                    RelaxedSection {
+                        data: section.data.clone(),
+                        relocations: relas.clone(),
+                        edits: BTreeMap::new(),
+                        deletions: Vec::new(),
+                    },
+                );
+            }
+        }
+
+        Relaxation {
+            sections: relaxed,
+            passes: 0,
+        }
+    }
+
+    // plan the edits for the current layout. Returns whether the plan
+    // changed, in which case the sections need laying out again. Alignment
+    // padding is always cut down to what's needed, the rest only happens
+    // with `relax`.
+    pub(crate) fn pass(
+        &mut self,
+        file: &ElfFile64,
+        sections: &mut [Section64],
+        relax: bool,
+    ) -> Result<bool, LinkError> {
+        self.passes += 1;
+        let relax = relax && self.passes <= MAX_PASSES;
+
+        let addrs: Vec<u64> = sections.iter().map(|s| s.addr).collect();
+        let gp = file
+            .symbols
+            .iter()
+            .position(|s| s.name == GLOBAL_POINTER_SYMBOL && s.shndx != 0)
+            .map(|sym_idx| self.address(file, &addrs, sym_idx, 0));
+
+        let mut indexes: Vec<usize> = self.sections.keys().copied().collect();
+        indexes.sort_unstable();
+        let mut plans = Vec::new();
+        for i in indexes {
+            let section = &self.sections[&i];
+            let relas = &section.relocations;
+            let mut order: Vec<usize> = (0..relas.len()).collect();
+            order.sort_by_key(|j| relas[*j].offset);
+
+            let mut edits = BTreeMap::new();
+            // bytes deleted before the relocation being looked at
+            let mut deleted = 0;
+            // the end of the bytes the last edit touched, edits can't overlap
+            let mut edited_until = 0;
+            for j in order {
+                let rela = &relas[j];
+                let p = addrs[i] + rela.offset - deleted;
+                let relaxable = relax
+                    && relas.get(j + 1).is_some_and(|next| {
+                        next.get_type() == R_RISCV_RELAX && next.offset == rela.offset
+                    });
+                let target = || self.address(file, &addrs, rela.get_sym(), rela.addend);
+                let near_gp = || gp.is_some_and(|gp| gp_in_range(gp, target()));
+
+                let in_section = |len: u64| {
+                    rela.offset >= edited_until && rela.offset + len <= section.data.len() as u64
+                };
+
+                let edit = match rela.get_type() {
+                    R_RISCV_ALIGN => {
+                        if !in_section(rela.addend) {
+                            return Err(LinkError::InvalidRelocation(format!(
+                                "{}: R_RISCV_ALIGN at {:#x} pads past the end of the section or into other code",
+                                &sections[i].name, rela.offset
+                            )));
+                        }
+                        // padding is made of whole instructions, which
+                        // can't start at an odd address
+                        if !p.is_multiple_of(2) {
+                            return Err(LinkError::InvalidRelocation(format!(
+                                "{}: R_RISCV_ALIGN at {:#x} isn't on an instruction boundary",
+                                &sections[i].name, rela.offset
+                            )));
+                        }
+                        // the addend is the padding the assembler put in,
+                        // enough for the worst case
+                        let align = (rela.addend + 2).next_power_of_two();
+                        let keep = utils::align_up(p, align) - p;
+                        if keep > rela.addend {
+                            return Err(LinkError::InvalidRelocation(format!(
+                                "{}: section is not aligned enough for R_RISCV_ALIGN",
+                                &sections[i].name
+                            )));
+                        }
+                        Some(Edit::Align(keep)).filter(|_| keep < rela.addend)
+                    }
+                    R_RISCV_CALL | R_RISCV_CALL_PLT if relaxable && jal_in_range(p, target()) => {
+                        Some(Edit::Call)
+                    }
+                    R_RISCV_HI20 if relaxable && near_gp() => Some(Edit::DeleteLui),
+                    R_RISCV_LO12_I | R_RISCV_LO12_S if relaxable && near_gp() => Some(Edit::UseGp),
+                    _ => None,
+                };
+                // malformed input can ask for edits that overlap, or that
+                // go past the end of the section
+                let edit = edit.filter(|edit| in_section(edit.len(rela)));
+
+                deleted += match edit {
+                    Some(Edit::Call) | Some(Edit::DeleteLui) => 4,
+                    Some(Edit::Align(keep)) => rela.addend - keep,
+                    Some(Edit::UseGp) | None => 0,
+                };
+                if let Some(edit) = edit {
+                    edited_until = rela.offset + edit.len(rela);
+                    edits.insert(j, edit);
+                }
+            }
+
+            plans.push((i, edits));
+        }
+
+        let mut changed = false;
+        for (i, edits) in plans {
+            let section = self.sections.get_mut(&i).unwrap();
+            if section.edits != edits {
+                section.set_edits(edits);
+                sections[i].data.resize(section.len(), 0);
+                changed = true;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    // make the planned edits: delete bytes, rewrite instructions and
+    // relocations, and move the symbols defined in the relaxed sections
+    pub(crate) fn finish(self, file: &mut ElfFile64, sections: &mut [Section64]) {
+        for (i, section) in self.sections.iter() {
+            let mut data = Vec::with_capacity(section.len());
+            let mut next = 0;
+            for (start, len) in section.deletions.iter() {
+                data.extend_from_slice(&section.data[next..*start as usize]);
+                next = (start + len) as usize;
+            }
+            data.extend_from_slice(&section.data[next..]);
+
+            let mut relocations = Vec::new();
+            for (j, rela) in section.relocations.iter().enumerate() {
+                let r#type = rela.get_type();
+                let offset = section.adjust(rela.offset) as usize;
+                let r#type = match section.edits.get(&j) {
+                    Some(Edit::Call) => {
+                        let rd = call_rd(&section.data, rela.offset as usize);
+                        LittleEndian::write_u32(&mut data[offset..], jal(rd));
+                        R_RISCV_JAL
+                    }
+                    Some(Edit::UseGp) => {
+                        use_gp(&mut data, offset);
+                        if r#type == R_RISCV_LO12_I {
+                            R_RISCV_GPREL_I
+                        } else {
+                            R_RISCV_GPREL_S
+                        }
+                    }
+                    Some(Edit::Align(keep)) => {
+                        let keep = *keep as usize;
+                        data[offset..offset + keep].copy_from_slice(&nops(keep));
+                        continue;
+                    }
+                    Some(Edit::DeleteLui) => continue,
+                    None if r#type == R_RISCV_RELAX || r#type == R_RISCV_ALIGN => continue,
+                    None => r#type,
+                };
+
+                let mut rela = rela.clone();
+                rela.offset = offset as u64;
+                rela.set_info(rela.get_sym(), r#type);
+                relocations.push(rela);
+            }
+
+            sections[*i].data = data;
+            sections[*i].relocations = Some(relocations);
+        }
+
+        for symbol in file.symbols.iter_mut() {
+            if symbol.shndx == 0 || symbol.shndx >= SHN_LORESERVE {
+                continue;
+            }
+            if let Some(section) = self.sections.get(&(symbol.shndx as usize)) {
+                let end = section.adjust(symbol.value + symbol.size);
+                symbol.value = section.adjust(symbol.value);
+                symbol.size = end - symbol.value;
+            }
+        }
+
+        // relocations against a section symbol point into the section with
+        // their addend
+        for section in sections.iter_mut() {
+            let relas = match section.relocations.as_mut() {
+                Some(relas) => relas,
+                None => continue,
+            };
+
+            for rela in relas.iter_mut() {
+                let symbol = &file.symbols[rela.get_sym()];
+                if sym_type(symbol) != STT_SECTION {
+                    continue;
+                }
+                if let Some(relaxed) = self.sections.get(&(symbol.shndx as usize)) {
+                    rela.addend = relaxed.adjust(rela.addend);
+                }
+            }
+        }
+    }
+
+    // the address of a symbol plus an addend, with the edits planned so far
+    fn address(&self, file: &ElfFile64, addrs: &[u64], sym_idx: usize, addend: u64) -> u64 {
+        let symbol = &file.symbols[sym_idx];
+        if symbol.shndx == 0 || symbol.shndx >= SHN_LORESERVE {
+            return symbol.value.wrapping_add(addend);
+        }
+
+        let shndx = symbol.shndx as usize;
+        let offset = match self.sections.get(&shndx) {
+            Some(section) if sym_type(symbol) == STT_SECTION => section.adjust(addend),
+            Some(section) => section.adjust(symbol.value).wrapping_add(addend),
+            None => symbol.value.wrapping_add(addend),
+        };
+
+        addrs[shndx] + offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::{LinkOptions, OutputKind};
+
+    // a call, a `lui` and a load of a variable near the global pointer, and
+    // alignment padding, with symbols after each of them
+    const RELAX_O: &[u8] = include_bytes!("../test-files/05-riscv-relaxation/relax.o");
+
+    fn link_with(relax: bool, change: impl Fn(&mut ElfFile64)) -> Result<ElfFile64, LinkError> {
+        let mut input = ElfFile64::parse(RELAX_O).unwrap();
+        change(&mut input);
+        let options = LinkOptions {
+            output_kind: OutputKind::StaticExecutable,
+            relax,
+            ..LinkOptions::default()
+        };
+
+        crate::link(vec![input], &[], &options).map(|(output, _)| output)
+    }
+
+    fn symbol(file: &ElfFile64, name: &str) -> u64 {
+        file.symbols.iter().find(|s| s.name == name).unwrap().value
+    }
+
+    // the instruction at `addr` in .text
+    fn insn(file: &ElfFile64, addr: u64) -> u32 {
+        let text = file
+            .unorganized_sections
+            .iter()
+            .find(|s| s.name == ".text")
+            .unwrap();
+        LittleEndian::read_u32(&text.data[(addr - text.addr) as usize..])
+    }
+
+    fn text_relocations(file: &mut ElfFile64) -> &mut Vec<RelocationA64> {
+        let text = file
+            .unorganized_sections
+            .iter_mut()
+            .find(|s| s.name == ".text")
+            .unwrap();
+        text.relocations.as_mut().unwrap()
+    }
+
+    // the `lla gp, __global_pointer$` at the start isn't relaxed
+    const CALL: u64 = 8;
+
+    #[test]
+    fn calls_become_jal() {
+        let output = link_with(true, |_| {}).unwrap();
+        let start = symbol(&output, "_start");
+        let jal = insn(&output, start + CALL);
+        assert_eq!(jal & 0xfff, 0x0ef, "jal ra");
+        // bits 10:1 of the offset, enough for this one
+        let disp = (jal >> 21 & 0x3ff) << 1;
+        assert_eq!(start + CALL + disp as u64, symbol(&output, "callee"));
+        assert_eq!(symbol(&output, "after_call"), start + CALL + 4);
+
+        // without relaxation the call stays an `auipc`/`jalr` pair
+        let output = link_with(false, |_| {}).unwrap();
+        let start = symbol(&output, "_start");
+        assert_eq!(insn(&output, start + CALL) & 0x7f, 0x17, "auipc");
+        assert_eq!(symbol(&output, "after_call"), start + CALL + 8);
+    }
+
+    #[test]
+    fn lui_is_deleted_and_loads_use_gp() {
+        let output = link_with(true, |_| {}).unwrap();
+        let lw = insn(&output, symbol(&output, "after_call"));
+        assert_eq!(lw & 0x707f, 0x2003, "lw");
+        assert_eq!(lw >> 15 & 0x1f, 3, "gp");
+        let offset = (lw as i32 >> 20) as i64;
+        let gp = symbol(&output, GLOBAL_POINTER_SYMBOL) as i64;
+        assert_eq!((gp + offset) as u64, symbol(&output, "var"));
+        assert_eq!(
+            symbol(&output, "after_lui"),
+            symbol(&output, "after_call") + 4
+        );
+    }
+
+    #[test]
+    fn alignment_padding_is_cut() {
+        for relax in [true, false].iter() {
+            let output = link_with(*relax, |_| {}).unwrap();
+            let aligned = symbol(&output, "aligned");
+            assert_eq!(aligned % 16, 0);
+            // the assembler put in 14 bytes
+            assert!(aligned - symbol(&output, "after_lui") < 14);
+        }
+    }
+
+    #[test]
+    fn symbols_and_relocations_move_with_the_code() {
+        let output = link_with(true, |_| {}).unwrap();
+        let data = output
+            .unorganized_sections
+            .iter()
+            .find(|s| s.name == ".data")
+            .unwrap();
+        let pointers: Vec<u64> = data.data[8..]
+            .chunks(8)
+            .map(LittleEndian::read_u64)
+            .collect();
+        let expected: Vec<u64> = ["after_call", "after_lui", "aligned", "callee"]
+            .iter()
+            .map(|name| symbol(&output, name))
+            .collect();
+        // the last one is against the section symbol
+        assert_eq!(pointers, expected);
+    }
+
+    #[test]
+    fn bad_relocations_are_errors() {
+        // padding that goes past the end of .text
+        let result = link_with(true, |input| {
+            let relas = text_relocations(input);
+            let align = relas
+                .iter_mut()
+                .find(|r| r.get_type() == R_RISCV_ALIGN)
+                .unwrap();
+            align.addend = 0x100;
+        });
+        assert!(matches!(result, Err(LinkError::InvalidRelocation(_))));
+
+        // padding that starts in the middle of an instruction
+        let result = link_with(true, |input| {
+            let relas = text_relocations(input);
+            let align = relas
+                .iter_mut()
+                .find(|r| r.get_type() == R_RISCV_ALIGN)
+                .unwrap();
+            align.offset += 3;
+        });
+        assert!(matches!(result, Err(LinkError::InvalidRelocation(_))));
+
+        // an address `lui` can't load
+        let result = link_with(false, |input| {
+            let relas = text_relocations(input);
+            let hi = relas
+                .iter_mut()
+                .find(|r| r.get_type() == R_RISCV_HI20)
+                .unwrap();
+            hi.addend = 1 << 40;
+        });
+        assert!(matches!(result, Err(LinkError::Overflow(_))));
+    }
+}
@@ -1,15 +1,8 @@
 use std::collections::HashMap;
 
+use crate::elf::symbol::{sym_bind, STB_LOCAL};
 use crate::elf::ElfFile64;
 
-pub fn next_aligned_value(value: usize, align: usize) -> usize {
-    if align == 0 || align == 1 {
-        value
-    } else {
-        (value & (!align + 1)) + align
-    }
-}
-
 pub fn build_section_name_map(file: &ElfFile64) -> HashMap<String, usize> {
     let mut retval = HashMap::new();
     for (i, section) in file.unorganized_sections.iter().enumerate() {
@@ -22,8 +15,61 @@ pub fn build_section_name_map(file: &ElfFile64) -> HashMap<String, usize> {
 pub fn build_symbol_name_map(file: &ElfFile64) -> HashMap<String, usize> {
     let mut retval = HashMap::new();
     for (i, symbol) in file.symbols.iter().enumerate() {
-        retval.insert(symbol.name.clone(), i);
+        if sym_bind(symbol) != STB_LOCAL {
+            retval.insert(symbol.name.clone(), i);
+        }
     }
 
     retval
 }
+
+pub fn align_up(value: u64, align: u64) -> u64 {
+    if align <= 1 {
+        value
+    } else {
+        value.div_ceil(align) * align
+    }
+}
+
+// shell-style wildcard matching, supporting `*`, `?` and `[...]` (with `!` or
+// `^` for negation and `a-z` ranges)
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.first() {
+        None => s.is_empty(),
+        Some(b'*') => (0..=s.len()).any(|i| glob_match(&pattern[1..], &s[i..])),
+        Some(b'?') => !s.is_empty() && glob_match(&pattern[1..], &s[1..]),
+        Some(b'[') => {
+            let end = match pattern.iter().skip(2).position(|c| *c == b']') {
+                Some(end) => end + 2,
+                // no closing bracket, treat `[` literally
+                None => return s.first() == Some(&b'[') && glob_match(&pattern[1..], &s[1..]),
+            };
+
+            let c = match s.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+
+            let mut class = &pattern[1..end];
+            let negated = class[0] == b'!' || class[0] == b'^';
+            if negated {
+                class = &class[1..];
+            }
+
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+
+            matched != negated && glob_match(&pattern[end + 1..], &s[1..])
+        }
+        Some(p) => s.first() == Some(p) && glob_match(&pattern[1..], &s[1..]),
+    }
+}
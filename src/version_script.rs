@@ -0,0 +1,330 @@
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag, take_until, take_while1};
+use nom::character::complete::{char, multispace1, not_line_ending};
+use nom::combinator::{all_consuming, map, opt, value};
+use nom::multi::many0;
+use nom::sequence::{delimited, pair, preceded, terminated};
+use nom::Finish;
+use nom::IResult;
+
+use crate::utils;
+
+// a single entry in a version script, e.g. the `foo*` in `global: foo*;`
+#[derive(Clone, Debug)]
+pub struct VersionPattern {
+    pub pattern: String,
+    // matched against the demangled name (in an `extern "C++"` block)
+    pub cpp: bool,
+    // quoted patterns are matched literally
+    pub exact: bool,
+}
+
+// a block of a version script, e.g.
+//   VERS_2.0 { global: foo; local: *; } VERS_1.0;
+// anonymous blocks have no name
+#[derive(Clone, Debug)]
+pub struct VersionNode {
+    pub name: Option<String>,
+    pub global: Vec<VersionPattern>,
+    pub local: Vec<VersionPattern>,
+    pub parents: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VersionScript {
+    pub nodes: Vec<VersionNode>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VersionAssignment {
+    // exported, with the version of the node at the given index
+    Global(usize),
+    // made local to the output
+    Local,
+}
+
+#[derive(Debug)]
+pub struct VersionScriptParseError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scope {
+    Global,
+    Local,
+}
+
+enum NodeEntry {
+    Scope(Scope),
+    Patterns(Vec<VersionPattern>),
+}
+
+impl VersionPattern {
+    fn has_wildcard(&self) -> bool {
+        !self.exact && self.pattern.contains(['*', '?', '['])
+    }
+
+    pub fn matches(&self, name: &str, demangled: Option<&str>) -> bool {
+        let candidate = if self.cpp {
+            match demangled {
+                Some(demangled) => demangled,
+                None => return false,
+            }
+        } else {
+            name
+        };
+
+        if self.has_wildcard() {
+            utils::glob_match(self.pattern.as_bytes(), candidate.as_bytes())
+        } else {
+            self.pattern == candidate
+        }
+    }
+}
+
+impl VersionScript {
+    pub fn parse(input: &str) -> Result<Self, VersionScriptParseError> {
+        let (_, nodes) = Finish::finish(all_consuming(terminated(many0(node), skip))(input))
+            .map_err(|_: nom::error::Error<&str>| VersionScriptParseError)?;
+
+        Ok(VersionScript { nodes })
+    }
+
+    // the names of the versions this script defines, in order
+    pub fn version_names(&self) -> Vec<String> {
+        self.nodes.iter().filter_map(|n| n.name.clone()).collect()
+    }
+
+    // decide what happens to the symbol `name`. Like ld, exact matches win
+    // over wildcards, and `*` loses to every other wildcard. Returns None if
+    // no pattern matches.
+    pub fn assign(&self, name: &str) -> Option<VersionAssignment> {
+        let demangled = cpp_demangle::Symbol::new(name).ok().map(|s| s.to_string());
+
+        let candidates = |pass: usize| {
+            self.nodes.iter().enumerate().find_map(|(i, node)| {
+                let in_pass = |p: &&VersionPattern| match pass {
+                    0 => !p.has_wildcard(),
+                    1 => p.has_wildcard() && p.pattern != "*",
+                    _ => p.pattern == "*",
+                };
+                let matches = |patterns: &Vec<VersionPattern>| {
+                    patterns
+                        .iter()
+                        .filter(in_pass)
+                        .any(|p| p.matches(name, demangled.as_deref()))
+                };
+
+                if matches(&node.global) {
+                    Some(VersionAssignment::Global(i))
+                } else if matches(&node.local) {
+                    Some(VersionAssignment::Local)
+                } else {
+                    None
+                }
+            })
+        };
+
+        (0..3).find_map(candidates)
+    }
+}
+
+// whitespace and comments (both `#` and C-style)
+fn skip(input: &str) -> IResult<&str, ()> {
+    let line_comment = preceded(char('#'), not_line_ending);
+    let block_comment = delimited(tag("/*"), take_until("*/"), tag("*/"));
+
+    value((), many0(alt((multispace1, line_comment, block_comment))))(input)
+}
+
+fn token<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    preceded(skip, parser)
+}
+
+fn version_name(input: &str) -> IResult<&str, String> {
+    let (input, name) = token(take_while1(|c: char| {
+        c.is_ascii_alphanumeric() || c == '_' || c == '.'
+    }))(input)?;
+
+    Ok((input, name.to_string()))
+}
+
+fn scope(input: &str) -> IResult<&str, Scope> {
+    let global = value(Scope::Global, tag("global"));
+    let local = value(Scope::Local, tag("local"));
+
+    terminated(token(alt((global, local))), token(char(':')))(input)
+}
+
+// a pattern runs up to the `;` that ends it, quoted patterns are exact
+fn pattern(cpp: bool) -> impl FnMut(&str) -> IResult<&str, VersionPattern> {
+    move |input: &str| {
+        let quoted = map(delimited(char('"'), is_not("\""), char('"')), |p: &str| {
+            (p.to_string(), true)
+        });
+        let unquoted = map(is_not(";}"), |p: &str| (p.trim().to_string(), false));
+
+        let (input, (pattern, exact)) =
+            terminated(token(alt((quoted, unquoted))), token(char(';')))(input)?;
+
+        Ok((
+            input,
+            VersionPattern {
+                pattern,
+                cpp,
+                exact,
+            },
+        ))
+    }
+}
+
+// extern "C++" { ns::foo*; "ns::bar(int)"; };
+fn extern_block(input: &str) -> IResult<&str, Vec<VersionPattern>> {
+    let (input, _) = token(tag("extern"))(input)?;
+    let (input, language) = token(delimited(char('"'), is_not("\""), char('"')))(input)?;
+    let cpp = match language {
+        "C++" => true,
+        "C" => false,
+        _ => {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Tag,
+            )))
+        }
+    };
+
+    let (input, patterns) = delimited(
+        token(char('{')),
+        many0(pattern(cpp)),
+        pair(token(char('}')), opt(token(char(';')))),
+    )(input)?;
+
+    Ok((input, patterns))
+}
+
+fn node_entry(input: &str) -> IResult<&str, NodeEntry> {
+    alt((
+        map(scope, NodeEntry::Scope),
+        map(extern_block, NodeEntry::Patterns),
+        map(pattern(false), |p| NodeEntry::Patterns(vec![p])),
+    ))(input)
+}
+
+fn node(input: &str) -> IResult<&str, VersionNode> {
+    let (input, name) = opt(version_name)(input)?;
+    let (input, entries) = delimited(token(char('{')), many0(node_entry), token(char('}')))(input)?;
+    let (input, parents) = terminated(many0(version_name), token(char(';')))(input)?;
+
+    // patterns before any `global:` or `local:` are global
+    let mut node = VersionNode {
+        name,
+        global: Vec::new(),
+        local: Vec::new(),
+        parents,
+    };
+    let mut scope = Scope::Global;
+    for entry in entries {
+        match entry {
+            NodeEntry::Scope(s) => scope = s,
+            NodeEntry::Patterns(mut patterns) => match scope {
+                Scope::Global => node.global.append(&mut patterns),
+                Scope::Local => node.local.append(&mut patterns),
+            },
+        }
+    }
+
+    Ok((input, node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assign(script: &str, name: &str) -> Option<VersionAssignment> {
+        VersionScript::parse(script).unwrap().assign(name)
+    }
+
+    #[test]
+    fn exact_names_beat_wildcards() {
+        let script = "V1 { global: foo*; }; V2 { global: foobar; } V1;";
+        assert_eq!(assign(script, "foobar"), Some(VersionAssignment::Global(1)));
+        assert_eq!(assign(script, "foobaz"), Some(VersionAssignment::Global(0)));
+        assert_eq!(assign(script, "bar"), None);
+    }
+
+    #[test]
+    fn star_loses_to_other_wildcards() {
+        let script = "V1 { local: *; }; V2 { global: foo?; };";
+        assert_eq!(assign(script, "foo1"), Some(VersionAssignment::Global(1)));
+        assert_eq!(assign(script, "foo12"), Some(VersionAssignment::Local));
+    }
+
+    #[test]
+    fn quoted_patterns_are_exact() {
+        let script = "V1 { global: \"foo*\"; local: *; };";
+        assert_eq!(assign(script, "foo*"), Some(VersionAssignment::Global(0)));
+        assert_eq!(assign(script, "foobar"), Some(VersionAssignment::Local));
+    }
+
+    #[test]
+    fn cpp_patterns_match_demangled_names() {
+        let script = r#"
+            V1 {
+                global:
+                    extern "C++" { ns::foo*; "ns::bar(int)"; };
+                local: *;
+            };
+        "#;
+        assert_eq!(
+            assign(script, "_ZN2ns3fooEv"),
+            Some(VersionAssignment::Global(0))
+        );
+        assert_eq!(
+            assign(script, "_ZN2ns3barEi"),
+            Some(VersionAssignment::Global(0))
+        );
+        assert_eq!(
+            assign(script, "_ZN2ns3barEl"),
+            Some(VersionAssignment::Local)
+        );
+        // the mangled name itself isn't matched against C++ patterns
+        assert_eq!(assign(script, "ns::foo"), Some(VersionAssignment::Local));
+    }
+
+    #[test]
+    fn global_wins_within_a_node_and_earlier_nodes_win() {
+        let script = "V1 { global: foo; local: foo; };";
+        assert_eq!(assign(script, "foo"), Some(VersionAssignment::Global(0)));
+
+        let script = "V1 { local: foo; }; V2 { global: foo; };";
+        assert_eq!(assign(script, "foo"), Some(VersionAssignment::Local));
+    }
+
+    #[test]
+    fn anonymous_nodes_comments_and_parents() {
+        let script = "# exported\n{ foo; /* and */ bar; };";
+        let parsed = VersionScript::parse(script).unwrap();
+        assert_eq!(parsed.nodes.len(), 1);
+        assert_eq!(parsed.nodes[0].name, None);
+        assert_eq!(parsed.nodes[0].global.len(), 2);
+
+        let script = "V1 { global: a; }; V2 { global: b; } V1;";
+        let parsed = VersionScript::parse(script).unwrap();
+        assert_eq!(parsed.version_names(), vec!["V1", "V2"]);
+        assert_eq!(parsed.nodes[1].parents, vec!["V1"]);
+    }
+
+    #[test]
+    fn malformed_scripts_are_errors() {
+        for script in [
+            "V1 { global: foo; }",
+            "V1 { global: foo; ",
+            "V1 { global: foo }; ",
+            "V1 { extern \"Java\" { foo; }; };",
+            "V1 { global: foo; }; }",
+            "/* unterminated",
+        ] {
+            assert!(VersionScript::parse(script).is_err(), "{}", script);
+        }
+    }
+}
@@ -0,0 +1,2 @@
+int hello(void) { return 1; }
+int goodbye(void) { return 2; }
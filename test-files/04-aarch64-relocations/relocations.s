@@ -0,0 +1,22 @@
+// one of each data relocation, and branches to a function that isn't there
+    .weak missing
+
+    .text
+    .globl _start
+_start:
+    bl missing
+    b missing
+    ret
+
+    .section .text.near, "ax"
+near:
+    .hword _start - .
+
+    .data
+    .p2align 3
+    .xword _start + 1
+    .word _start + 2
+    .hword missing + 0x1234
+    .hword 0
+    .xword _start - .
+    .word _start - .
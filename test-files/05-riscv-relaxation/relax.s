@@ -0,0 +1,32 @@
+// one of each kind of relaxation: a call that becomes a `jal`, a `lui`
+// that's deleted and the load after it rewritten to use the global pointer,
+// and alignment padding that's cut down, with a symbol and a relocation
+// against the section symbol after each to be moved
+    .globl _start
+    .text
+_start:
+    .option push
+    .option norelax
+    lla gp, __global_pointer$
+    .option pop
+    call callee
+after_call:
+    lui a0, %hi(var)
+    lw a1, %lo(var)(a0)
+after_lui:
+    .p2align 4
+aligned:
+    ret
+callee:
+    ret
+
+    .data
+var:
+    .word 1
+    .p2align 3
+pointers:
+    .quad after_call
+    .quad after_lui
+    .quad aligned
+    // callee, through the section symbol
+    .quad .text + 0x28
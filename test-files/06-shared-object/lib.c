@@ -0,0 +1,12 @@
+// built into a shared object by the tests: `external` is called through the
+// PLT, `counter` is read through the GOT, and `pointers` needs a
+// R_X86_64_RELATIVE relocation for each of its entries
+extern int counter;
+int external(void);
+
+static int local_value = 3;
+int *pointers[] = {&local_value, &local_value, &counter};
+
+__attribute__((visibility("hidden"))) int hidden(void) { return local_value; }
+
+int exported(void) { return external() + counter + hidden(); }
@@ -0,0 +1,4 @@
+// `value` is default visibility here, but ref.c's reference hides it
+int value = 1;
+
+__attribute__((visibility("protected"))) int get(void) { return value; }
@@ -0,0 +1,4 @@
+extern __attribute__((visibility("hidden"))) int value;
+int get(void);
+
+int use(void) { return value + get(); }
@@ -0,0 +1,4 @@
+// a hidden symbol has to be defined by one of the inputs
+extern __attribute__((visibility("hidden"))) int missing;
+
+int use_missing(void) { return missing; }
@@ -0,0 +1,36 @@
+# arrays of every kind an input can give, linked by init_array's tests
+
+        .text
+        .globl _start
+_start: ret
+a:      ret
+b:      ret
+c:      ret
+d:      ret
+e:      ret
+f:      ret
+g:      ret
+
+        # unnumbered, runs after everything with a priority
+        .section .init_array,"aw",@init_array
+        .quad a
+
+        .section .init_array.00100,"aw",@init_array
+        .quad b
+
+        .section .init_array.00050,"aw",@init_array
+        .quad c
+
+        # priority 65535 - 65000 = 535, run last to first
+        .section .ctors.65000,"aw",@progbits
+        .quad d, e
+
+        # unnumbered too, after .init_array's
+        .section .ctors,"aw",@progbits
+        .quad f, g
+
+        .data
+        .globl bounds
+bounds: .quad __init_array_start, __init_array_end
+
+        .section .note.GNU-stack,"",@progbits
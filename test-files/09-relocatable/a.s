@@ -0,0 +1,13 @@
+# i386, so the addends are in the places (SHT_REL), each input points into
+# its own .text through the section symbol and calls the other's function
+
+        .text
+        .globl a
+a:      call b
+        ret
+.La:    ret
+
+        .data
+        .long .La
+
+        .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,13 @@
+# see a.s
+
+        .text
+        .globl b
+b:      ret
+.Lb:    call a
+        ret
+
+        .data
+        .long .Lb + 2
+        .long a
+
+        .section .note.GNU-stack,"",@progbits